@@ -0,0 +1,377 @@
+//! Host tool to sanity check a `.falkdump` snapshot without booting a node
+//!
+//! Prints the header, the registers (after the same filtering the kernel
+//! applies when loading the snapshot), the physical memory ranges, the FPU
+//! state, and the mapped virtual memory regions, and disassembles the code at
+//! RIP. Optionally hex dumps a range of virtual memory.
+
+use std::error::Error;
+use std::convert::TryInto;
+use std::process::Command;
+
+use page_table::{VirtAddr, PhysAddr, PAGE_WRITE, PAGE_USER, PAGE_NX};
+use paging::*;
+use falkdump::{FalkDump, Registers, VmxSegment};
+
+/// Number of bytes to disassemble at RIP
+const DISASM_BYTES: usize = 64;
+
+/// Decode the VT-x access rights of a segment into a human readable form
+fn describe(segment: &VmxSegment) -> String {
+    let ar = segment.access_rights;
+    if ar & (1 << 16) != 0 {
+        return "unusable".into();
+    }
+
+    let mut ret = format!("type {:#x} dpl {}", ar & 0xf, (ar >> 5) & 3);
+    for &(bit, name) in &[(4, "S"), (7, "P"), (12, "AVL"), (13, "L"),
+                          (14, "D/B"), (15, "G")] {
+        if ar & (1 << bit) != 0 {
+            ret += " ";
+            ret += name;
+        }
+    }
+    ret
+}
+
+/// Pretty print the registers, with the same fixups applied as the kernel
+/// does when it loads a falkdump
+fn print_registers(regs: &Registers) {
+    print!("Register state version {}, size {:#x}\n\n",
+           regs.qemu_version, regs.qemu_size);
+
+    let gprs = [
+        ("rax", regs.rax), ("rbx", regs.rbx), ("rcx", regs.rcx),
+        ("rdx", regs.rdx), ("rsi", regs.rsi), ("rdi", regs.rdi),
+        ("rsp", regs.rsp), ("rbp", regs.rbp), ("r8",  regs.r8),
+        ("r9",  regs.r9),  ("r10", regs.r10), ("r11", regs.r11),
+        ("r12", regs.r12), ("r13", regs.r13), ("r14", regs.r14),
+        ("r15", regs.r15),
+    ];
+    for (ii, (name, val)) in gprs.iter().enumerate() {
+        print!("{:>4} {:016x}{}", name, val,
+               if ii % 4 == 3 { "\n" } else { " " });
+    }
+    print!(" rip {:016x} rfl {:016x}\n\n", regs.rip, regs.rflags);
+
+    // The kernel runs the guest with VMXE set in CR4
+    print!(" cr0 {:016x}  cr2 {:016x}  cr3 {:016x}\n",
+           regs.cr0, regs.cr2, regs.cr3);
+    print!(" cr4 {:016x}  cr8 {:016x} efer {:016x}\n",
+           regs.cr4 | (1 << 13), regs.cr8, regs.efer);
+    print!(" dr7 {:016x}\n\n", regs.dr[7]);
+
+    print!("kernel_gs_base {:016x} star   {:016x}\n",
+           regs.kernel_gs_base, regs.star);
+    print!("lstar          {:016x} cstar  {:016x}\n",
+           regs.lstar, regs.cstar);
+    print!("fmask          {:016x}\n", regs.fmask);
+    print!("sysenter_cs    {:016x} sysenter_esp {:016x} \
+            sysenter_eip {:016x}\n\n",
+           regs.sysenter_cs, regs.sysenter_esp, regs.sysenter_eip);
+
+    let segs = regs.vmx_segments();
+    for (name, seg) in &[("cs", segs.cs), ("ds", segs.ds), ("es", segs.es),
+                         ("fs", segs.fs), ("gs", segs.gs), ("ss", segs.ss),
+                         ("ldtr", segs.ldtr), ("tr", segs.tr)] {
+        print!("{:>4} {:04x} base {:016x} limit {:08x} ar {:05x} ({})\n",
+               name, seg.selector, seg.base, seg.limit,
+               seg.access_rights, describe(seg));
+    }
+    print!("gdtr      base {:016x} limit {:08x}\n",
+           regs.gdtr.base, regs.gdtr.limit);
+    print!("idtr      base {:016x} limit {:08x}\n\n",
+           regs.idtr.base, regs.idtr.limit);
+}
+
+/// Pretty print the FPU and SSE state from the FXSAVE area
+fn print_fxsave(regs: &Registers) {
+    let fx = &regs.fxsave;
+    let u16_at = |off: usize| {
+        u16::from_le_bytes(fx[off..off + 2].try_into().unwrap())
+    };
+
+    print!("fcw {:04x} fsw {:04x} ftw {:02x} mxcsr {:08x}\n",
+           u16_at(0), u16_at(2), fx[4],
+           u32::from_le_bytes(fx[24..28].try_into().unwrap()));
+
+    for ii in 0..16 {
+        let xmm = u128::from_le_bytes(
+            fx[160 + ii * 16..176 + ii * 16].try_into().unwrap());
+        print!("xmm{:<2} {:032x}{}", ii, xmm,
+               if ii % 2 == 1 { "\n" } else { " " });
+    }
+    print!("\n");
+}
+
+/// Paging modes of the guest
+#[derive(Clone, Copy)]
+enum PagingMode {
+    /// 32-bit paging without PAE
+    Bits32,
+
+    /// 32-bit paging with PAE
+    Bits32Pae,
+
+    /// 4-level 64-bit paging
+    Bits64,
+}
+
+/// Access to the guest memory of a parsed falkdump
+struct Guest<'a>(FalkDump<'a>);
+
+impl<'a> Guest<'a> {
+    /// Get the contents of physical memory from `paddr` to the end of its
+    /// page
+    fn phys_page(&self, paddr: PhysAddr) -> Option<&'a [u8]> {
+        self.0.phys_page(paddr.0)
+    }
+
+    /// Read a `u32` from physical memory
+    fn read_phys_u32(&self, paddr: PhysAddr) -> Option<u32> {
+        Some(u32::from_le_bytes(self.phys_page(paddr)?.get(..4)?
+                                .try_into().unwrap()))
+    }
+
+    /// Read a `u64` from physical memory
+    fn read_phys_u64(&self, paddr: PhysAddr) -> Option<u64> {
+        Some(u64::from_le_bytes(self.phys_page(paddr)?.get(..8)?
+                                .try_into().unwrap()))
+    }
+
+    /// Get the paging mode of the snapshot, `None` if paging is disabled or
+    /// the paging state is invalid
+    fn paging_mode(&self) -> Option<PagingMode> {
+        let regs = &self.0.regs;
+        let (cr0, cr4, efer) = (regs.cr0, regs.cr4, regs.efer);
+        if cr0 & (1 << 31) == 0 {
+            None
+        } else if efer & (1 << 8) == 0 {
+            if cr4 & (1 << 5) == 0 {
+                Some(PagingMode::Bits32)
+            } else {
+                Some(PagingMode::Bits32Pae)
+            }
+        } else if cr4 & (1 << 5) != 0 {
+            Some(PagingMode::Bits64)
+        } else {
+            None
+        }
+    }
+
+    /// Translate a linear address to a physical address using the page
+    /// tables in CR3
+    fn translate(&self, vaddr: u64) -> Option<PhysAddr> {
+        let cr3 = self.0.regs.cr3;
+        let (page, off, _) = match self.paging_mode() {
+            None => return Some(PhysAddr(vaddr)),
+            Some(PagingMode::Bits32) => translate_32_no_pae(cr3,
+                VirtAddr(vaddr), |paddr| self.read_phys_u32(paddr))?,
+            Some(PagingMode::Bits32Pae) => translate_32_pae(cr3,
+                VirtAddr(vaddr), |paddr| self.read_phys_u64(paddr))?,
+            Some(PagingMode::Bits64) => translate_64_4_level(cr3,
+                VirtAddr(vaddr), |paddr| self.read_phys_u64(paddr))?,
+        };
+        Some(PhysAddr(page.0 + off))
+    }
+
+    /// Read up to `size` bytes of virtual memory at `vaddr`, stopping at the
+    /// first byte which is not mapped
+    fn read_virt(&self, vaddr: u64, size: usize) -> Vec<u8> {
+        let mut ret = Vec::new();
+        while ret.len() < size {
+            let page = self.translate(vaddr.wrapping_add(ret.len() as u64))
+                .and_then(|x| self.phys_page(x));
+            let page = match page {
+                Some(page) => page,
+                None       => break,
+            };
+
+            let remain = size - ret.len();
+            ret.extend_from_slice(&page[..std::cmp::min(page.len(), remain)]);
+        }
+        ret
+    }
+
+    /// Get all mapped virtual memory regions, as (start, end (inclusive),
+    /// permissions). Contiguous pages with the same permissions are merged.
+    fn mapped_regions(&self) -> Vec<(u64, u64, String)> {
+        let cr3 = self.0.regs.cr3;
+        let mut regions: Vec<(u64, u64, String)> = Vec::new();
+
+        let mut callback = |vaddr: VirtAddr, _: PhysAddr, size: u64,
+                            ent: u64| {
+            let perms = format!("r{}{}{}",
+                if ent & PAGE_WRITE != 0 { "w" } else { "-" },
+                if ent & PAGE_NX    != 0 { "-" } else { "x" },
+                if ent & PAGE_USER  != 0 { "u" } else { "k" });
+            let end = vaddr.0 + (size - 1);
+
+            if let Some(last) = regions.last_mut() {
+                if last.1.wrapping_add(1) == vaddr.0 && last.2 == perms {
+                    last.1 = end;
+                    return;
+                }
+            }
+            regions.push((vaddr.0, end, perms));
+        };
+
+        let get_page = |paddr| self.phys_page(paddr);
+        match self.paging_mode() {
+            None => {}
+            Some(PagingMode::Bits32) =>
+                translate_32_no_pae_mappings(cr3, get_page, &mut callback),
+            Some(PagingMode::Bits32Pae) =>
+                translate_32_pae_mappings(cr3, get_page, &mut callback),
+            Some(PagingMode::Bits64) =>
+                translate_64_4_level_mappings(cr3, get_page, &mut callback),
+        }
+
+        regions
+    }
+}
+
+/// Hex dump `bytes`, which are located at `addr`
+fn hexdump(addr: u64, bytes: &[u8]) {
+    for (ii, chunk) in bytes.chunks(16).enumerate() {
+        print!("{:016x}: ", addr + ii as u64 * 16);
+        for byte in chunk {
+            print!("{:02x} ", byte);
+        }
+        for _ in chunk.len()..16 {
+            print!("   ");
+        }
+
+        let ascii: String = chunk.iter().map(|&x| {
+            if x.is_ascii_graphic() || x == b' ' { x as char } else { '.' }
+        }).collect();
+        print!(" {}\n", ascii);
+    }
+}
+
+/// Disassemble `bytes` located at `addr` with `objdump`, in the mode
+/// selected by the access rights of `cs`. Falls back to a hex dump if
+/// `objdump` is not available.
+fn disassemble(addr: u64, bytes: &[u8], cs: &VmxSegment) {
+    let arch = if cs.access_rights & (1 << 13) != 0 {
+        "i386:x86-64"
+    } else if cs.access_rights & (1 << 14) != 0 {
+        "i386"
+    } else {
+        "i8086"
+    };
+
+    // objdump only disassembles files
+    let path = std::env::temp_dir()
+        .join(format!("falkdump_inspect_{}.bin", std::process::id()));
+    let output = std::fs::write(&path, bytes).ok().and_then(|_| {
+        Command::new("objdump")
+            .args(&["-D", "-b", "binary", "-M", "intel", "-m", arch])
+            .arg(format!("--adjust-vma={:#x}", addr))
+            .arg(&path)
+            .output().ok()
+    });
+    let _ = std::fs::remove_file(&path);
+
+    match output {
+        Some(output) if output.status.success() => {
+            // Only print the instructions
+            let output = String::from_utf8_lossy(&output.stdout);
+            for line in output.lines()
+                    .skip_while(|x| !x.ends_with("<.data>:")).skip(1) {
+                print!("{}\n", line);
+            }
+        }
+        _ => {
+            print!("objdump not available, hex dump instead\n");
+            hexdump(addr, bytes);
+        }
+    }
+}
+
+/// Parse a number, in hex if prefixed with `0x`
+fn parse_num(val: &str) -> Result<u64, Box<dyn Error>> {
+    let val = val.replace("_", "");
+    Ok(if val.starts_with("0x") {
+        u64::from_str_radix(&val[2..], 16)?
+    } else {
+        val.parse()?
+    })
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 && args.len() != 4 {
+        print!("Usage: falkdump_inspect <falkdump> [<vaddr> <size>]\n\
+                \n\
+                If a virtual address and size are specified, the virtual \
+                memory is hex dumped\n");
+        return Ok(());
+    }
+
+    let contents = std::fs::read(&args[1])?;
+    let dump = FalkDump::parse(&contents)
+        .map_err(|err| err.to_string())?;
+    let guest = Guest(dump);
+    let dump  = &guest.0;
+
+    // Dump the requested virtual memory
+    if args.len() == 4 {
+        let vaddr = parse_num(&args[2])?;
+        let size  = parse_num(&args[3])? as usize;
+
+        let bytes = guest.read_virt(vaddr, size);
+        hexdump(vaddr, &bytes);
+        if bytes.len() < size {
+            print!("Memory at {:#x} is not mapped\n",
+                   vaddr + bytes.len() as u64);
+        }
+        return Ok(());
+    }
+
+    print!("{}: {} bytes, falkdump version {}\n\n", args[1],
+           contents.len(), dump.version);
+
+    // Registers and FPU state
+    print_registers(&dump.regs);
+    print_fxsave(&dump.regs);
+
+    // Physical memory
+    let phys_size: u64 = dump.phys_ranges.iter().map(|x| x.size()).sum();
+    print!("Physical memory ranges ({} MiB)\n", phys_size / 1024 / 1024);
+    for range in dump.phys_ranges.iter() {
+        print!("    {:016x}-{:016x} at file offset {:#x}\n",
+               range.start, range.end, range.offset);
+    }
+    print!("\n");
+
+    // Virtual memory
+    match guest.paging_mode() {
+        Some(mode) => {
+            print!("Mapped virtual memory ({} paging)\n", match mode {
+                PagingMode::Bits32    => "32-bit",
+                PagingMode::Bits32Pae => "32-bit PAE",
+                PagingMode::Bits64    => "4-level 64-bit",
+            });
+            for (start, end, perms) in guest.mapped_regions() {
+                print!("    {:016x}-{:016x} {} {:10} KiB\n",
+                       start, end, perms, (end - start + 1) / 1024);
+            }
+        }
+        None => print!("Paging is disabled\n"),
+    }
+    print!("\n");
+
+    // Code at RIP
+    let cs   = dump.regs.vmx_segments().cs;
+    let rip  = cs.base.wrapping_add(dump.regs.rip);
+    let code = guest.read_virt(rip, DISASM_BYTES);
+    print!("Code at {:#x}\n", rip);
+    if code.is_empty() {
+        print!("    RIP is not mapped\n");
+    } else {
+        disassemble(rip, &code, &cs);
+    }
+
+    Ok(())
+}
@@ -2,18 +2,23 @@
 //! a given target
 
 pub mod windows;
+pub mod linux;
+pub mod symbols;
+pub mod mutate;
+pub mod breakpoint;
+pub mod coverage;
+pub mod trace;
 
 use core::mem::size_of;
 use core::cell::{Cell, RefCell};
-use core::convert::TryInto;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 use core::alloc::Layout;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::borrow::Cow;
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet};
 
 use crate::mm;
 use crate::time;
@@ -24,14 +29,18 @@ use crate::net::tcp::TcpConnection;
 use crate::vtx::*;
 use crate::net::netmapping::NetMapping;
 use crate::core_locals::LockInterrupts;
-use crate::paging::*;
+use crate::minidump::{self, MiniDump};
+use paging::*;
 
 use aht::Aht;
 use falktp::{CoverageRecord, InputRecord, ServerMessage};
+use falktp::{CrashRecord, CrashRegisters, ModuleRecord, AttributionRecord};
+use falktp::PROTOCOL_VERSION;
 use noodle::*;
 use falkhash::FalkHasher;
 use lockcell::LockCell;
 use atomicvec::AtomicVec;
+use breakpoint::{BreakpointAddress, BreakpointAction, BreakpointCallback};
 use page_table::{PhysAddr, VirtAddr, PhysMem, PageType, Mapping};
 
 /// Trait to allow conversion of slices of bytes to primitives and back
@@ -137,6 +146,26 @@ pub enum Address {
     },
 }
 
+/// Errors from creating a `FuzzSession` from a snapshot
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The snapshot file could not be network mapped from the server
+    NetMap,
+
+    /// The snapshot is not a valid falkdump or QEMU ELF core
+    FalkDump(falkdump::Error),
+
+    /// The register sidecar file of a QEMU ELF core is not UTF-8
+    InvalidSidecar,
+
+    /// The snapshot is not a full 64-bit Windows kernel dump
+    MiniDump,
+
+    /// The special registers or segments could not be recovered from the
+    /// guest memory of the kernel dump
+    MiniDumpRegisters,
+}
+
 /// Number of microseconds to wait before syncing worker statistics into the
 /// `FuzzTarget`
 ///
@@ -144,6 +173,9 @@ pub enum Address {
 /// to cut down on the lock contention
 const STATISTIC_SYNC_INTERVAL: u64 = 100_000;
 
+/// Value of `FuzzSession::timeout` when fuzz cases have no timeout
+const NO_TIMEOUT: u64 = !0;
+
 /// A random number generator based off of xorshift64
 pub struct Rng(Cell<u64>);
 
@@ -216,8 +248,29 @@ struct NetBacking<'a> {
     /// Mapping of physical region base to offset into `memory` and the end
     /// (inclusive) of the region
     phys_ranges: BTreeMap<u64, (usize, u64)>,
+
+    /// Page aligned copies of the guest physical pages whose contents are not
+    /// page aligned in `memory`, keyed by guest physical address
+    unaligned_pages: LockCell<BTreeMap<u64, Box<Page>>, LockInterrupts>,
+}
+
+impl<'a> NetBacking<'a> {
+    /// Create a new network backing from the snapshot `memory`, with the
+    /// physical regions described by `phys_ranges`
+    fn new(memory: NetMapping<'a>, phys_ranges: BTreeMap<u64, (usize, u64)>)
+            -> Self {
+        NetBacking {
+            memory:          memory,
+            phys_ranges:     phys_ranges,
+            unaligned_pages: LockCell::new(BTreeMap::new()),
+        }
+    }
 }
 
+/// A page aligned page of memory
+#[repr(C, align(4096))]
+struct Page([u8; 4096]);
+
 struct Backing<'a> {
     /// A master to this backing
     master: Option<Arc<Backing<'a>>>,
@@ -262,11 +315,23 @@ impl<'a> Backing<'a> {
                 // the region
                 let offset = offset
                     .checked_add((gpaddr.0 - phys_base) as usize)?;
-                assert!(offset & 0xfff == 0, "Whoa, page offset not aligned");
 
                 // Get a slice to the memory backing this requested region
                 let data = netmem.memory.get(offset..offset + 4096)?;
-                Some(VirtAddr(data.as_ptr() as u64))
+                if offset & 0xfff == 0 {
+                    return Some(VirtAddr(data.as_ptr() as u64));
+                }
+
+                // The page is not page aligned in the snapshot, so it cannot
+                // be directly mapped. Make an aligned copy of it, which lives
+                // as long as the backing.
+                let mut pages = netmem.unaligned_pages.lock();
+                let page = pages.entry(gpaddr.0).or_insert_with(|| {
+                    let mut page = Box::new(Page([0; 4096]));
+                    page.0.copy_from_slice(data);
+                    page
+                });
+                Some(VirtAddr(page.0.as_ptr() as u64))
             } else {
                 // Nobody can provide the memory for us, it's not present
                 None
@@ -303,6 +368,10 @@ pub struct Worker<'a> {
     /// Unique worker identifier
     worker_id: u64,
 
+    /// Number of the last snapshot switch request of the session this worker
+    /// has seen, see `requested_snapshot`
+    snapshot_request: u64,
+
     /// A connection to the server
     server: Option<BufferedIo<TcpConnection>>,
 
@@ -323,6 +392,25 @@ pub struct Worker<'a> {
     /// Addresses reference their refcounts, which tracks the number of uses
     /// of that page as metadata
     page_metadata: RefCell<BTreeMap<PhysAddr, usize>>,
+
+    /// Breakpoints installed in guest memory, from the guest physical
+    /// address of the breakpoint to the index of the breakpoint in the
+    /// session and the original byte at the address
+    breakpoints: BTreeMap<PhysAddr, (usize, u8)>,
+
+    /// Indices of breakpoints of the session which are not yet installed
+    pending_breakpoints: Vec<usize>,
+
+    /// Guest physical address of the breakpoint whose original instruction
+    /// is being single stepped, the breakpoint is re-inserted after it
+    step_over: Option<PhysAddr>,
+
+    /// Instruction trace being recorded by `trace_input`, if any
+    trace: Option<trace::Trace>,
+
+    /// Basic blocks with a coverage breakpoint armed by `arm_coverage`, only
+    /// used on the master until they are moved into the session
+    blocks: BTreeMap<PhysAddr, coverage::Block>,
 }
 
 impl<'a> Worker<'a> {
@@ -339,6 +427,7 @@ impl<'a> Worker<'a> {
             sync:           0,
             session:        None,
             worker_id:      !0,
+            snapshot_request: 0,
             module_list:    BTreeMap::new(),
             fuzz_input:     RefCell::new(Vec::new()),
             server:         None,
@@ -346,6 +435,11 @@ impl<'a> Worker<'a> {
             enlightenment:  None,
             pml:            Vec::new(),
             page_metadata:  Default::default(),
+            breakpoints:    BTreeMap::new(),
+            pending_breakpoints: Vec::new(),
+            step_over:      None,
+            trace:          None,
+            blocks:         BTreeMap::new(),
         }
     }
     
@@ -357,6 +451,13 @@ impl<'a> Worker<'a> {
         let mut vm = Vm::new();
         vm.guest_regs.copy_from(&master.vm.guest_regs);
 
+        // All breakpoints of the session have yet to be installed
+        let pending_breakpoints = (0..session.breakpoints.len()).collect();
+
+        // Only switch requests made after the worker was created apply to it
+        let snapshot_request = session.requested_snapshot.lock().as_ref()
+            .map(|x| x.0).unwrap_or(0);
+
         // Create the new VM referencing the master
         Worker {
             backing: Backing {
@@ -369,6 +470,7 @@ impl<'a> Worker<'a> {
             sync:           0,
             session:        Some(session),
             worker_id:      worker_id,
+            snapshot_request: snapshot_request,
             module_list:    BTreeMap::new(),
             server:         None,
             fuzz_input:     RefCell::new(Vec::new()),
@@ -376,6 +478,11 @@ impl<'a> Worker<'a> {
             enlightenment:  None,
             pml:            Vec::new(),
             page_metadata:  Default::default(),
+            breakpoints:    BTreeMap::new(),
+            pending_breakpoints: pending_breakpoints,
+            step_over:      None,
+            trace:          None,
+            blocks:         BTreeMap::new(),
         }
     }
     
@@ -391,6 +498,78 @@ impl<'a> Worker<'a> {
         self.backing.vm.set_reg(reg, val)
     }
 
+    /// Load the register state from a falkdump into the guest VM context,
+    /// with the QEMU to VT-x fixups applied
+    fn set_registers(&mut self, regs: &falkdump::Registers) {
+        self.set_reg(Register::Rax,    regs.rax);
+        self.set_reg(Register::Rbx,    regs.rbx);
+        self.set_reg(Register::Rcx,    regs.rcx);
+        self.set_reg(Register::Rdx,    regs.rdx);
+        self.set_reg(Register::Rsi,    regs.rsi);
+        self.set_reg(Register::Rdi,    regs.rdi);
+        self.set_reg(Register::Rsp,    regs.rsp);
+        self.set_reg(Register::Rbp,    regs.rbp);
+        self.set_reg(Register::R8,     regs.r8);
+        self.set_reg(Register::R9,     regs.r9);
+        self.set_reg(Register::R10,    regs.r10);
+        self.set_reg(Register::R11,    regs.r11);
+        self.set_reg(Register::R12,    regs.r12);
+        self.set_reg(Register::R13,    regs.r13);
+        self.set_reg(Register::R14,    regs.r14);
+        self.set_reg(Register::R15,    regs.r15);
+        self.set_reg(Register::Rip,    regs.rip);
+        self.set_reg(Register::Rflags, regs.rflags);
+
+        // Load the segments
+        let segs = regs.vmx_segments();
+        macro_rules! set_segment {
+            ($seg:expr, $sel:ident, $base:ident, $limit:ident, $ar:ident) => {
+                self.set_reg(Register::$sel,   $seg.selector);
+                self.set_reg(Register::$base,  $seg.base);
+                self.set_reg(Register::$limit, $seg.limit);
+                self.set_reg(Register::$ar,    $seg.access_rights);
+            }
+        }
+
+        set_segment!(segs.es, Es, EsBase, EsLimit, EsAccessRights);
+        set_segment!(segs.cs, Cs, CsBase, CsLimit, CsAccessRights);
+        set_segment!(segs.ss, Ss, SsBase, SsLimit, SsAccessRights);
+        set_segment!(segs.ds, Ds, DsBase, DsLimit, DsAccessRights);
+        set_segment!(segs.fs, Fs, FsBase, FsLimit, FsAccessRights);
+        set_segment!(segs.gs, Gs, GsBase, GsLimit, GsAccessRights);
+        set_segment!(segs.ldtr, Ldtr, LdtrBase, LdtrLimit, LdtrAccessRights);
+        set_segment!(segs.tr, Tr, TrBase, TrLimit, TrAccessRights);
+
+        self.set_reg(Register::GdtrBase,  regs.gdtr.base);
+        self.set_reg(Register::GdtrLimit, regs.gdtr.limit as u64);
+        self.set_reg(Register::IdtrBase,  regs.idtr.base);
+        self.set_reg(Register::IdtrLimit, regs.idtr.limit as u64);
+
+        // Control registers, we must have VMXE set in CR4
+        self.set_reg(Register::Cr0, regs.cr0);
+        self.set_reg(Register::Cr2, regs.cr2);
+        self.set_reg(Register::Cr3, regs.cr3);
+        self.set_reg(Register::Cr4, regs.cr4 | (1 << 13));
+        self.set_reg(Register::Cr8, regs.cr8);
+
+        self.set_reg(Register::KernelGsBase, regs.kernel_gs_base);
+        self.set_reg(Register::CStar,        regs.cstar);
+        self.set_reg(Register::LStar,        regs.lstar);
+        self.set_reg(Register::FMask,        regs.fmask);
+        self.set_reg(Register::Star,         regs.star);
+        self.set_reg(Register::SysenterCs,   regs.sysenter_cs);
+        self.set_reg(Register::SysenterEsp,  regs.sysenter_esp);
+        self.set_reg(Register::SysenterEip,  regs.sysenter_eip);
+        self.set_reg(Register::Efer,         regs.efer);
+        self.set_reg(Register::Dr7,          regs.dr[7]);
+
+        unsafe {
+            self.backing.vm.set_fxsave(
+                core::ptr::read_unaligned(
+                    regs.fxsave.as_ptr() as *const FxSave));
+        }
+    }
+
     /// Get the current CPL
     #[inline]
     pub fn cpl(&mut self) -> u8 {
@@ -414,6 +593,23 @@ impl<'a> Worker<'a> {
         self.enlightenment = enlightenment;
     }
 
+    /// Get the snapshot file the server requested the session to switch to,
+    /// if it was requested since this worker last checked. Every worker of
+    /// the session sees each request once. The fuzzer is expected to create
+    /// a new `FuzzSession` from it, and may keep fuzzing this session if the
+    /// switch fails.
+    pub fn requested_snapshot(&mut self) -> Option<String> {
+        let session = self.session.as_ref()?;
+        let requested = session.requested_snapshot.lock();
+        match &*requested {
+            Some((request, snapshot)) if *request != self.snapshot_request => {
+                self.snapshot_request = *request;
+                Some(snapshot.clone())
+            }
+            _ => None,
+        }
+    }
+
     /// Get a random existing input
     pub fn rand_input(&self) -> Option<&[u8]> {
         // Get access to the session
@@ -433,14 +629,24 @@ impl<'a> Worker<'a> {
 
     /// Perform a single fuzz case to completion
     pub fn fuzz_case(&mut self) -> VmExit {
+        // Get access to the session
+        let session = self.session.as_ref().unwrap().clone();
+
+        // Wait while the server has paused fuzzing
+        while session.paused.load(Ordering::SeqCst) {
+            // Keep syncing with the server such that we can be resumed
+            if self.worker_id == 0 {
+                session.report_statistics(self.server.as_mut().unwrap());
+            }
+
+            time::sleep(STATISTIC_SYNC_INTERVAL);
+        }
+
         let fuzz_start = cpu::rdtsc();
 
         // Start a timer
         let it = cpu::rdtsc();
 
-        // Get access to the session
-        let session = self.session.as_ref().unwrap().clone();
-
         // Get access to the master
         let master =
             self.backing.master.as_ref().expect("Cannot fuzz without master");
@@ -478,6 +684,9 @@ impl<'a> Worker<'a> {
                 "memory", "rcx", "rdi", "rsi", "cc" : 
                 "intel", "volatile");
             }
+
+            // The master does not have our breakpoints, put them back
+            self.reinsert_breakpoints(paddr, page);
         }
 
         // Clear the PML as everything has been cleaned
@@ -486,6 +695,12 @@ impl<'a> Worker<'a> {
         // Load the original snapshot registers
         self.backing.vm.guest_regs.copy_from(&master.vm.guest_regs);
 
+        // Install any breakpoints which are not installed yet. This may
+        // change EPT mappings, so it must happen before the VMCS reset.
+        if !self.pending_breakpoints.is_empty() {
+            self.install_breakpoints();
+        }
+
         // Reset the VMCS state, this also invalidates the TLB entries since
         // we have now changed the paging structures with EPT above
         self.backing.vm.reset();
@@ -514,13 +729,31 @@ impl<'a> Worker<'a> {
         }
 
         // Compute the timeout
-        let timeout = session.timeout.map(|x| time::future(x));
+        let timeout = match session.timeout.load(Ordering::SeqCst) {
+            NO_TIMEOUT => None,
+            timeout    => Some(time::future(timeout)),
+        };
+
+        // Set if a breakpoint ended the fuzz case, such that it is not
+        // reported as a crash
+        let mut stopped = false;
+
+        // Single step the guest if we are recording a trace. A breakpoint
+        // which was being stepped over has been put back by the reset.
+        self.backing.vm.monitor_trap = self.trace.is_some();
+        self.step_over = None;
 
         let vmexit = 'vm_loop: loop {
             if cpu::rdtsc() >= timeout.unwrap_or(!0) {
                 break 'vm_loop VmExit::Timeout;
             }
 
+            // Save the RIP of the instruction about to be traced
+            if self.trace.is_some() {
+                let rip = self.reg(Register::Rip);
+                self.trace.as_mut().unwrap().rip = rip;
+            }
+
             // Set the pre-emption timer for randomly breaking into the VM
             // to enforce timeouts
             self.backing.vm.preemption_timer = None; //Some(3);
@@ -533,30 +766,7 @@ impl<'a> Worker<'a> {
             // Closure to invoke if we want to report new coverage
             let mut report_coverage = || {
                 let rip = self.reg(Register::Rip);
-                let mut modoff = self.resolve_module(rip);
-
-                if modoff.0.is_none() && self.enlightenment.is_some() {
-                    // Get the current context ID
-                    let pt = self.context_id();
-
-                    // Check if we have a module list for this process
-                    if !self.module_list.contains_key(&pt) {
-                        // Oooh, go try to get the module list for this
-                        // process
-
-                        // Request the module list from enlightenment
-                        let mut enl = self.enlightenment.take().unwrap();
-                        if let Some(ml) = enl.get_module_list(self) {
-                            // Save the module list for the process
-                            self.module_list.insert(pt, ml);
-                        
-                            // Re-resolve the module + offset
-                            modoff = self.resolve_module(rip);
-                        }
-
-                        self.enlightenment = Some(enl);
-                    }
-                }
+                let modoff = self.resolve_module_enlightened(rip);
 
                 let input = self.fuzz_input.borrow();
                 session.report_coverage(Some((&*input, &self.hasher)),
@@ -577,6 +787,11 @@ impl<'a> Worker<'a> {
                 }
                 VmExit::EptViolation { addr, read, write, exec } => {
                     if self.translate(addr, read, write, exec).is_some() {
+                        // Log the fault for the step being traced, as the
+                        // PML never sees a page which is mapped dirty
+                        if let Some(trace) = self.trace.as_mut() {
+                            trace.log_fault(addr, read, write);
+                        }
                         continue 'vm_loop;
                     }
                 }
@@ -723,6 +938,36 @@ impl<'a> Worker<'a> {
                     report_coverage();
                     continue 'vm_loop;
                 }
+                VmExit::MonitorTrap => {
+                    // Put back a breakpoint we stepped over, and record the
+                    // step if we are tracing
+                    self.step_over_done();
+                    if self.trace.is_some() {
+                        self.record_step();
+                    }
+                    continue 'vm_loop;
+                }
+                VmExit::Exception(Exception::Breakpoint) => {
+                    if let Some(paddr) = self.breakpoint_paddr() {
+                        // Report coverage if this is a basic block
+                        let covered = self.hit_coverage(paddr);
+
+                        // Invoke the callback if this is one of our
+                        // breakpoints
+                        match self.handle_breakpoint(paddr) {
+                            Some(BreakpointAction::Continue) => {
+                                continue 'vm_loop;
+                            }
+                            Some(BreakpointAction::Stop) => {
+                                stopped = true;
+                                break 'vm_loop vmexit;
+                            }
+                            Some(BreakpointAction::Crash) => {}
+                            None if covered => continue 'vm_loop,
+                            None => {}
+                        }
+                    }
+                }
                 _ => {},
             }
             
@@ -737,6 +982,13 @@ impl<'a> Worker<'a> {
             break 'vm_loop vmexit;
         };
 
+        // Unhandled exceptions and accesses to memory which does not exist
+        // are crashes
+        if !stopped && matches!(vmexit,
+                VmExit::Exception(_) | VmExit::EptViolation { .. }) {
+            self.report_crash(&vmexit);
+        }
+
         // Get the remainder in the PML. Since the PML index is 511 when the
         // list is empty, we should add 1 so it becomes 512. This would cause
         // the slice to be [512..512], and thus empty, when the list is
@@ -768,6 +1020,146 @@ impl<'a> Worker<'a> {
         vmexit
     }
 
+    /// Report the current state of the VM as a crash caused by `vmexit`
+    ///
+    /// Crashes are bucketed by the kind of VM exit and the module + offset of
+    /// RIP, only the first crash in a bucket is reported to the server
+    pub fn report_crash(&mut self, vmexit: &VmExit) {
+        // Get access to the session
+        let session = self.session.as_ref().unwrap().clone();
+
+        // Resolve where the crash happened
+        let rip = self.reg(Register::Rip);
+        let (module, offset) = self.resolve_module_enlightened(rip);
+
+        // Check if this crash is new
+        let kind = vmexit_kind(vmexit);
+        if !session.crash_buckets.lock()
+                .insert((kind.clone(), module.clone(), offset)) {
+            return;
+        }
+
+        // Snapshot the registers
+        let regs = CrashRegisters {
+            rax:    self.reg(Register::Rax),
+            rbx:    self.reg(Register::Rbx),
+            rcx:    self.reg(Register::Rcx),
+            rdx:    self.reg(Register::Rdx),
+            rsi:    self.reg(Register::Rsi),
+            rdi:    self.reg(Register::Rdi),
+            rsp:    self.reg(Register::Rsp),
+            rbp:    self.reg(Register::Rbp),
+            r8:     self.reg(Register::R8),
+            r9:     self.reg(Register::R9),
+            r10:    self.reg(Register::R10),
+            r11:    self.reg(Register::R11),
+            r12:    self.reg(Register::R12),
+            r13:    self.reg(Register::R13),
+            r14:    self.reg(Register::R14),
+            r15:    self.reg(Register::R15),
+            rip:    rip,
+            rflags: self.reg(Register::Rflags),
+            cr0:    self.reg(Register::Cr0),
+            cr2:    self.reg(Register::Cr2),
+            cr3:    self.reg(Register::Cr3),
+            cr4:    self.reg(Register::Cr4),
+            cs:     self.reg(Register::Cs),
+            ss:     self.reg(Register::Ss),
+        };
+
+        // Save the input which caused the crash
+        let input = self.fuzz_input.borrow().clone();
+        let input = InputRecord {
+            hash:  self.hasher.hash(&input),
+            input: Cow::Owned(Arc::new(input)),
+        };
+
+        // Queue the crash to be reported to the server
+        session.pending_crashes.lock().push(CrashRecord {
+            input:  input,
+            kind:   Cow::Owned(kind),
+            vmexit: Cow::Owned(format!("{:x?}", vmexit)),
+            module: module.map(|x| Cow::Owned(x)),
+            offset: offset,
+            regs:   regs,
+        });
+    }
+
+    /// Attempt to resolve `addr` into a module + offset, using the
+    /// enlightenment to fetch the module list for the current context if we
+    /// do not already have one
+    pub fn resolve_module_enlightened(&mut self, addr: u64)
+            -> (Option<Arc<String>>, u64) {
+        let modoff = self.resolve_module(addr);
+
+        if modoff.0.is_none() && self.enlightenment.is_some() {
+            // Get the current context ID
+            let pt = self.context_id();
+
+            // Check if we have a module list for this process, and if not go
+            // try to get it
+            if !self.module_list.contains_key(&pt) &&
+                    self.fetch_module_list() {
+                // Re-resolve the module + offset
+                return self.resolve_module(addr);
+            }
+        }
+
+        modoff
+    }
+
+    /// Request the module list for the current context from the
+    /// enlightenment, and save it in `module_list`
+    ///
+    /// Returns `true` if the enlightenment produced a module list
+    fn fetch_module_list(&mut self) -> bool {
+        // Get the current context ID
+        let pt = self.context_id();
+
+        // Request the module list from enlightenment
+        let mut enl = match self.enlightenment.take() {
+            Some(enl) => enl,
+            None      => return false,
+        };
+        let module_list = enl.get_module_list(self);
+        self.enlightenment = Some(enl);
+
+        if let Some(ml) = module_list {
+            // Let the server know about any new modules
+            if let Some(session) = self.session.clone() {
+                session.report_modules(self, &ml);
+            }
+
+            // Save the module list for the process
+            self.module_list.insert(pt, ml);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the base address of the module `name` in the current context,
+    /// using the enlightenment to fetch the module list for the context if
+    /// we do not already have one. Names are compared case-insensitively.
+    pub fn module_base(&mut self, name: &str) -> Option<u64> {
+        self.find_module(name).map(|(base, _)| base)
+    }
+
+    /// Get the base address of the module `name` in the current context and
+    /// its name as reported by the enlightenment, see `module_base`
+    pub fn find_module(&mut self, name: &str) -> Option<(u64, Arc<String>)> {
+        // Get the current context ID
+        let pt = self.context_id();
+
+        if !self.module_list.contains_key(&pt) {
+            self.fetch_module_list();
+        }
+
+        self.module_list.get(&pt)?.iter()
+            .find(|(_, (_, x))| x.eq_ignore_ascii_case(name))
+            .map(|(&base, (_, x))| (base, x.clone()))
+    }
+
     /// Attempt to resolve the `addr` into a module + offset based on the
     /// current `module_list`
     pub fn resolve_module(&mut self, addr: u64) -> (Option<Arc<String>>, u64) {
@@ -1030,6 +1422,45 @@ impl<'a> Worker<'a> {
         }, buf)
     }
     
+    /// Writes the contents of `T` to the guest virtual memory at `vaddr`
+    /// using the current page table
+    pub fn write_virt<T: Primitive>(&mut self, vaddr: VirtAddr, val: T)
+            -> Option<()> {
+        self.write_virt_from(vaddr, val.cast())
+    }
+
+    /// Write the contents of `buf` to the guest virtual memory at `vaddr`
+    /// using the current page table
+    ///
+    /// Returns `None` if the request cannot be fully satisfied. It is possible
+    /// that some writing did occur, but is partial.
+    pub fn write_virt_from(&mut self, vaddr: VirtAddr, buf: &[u8])
+            -> Option<()> {
+        let cr3 = self.reg(Register::Cr3);
+        self.write_virt_cr3_from(vaddr, buf, cr3)
+    }
+
+    /// Translate the guest virtual address `vaddr` into a guest physical
+    /// address using the current page table
+    pub fn translate_virt(&mut self, vaddr: VirtAddr) -> Option<PhysAddr> {
+        let cr3 = self.reg(Register::Cr3);
+        let (page, off, _) = match self.paging_mode()? {
+            PagingMode::Bits32 => {
+                translate_32_no_pae(cr3, vaddr,
+                    |paddr| self.read_phys(paddr))?
+            }
+            PagingMode::Bits32Pae => {
+                translate_32_pae(cr3, vaddr, |paddr| self.read_phys(paddr))?
+            }
+            PagingMode::Bits64 => {
+                translate_64_4_level(cr3, vaddr,
+                    |paddr| self.read_phys(paddr))?
+            }
+        };
+
+        Some(PhysAddr(page.0.wrapping_add(off)))
+    }
+
     /// Write the contents of `buf` to the guest virtual memory at `vaddr`
     /// using page table `cr3`
     ///
@@ -1319,6 +1750,42 @@ impl<'a> Worker<'a> {
     }
 }
 
+/// Get the kind of a VM exit, without any information specific to the
+/// instance of the VM exit (such as faulting addresses). This is used for
+/// bucketing crashes.
+fn vmexit_kind(vmexit: &VmExit) -> String {
+    match vmexit {
+        VmExit::Exception(Exception::PageFault { write, exec, .. }) => {
+            if *exec {
+                "PageFaultExec".into()
+            } else if *write {
+                "PageFaultWrite".into()
+            } else {
+                "PageFaultRead".into()
+            }
+        }
+        VmExit::Exception(Exception::GeneralProtectionFault(_)) => {
+            "GeneralProtectionFault".into()
+        }
+        VmExit::Exception(exception) => format!("{:?}", exception),
+        VmExit::EptViolation { write, exec, .. } => {
+            if *exec {
+                "EptViolationExec".into()
+            } else if *write {
+                "EptViolationWrite".into()
+            } else {
+                "EptViolationRead".into()
+            }
+        }
+        _ => {
+            // Use the name of the variant, without any fields
+            let name = format!("{:?}", vmexit);
+            name.split(|x: char| !x.is_ascii_alphanumeric())
+                .next().unwrap().into()
+        }
+    }
+}
+
 type InjectCallback<'a> = fn(&mut Worker<'a>);
 
 type VmExitFilter<'a> = fn(&mut Worker<'a>, &VmExit) -> bool;
@@ -1328,8 +1795,16 @@ pub struct FuzzSession<'a> {
     /// Master VM state
     master_vm: Arc<Backing<'a>>,
 
-    /// Timeout for each fuzz case
-    timeout: Option<u64>,
+    /// Timeout for each fuzz case in microseconds, `NO_TIMEOUT` if there is
+    /// no timeout. This may be changed by the server.
+    timeout: AtomicU64,
+
+    /// Set when the server has paused fuzzing
+    paused: AtomicBool,
+
+    /// Snapshot file the server last requested the session to switch to,
+    /// along with the number of the request, which starts at 1
+    requested_snapshot: LockCell<Option<(u64, String)>, LockInterrupts>,
 
     /// Callback to invoke before every fuzz case, for the fuzzer to inject
     /// information into the VM
@@ -1338,6 +1813,23 @@ pub struct FuzzSession<'a> {
     /// Callback to invoke when VM exits are hit to allow a user to handle VM
     /// exits to re-enter the VM
     vmexit_filter: Option<VmExitFilter<'a>>,
+
+    /// Breakpoints to install in the workers, and the callbacks to invoke
+    /// when they are hit
+    breakpoints: Vec<(BreakpointAddress, BreakpointCallback<'a>)>,
+
+    /// Basic blocks with a coverage breakpoint armed in the master, keyed by
+    /// guest physical address
+    blocks: BTreeMap<PhysAddr, coverage::Block>,
+
+    /// Maximum number of mutations `Worker::mutate` stacks on an input
+    mutations: usize,
+
+    /// Maximum size of inputs produced by `Worker::mutate`
+    max_input_size: usize,
+
+    /// Tokens `Worker::mutate` inserts into inputs
+    dictionary: Vec<Vec<u8>>,
     
     /// All observed coverage information
     coverage: Aht<CoverageRecord<'a>, (), 1048576>,
@@ -1348,6 +1840,23 @@ pub struct FuzzSession<'a> {
     /// Inputs which have yet to be reported to the server
     pending_inputs: LockCell<Vec<InputRecord<'a>>, LockInterrupts>,
 
+    /// Inputs which caused new coverage which have yet to be reported to the
+    /// server
+    pending_attributions: LockCell<Vec<AttributionRecord<'a>>, LockInterrupts>,
+
+    /// Crash buckets (kind, module, offset) which have been observed
+    crash_buckets:
+        LockCell<BTreeSet<(String, Option<Arc<String>>, u64)>, LockInterrupts>,
+
+    /// Crashes which have yet to be reported to the server
+    pending_crashes: LockCell<Vec<CrashRecord<'a>>, LockInterrupts>,
+
+    /// Names of modules which have been observed in module lists
+    modules: LockCell<BTreeSet<Arc<String>>, LockInterrupts>,
+
+    /// Modules which have yet to be reported to the server
+    pending_modules: LockCell<Vec<ModuleRecord<'a>>, LockInterrupts>,
+
     /// Table mapping input hashes to inputs
     input_dedup: Aht<u128, Arc<Vec<u8>>, 1048576>,
 
@@ -1360,6 +1869,10 @@ pub struct FuzzSession<'a> {
     /// Address to use when communicating with the server
     server_addr: String,
 
+    /// Name of the fuzz target, the server keeps a separate corpus and
+    /// coverage database for each target. Defaults to the snapshot name.
+    target: String,
+
     /// Number of workers
     workers: AtomicU64,
 
@@ -1368,250 +1881,271 @@ pub struct FuzzSession<'a> {
 }
 
 impl<'a> FuzzSession<'a> {
-    /// Create a new empty fuzz session
-    pub fn from_falkdump<S, F>(server: &str, name: S, init_master: F) -> Self
+    /// Create a new empty fuzz session from the falkdump `name` on `server`
+    pub fn from_falkdump<S, F>(server: &str, name: S, init_master: F)
+            -> Result<Self, SnapshotError>
             where F: FnOnce(&mut Worker),
                   S: AsRef<str> {
-        macro_rules! consume {
-            ($ptr:expr, $ty:ty) => {{
-                let ret = <$ty>::from_le_bytes(
-                    $ptr[..size_of::<$ty>()].try_into().unwrap());
-                $ptr = &$ptr[size_of::<$ty>()..];
-                ret
-            }}
-        }
-
         // Convert the generic name into a reference to a string
         let name: &str = name.as_ref();
 
         // Network map the memory file contents as read-only
         let memory = NetMapping::new(server, name.as_ref(), true)
-            .expect("Failed to netmap falkdump");
+            .ok_or(SnapshotError::NetMap)?;
 
-        // Check the signature
-        assert!(&memory[..8] == b"FALKDUMP", "Invalid signature for falkdump");
+        // Parse and validate the falkdump
+        let (regs, phys_ranges) = {
+            let dump = falkdump::FalkDump::parse(&memory)
+                .map_err(SnapshotError::FalkDump)?;
 
-        // Get a pointer to the file contents
-        let mut ptr = &memory[8..];
+            // Log the physical regions
+            let phys_ranges: BTreeMap<u64, (usize, u64)> =
+                dump.phys_ranges.iter().map(|range| {
+                    (range.start, (range.offset as usize, range.end))
+                }).collect();
 
-        // Get the size of the region region in bytes
-        let regs_size = consume!(ptr, u64);
-        ptr = &ptr[regs_size as usize..];
+            (dump.regs, phys_ranges)
+        };
 
-        // Get the number of regions
-        let regions = consume!(ptr, u64);
+        // Create a new master VM from the information provided
+        let netbacking = Arc::new(NetBacking::new(memory, phys_ranges));
+        let mut master = Worker::from_net(netbacking);
 
-        // Parse out the physical region information
-        let mut phys_ranges = BTreeMap::new();
-        for _ in 0..regions {
-            let start  = consume!(ptr, u64);
-            let end    = consume!(ptr, u64);
-            let offset = consume!(ptr, u64);
+        // Load the register state
+        master.set_registers(&regs);
 
-            assert!(end > start && end & 0xfff == 0xfff && start & 0xfff == 0);
+        Ok(Self::from_master(server, name, master, init_master))
+    }
+
+    /// Create a new empty fuzz session from the ELF core `name` on `server`,
+    /// as generated by `dump-guest-memory` in a stock QEMU
+    ///
+    /// Registers QEMU does not save are loaded from the sidecar file
+    /// `<name>.regs` if it exists, see `falkdump::elf` for the format. Any
+    /// remaining registers are inferred, and reported.
+    pub fn from_qemu_elf<S, F>(server: &str, name: S, init_master: F)
+            -> Result<Self, SnapshotError>
+            where F: FnOnce(&mut Worker),
+                  S: AsRef<str> {
+        // Convert the generic name into a reference to a string
+        let name: &str = name.as_ref();
+
+        // Network map the memory file contents as read-only
+        let memory = NetMapping::new(server, name.as_ref(), true)
+            .ok_or(SnapshotError::NetMap)?;
+
+        // Get the register sidecar file, if there is one. The server tells us
+        // if there is no such file rather than leaving us waiting.
+        let sidecar_name = format!("{}.regs", name);
+        let sidecar = NetMapping::new(server, &sidecar_name, true);
+        let sidecar = match &sidecar {
+            Some(sidecar) => Some(core::str::from_utf8(sidecar)
+                .map_err(|_| SnapshotError::InvalidSidecar)?),
+            None => None,
+        };
+
+        // Parse and validate the ELF core
+        let (regs, phys_ranges) = {
+            let elf = falkdump::elf::parse(&memory, sidecar)
+                .map_err(SnapshotError::FalkDump)?;
+
+            // Report the registers we had to guess
+            if !elf.inferred.is_empty() {
+                print!("{}: inferred registers {:?}, provide them in {}\n",
+                       name, elf.inferred, sidecar_name);
+            }
+
+            // Log the physical regions
+            let phys_ranges: BTreeMap<u64, (usize, u64)> =
+                elf.dump.phys_ranges.iter().map(|range| {
+                    (range.start, (range.offset as usize, range.end))
+                }).collect();
+
+            (elf.dump.regs, phys_ranges)
+        };
 
-            // Log the region
-            phys_ranges.insert(start, (offset as usize, end));
-        }
-        
         // Create a new master VM from the information provided
-        let netbacking = Arc::new(NetBacking { memory, phys_ranges });
-        let mut master = Worker::from_net(netbacking.clone());
-        
-        // Parse the registers from the register state
-        let mut ptr = &netbacking.memory[16..16 + regs_size as usize];
-        let _version = consume!(ptr, u32);
-        let _size    = consume!(ptr, u32);
-        master.set_reg(Register::Rax, consume!(ptr, u64));
-        master.set_reg(Register::Rbx, consume!(ptr, u64));
-        master.set_reg(Register::Rcx, consume!(ptr, u64));
-        master.set_reg(Register::Rdx, consume!(ptr, u64));
-        master.set_reg(Register::Rsi, consume!(ptr, u64));
-        master.set_reg(Register::Rdi, consume!(ptr, u64));
-        master.set_reg(Register::Rsp, consume!(ptr, u64));
-        master.set_reg(Register::Rbp, consume!(ptr, u64));
-        master.set_reg(Register::R8 , consume!(ptr, u64));
-        master.set_reg(Register::R9 , consume!(ptr, u64));
-        master.set_reg(Register::R10, consume!(ptr, u64));
-        master.set_reg(Register::R11, consume!(ptr, u64));
-        master.set_reg(Register::R12, consume!(ptr, u64));
-        master.set_reg(Register::R13, consume!(ptr, u64));
-        master.set_reg(Register::R14, consume!(ptr, u64));
-        master.set_reg(Register::R15, consume!(ptr, u64));
-        master.set_reg(Register::Rip, consume!(ptr, u64));
-        master.set_reg(Register::Rflags, consume!(ptr, u64));
-
-        master.set_reg(Register::Cs,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::CsLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::CsAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::CsBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Ds,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::DsLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::DsAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::DsBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Es,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::EsLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::EsAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::EsBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Fs,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::FsLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::FsAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::FsBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Gs,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::GsLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::GsAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::GsBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Ss,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::SsLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::SsAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::SsBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Ldtr,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::LdtrLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::LdtrAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::LdtrBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Tr,      consume!(ptr, u32) as u64);
-        master.set_reg(Register::TrLimit, consume!(ptr, u32) as u64);
-        master.set_reg(Register::TrAccessRights,
-                          (consume!(ptr, u32) as u64) >> 8);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::TrBase, consume!(ptr, u64));
-        
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::GdtrLimit, consume!(ptr, u32) as u64);
-        let _ = consume!(ptr, u32);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::GdtrBase, consume!(ptr, u64));
-        
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::IdtrLimit, consume!(ptr, u32) as u64);
-        let _ = consume!(ptr, u32);
-        let _ = consume!(ptr, u32);
-        master.set_reg(Register::IdtrBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Cr0, consume!(ptr, u64));
-        let _ = consume!(ptr, u64);
-        master.set_reg(Register::Cr2, consume!(ptr, u64));
-        master.set_reg(Register::Cr3, consume!(ptr, u64));
-        master.set_reg(Register::Cr4, consume!(ptr, u64) | (1 << 13));
-        
-        master.set_reg(Register::KernelGsBase, consume!(ptr, u64));
-        
-        master.set_reg(Register::Cr8, consume!(ptr, u64));
-        
-        master.set_reg(Register::CStar, consume!(ptr, u64));
-        master.set_reg(Register::LStar, consume!(ptr, u64));
-        master.set_reg(Register::FMask, consume!(ptr, u64));
-        master.set_reg(Register::Star,  consume!(ptr, u64));
-
-        master.set_reg(Register::SysenterCs,  consume!(ptr, u64));
-        master.set_reg(Register::SysenterEsp, consume!(ptr, u64));
-        master.set_reg(Register::SysenterEip, consume!(ptr, u64));
-        
-        master.set_reg(Register::Efer, consume!(ptr, u64));
+        let netbacking = Arc::new(NetBacking::new(memory, phys_ranges));
+        let mut master = Worker::from_net(netbacking);
 
-        let _ = consume!(ptr, u64);
-        let _ = consume!(ptr, u64);
-        let _ = consume!(ptr, u64);
-        let _ = consume!(ptr, u64);
-        let _ = consume!(ptr, u64);
-        let _ = consume!(ptr, u64);
-        let _ = consume!(ptr, u64);
-        master.set_reg(Register::Dr7, consume!(ptr, u64));
+        // Load the register state
+        master.set_registers(&regs);
 
-        unsafe {
-            // Remainder should be fxsave area
-            assert!(ptr.len() == 512);
+        Ok(Self::from_master(server, name, master, init_master))
+    }
 
-            master.backing.vm.set_fxsave(
-                core::ptr::read_unaligned(
-                    ptr[..512].as_ptr() as *const FxSave));
-        }
-        
-        let efer = master.reg(Register::Efer);
-        if efer & (1 << 8) != 0 {
-            // Long mode, QEMU gives some non-zero limits, zero them out
-            master.set_reg(Register::EsLimit, 0);
-            master.set_reg(Register::CsLimit, 0);
-            master.set_reg(Register::SsLimit, 0);
-            master.set_reg(Register::DsLimit, 0);
-            master.set_reg(Register::FsLimit, 0);
-            master.set_reg(Register::GsLimit, 0);
-        }
+    /// Create a new empty fuzz session from the full Windows kernel dump
+    /// `name` on `server`, as generated by `.dump /f` in WinDbg
+    ///
+    /// The general purpose registers and FPU state come from the context in
+    /// the dump header, the remaining registers are recovered from the
+    /// `_KSPECIAL_REGISTERS` and the GDT of the processor in guest memory.
+    pub fn from_minidump<S, F>(server: &str, name: S, init_master: F)
+            -> Result<Self, SnapshotError>
+            where F: FnOnce(&mut Worker),
+                  S: AsRef<str> {
+        // Convert the generic name into a reference to a string
+        let name: &str = name.as_ref();
 
-        /// Perform some filtering of the access rights as QEMU and VT-x have
-        /// slightly different expectations for these bits
-        macro_rules! filter_ars {
-            ($ar:expr, $lim:expr) => {
-                // Mark any non-present segment as inactive
-                if master.reg($ar) & (1 << 7) == 0 {
-                    master.set_reg($ar, 0x10000);
-                }
+        // Network map the memory file contents as read-only
+        let memory = NetMapping::new(server, name.as_ref(), true)
+            .ok_or(SnapshotError::NetMap)?;
 
-                // If any bit in the bottom 12 bits of the limit is zero, then
-                // G must be zero
-                if master.reg($lim) & 0xfff != 0xfff {
-                    let oldr = master.reg($ar);
-                    master.set_reg($ar, oldr & !(1 << 15));
-                }
-            }
+        // Parse the dump header
+        let dump = MiniDump::parse(&memory).ok_or(SnapshotError::MiniDump)?;
+
+        // Log the physical regions, skipping empty runs and runs which wrap
+        // the address space
+        let phys_ranges: BTreeMap<u64, (usize, u64)> =
+            dump.phys_ranges.iter().filter_map(|(&base, &(offset, size))| {
+                let end = base.checked_add((size as u64).checked_sub(1)?)?;
+                Some((base, (offset, end)))
+            }).collect();
+
+        // Create a new master VM from the information provided
+        let netbacking = Arc::new(NetBacking::new(memory, phys_ranges));
+        let mut master = Worker::from_net(netbacking);
+
+        // Enable 64-bit paging with the kernel page table such that we can
+        // read the processor state from guest memory. 64-bit Windows always
+        // runs with SCE, LME, LMA, and NXE set in EFER.
+        let efer = 0xd01;
+        master.set_reg(Register::Cr0,  (1 << 31) | 1);
+        master.set_reg(Register::Cr4,  1 << 5);
+        master.set_reg(Register::Efer, efer);
+        master.set_reg(Register::Cr3,  dump.directory_table_base);
+
+        let special = dump.special_registers(&mut master)
+            .ok_or(SnapshotError::MiniDumpRegisters)?;
+        let context = &dump.context;
+
+        let mut regs = falkdump::Registers::default();
+        regs.rax    = context.rax;
+        regs.rbx    = context.rbx;
+        regs.rcx    = context.rcx;
+        regs.rdx    = context.rdx;
+        regs.rsi    = context.rsi;
+        regs.rdi    = context.rdi;
+        regs.rsp    = context.rsp;
+        regs.rbp    = context.rbp;
+        regs.r8     = context.r8;
+        regs.r9     = context.r9;
+        regs.r10    = context.r10;
+        regs.r11    = context.r11;
+        regs.r12    = context.r12;
+        regs.r13    = context.r13;
+        regs.r14    = context.r14;
+        regs.r15    = context.r15;
+        regs.rip    = context.rip;
+        regs.rflags = context.eflags as u64;
+        regs.fxsave.copy_from_slice(&context.fxsave[..512]);
+
+        regs.dr[0] = context.dr0;
+        regs.dr[1] = context.dr1;
+        regs.dr[2] = context.dr2;
+        regs.dr[3] = context.dr3;
+        regs.dr[6] = context.dr6;
+        regs.dr[7] = context.dr7;
+
+        regs.cr0  = special.cr0;
+        regs.cr2  = special.cr2;
+        regs.cr3  = special.cr3;
+        regs.cr4  = special.cr4;
+        regs.cr8  = special.cr8;
+        regs.efer = efer;
+
+        regs.kernel_gs_base = special.msr_gs_swap;
+        regs.star           = special.msr_star;
+        regs.lstar          = special.msr_lstar;
+        regs.cstar          = special.msr_cstar;
+        regs.fmask          = special.msr_syscall_mask;
+
+        regs.gdtr.base  = special.gdtr_base;
+        regs.gdtr.limit = special.gdtr_limit as u32;
+        regs.idtr.base  = special.idtr_base;
+        regs.idtr.limit = special.idtr_limit as u32;
+
+        // Recover the hidden parts of the segments from the GDT
+        {
+            let mut segment = |selector: u16, system: bool| {
+                minidump::read_segment(&mut master, special.gdtr_base,
+                                       special.gdtr_limit, selector, system)
+                    .ok_or(SnapshotError::MiniDumpRegisters)
+            };
+
+            regs.cs   = segment(context.cs,   false)?;
+            regs.ds   = segment(context.ds,   false)?;
+            regs.es   = segment(context.es,   false)?;
+            regs.fs   = segment(context.fs,   false)?;
+            regs.gs   = segment(context.gs,   false)?;
+            regs.ss   = segment(context.ss,   false)?;
+            regs.tr   = segment(special.tr,   true)?;
+            regs.ldtr = segment(special.ldtr, true)?;
         }
 
-        filter_ars!(Register::EsAccessRights, Register::EsLimit);
-        filter_ars!(Register::CsAccessRights, Register::CsLimit);
-        filter_ars!(Register::SsAccessRights, Register::SsLimit);
-        filter_ars!(Register::DsAccessRights, Register::DsLimit);
-        filter_ars!(Register::FsAccessRights, Register::FsLimit);
-        filter_ars!(Register::GsAccessRights, Register::GsLimit);
-        filter_ars!(Register::LdtrAccessRights, Register::LdtrLimit);
-        filter_ars!(Register::TrAccessRights, Register::TrLimit);
+        // The FS and GS bases come from the MSRs in long mode
+        regs.fs.base = special.msr_fs_base;
+        regs.gs.base = special.msr_gs_base;
 
-        // Init the master VM
+        // Load the register state
+        master.set_registers(&regs);
+
+        Ok(Self::from_master(server, name, master, init_master))
+    }
+
+    /// Create a new fuzz session from a `master` worker which has its memory
+    /// and register state loaded from the snapshot `name`
+    fn from_master<F>(server: &str, name: &str, mut master: Worker<'a>,
+                      init_master: F) -> Self
+            where F: FnOnce(&mut Worker) {
+        // Init the master VM, this may arm basic block coverage
         init_master(&mut master);
+        let blocks = core::mem::take(&mut master.blocks);
 
         // Rip out only the backing from the master
         let master = Arc::new(master.backing);
 
         FuzzSession {
-            master_vm:        master,
-            coverage:         Aht::new(),
-            pending_coverage: LockCell::new(Vec::new()),
-            pending_inputs:   LockCell::new(Vec::new()),
-            stats:            LockCell::new(Statistics::default()),
-            timeout:          None,
-            inject:           None,
-            vmexit_filter:    None,
-            input_dedup:      Aht::new(),
-            inputs:           AtomicVec::new(),
-            workers:          AtomicU64::new(0),
-            id:               cpu::rdtsc(),
-            server_addr:      server.into(),
+            master_vm:            master,
+            coverage:             Aht::new(),
+            pending_coverage:     LockCell::new(Vec::new()),
+            pending_inputs:       LockCell::new(Vec::new()),
+            pending_attributions: LockCell::new(Vec::new()),
+            crash_buckets:        LockCell::new(BTreeSet::new()),
+            pending_crashes:      LockCell::new(Vec::new()),
+            modules:              LockCell::new(BTreeSet::new()),
+            pending_modules:      LockCell::new(Vec::new()),
+            stats:                LockCell::new(Statistics::default()),
+            timeout:              AtomicU64::new(NO_TIMEOUT),
+            paused:               AtomicBool::new(false),
+            requested_snapshot:   LockCell::new(None),
+            inject:               None,
+            vmexit_filter:        None,
+            breakpoints:          Vec::new(),
+            blocks:               blocks,
+            mutations:            mutate::DEFAULT_MUTATIONS,
+            max_input_size:       mutate::DEFAULT_MAX_INPUT_SIZE,
+            dictionary:           Vec::new(),
+            input_dedup:          Aht::new(),
+            inputs:               AtomicVec::new(),
+            workers:              AtomicU64::new(0),
+            id:                   cpu::rdtsc(),
+            server_addr:          server.into(),
+            target:               name.into(),
         }
     }
 
-    /// Set the timeout for the VMs in microseconds
-    pub fn timeout(mut self, timeout: u64) -> Self {
-        self.timeout = Some(timeout);
+    /// Set the timeout for the VMs in microseconds. A timeout of zero ends
+    /// every fuzz case immediately, `u64::MAX` disables the timeout.
+    pub fn timeout(self, timeout: u64) -> Self {
+        self.timeout.store(timeout, Ordering::SeqCst);
+        self
+    }
+
+    /// Set the name of the fuzz target this session reports to the server
+    /// as. Sessions with the same target share a corpus and coverage.
+    pub fn target<S: Into<String>>(mut self, target: S) -> Self {
+        self.target = target.into();
         self
     }
 
@@ -1631,6 +2165,43 @@ impl<'a> FuzzSession<'a> {
         self
     }
 
+    /// Add a breakpoint at `addr`, which is either a `VirtAddr` or a
+    /// (module, offset) tuple. `callback` is invoked every time a worker
+    /// hits the breakpoint, and decides how the guest continues, see
+    /// `BreakpointAction`.
+    ///
+    /// Addresses are resolved in the context of the snapshot, using the
+    /// enlightenment of the worker for modules.
+    pub fn breakpoint<A: Into<BreakpointAddress>>(mut self, addr: A,
+            callback: BreakpointCallback<'a>) -> Self {
+        self.breakpoints.push((addr.into(), callback));
+        self
+    }
+
+    /// Set the maximum number of mutations `Worker::mutate` stacks on an
+    /// input
+    pub fn mutations(mut self, mutations: usize) -> Self {
+        self.mutations = mutations;
+        self
+    }
+
+    /// Set the maximum size of inputs produced by `Worker::mutate` (in bytes)
+    pub fn max_input_size(mut self, max_input_size: usize) -> Self {
+        self.max_input_size = max_input_size;
+        self
+    }
+
+    /// Add `tokens` to the dictionary of `Worker::mutate`. Tokens are
+    /// inserted into inputs as-is, such as keywords or magic values of the
+    /// format being fuzzed.
+    pub fn dictionary<I, T>(mut self, tokens: I) -> Self
+            where I: IntoIterator<Item = T>,
+                  T: AsRef<[u8]> {
+        self.dictionary.extend(
+            tokens.into_iter().map(|x| x.as_ref().to_vec()));
+        self
+    }
+
     /// Get a new worker for this fuzz session
     pub fn worker(session: Arc<Self>) -> Worker<'a> {
         // Get a new worker ID
@@ -1657,6 +2228,18 @@ impl<'a> FuzzSession<'a> {
 
     /// Update statistics to the server
     pub fn report_statistics(&self, server: &mut BufferedIo<TcpConnection>) {
+        {
+            // Report new modules to the server, before any coverage which
+            // may reference them
+            let mut pending_modules = self.pending_modules.lock();
+            if pending_modules.len() > 0 {
+                ServerMessage::Modules(
+                    Cow::Borrowed(pending_modules.as_slice())
+                ).serialize(server).unwrap();
+                pending_modules.clear();
+            }
+        }
+
         {
             // Report new inputs to the server
             let mut pending_inputs = self.pending_inputs.lock();
@@ -1679,6 +2262,25 @@ impl<'a> FuzzSession<'a> {
             }
         }
 
+        {
+            // Report which inputs caused the new coverage
+            let mut pending_attributions = self.pending_attributions.lock();
+            if pending_attributions.len() > 0 {
+                ServerMessage::Attributions(
+                    Cow::Borrowed(pending_attributions.as_slice())
+                ).serialize(server).unwrap();
+                pending_attributions.clear();
+            }
+        }
+
+        {
+            // Report new crashes to the server
+            let mut pending_crashes = self.pending_crashes.lock();
+            for crash in pending_crashes.drain(..) {
+                ServerMessage::Crash(crash).serialize(server).unwrap();
+            }
+        }
+
         {
             let stats = self.stats.lock();
             ServerMessage::ReportStatistics {
@@ -1712,29 +2314,27 @@ impl<'a> FuzzSession<'a> {
                         self.report_coverage(None, record);
                     }
                 }
-                ServerMessage::Inputs(inputs) => {
-                    for input in inputs.iter() {
-                        // Insert the input into the dedup table
-                        let record = self.input_dedup.entry_or_insert(
-                                &input.hash, input.hash as usize,
-                                || Box::new(input.input.clone().into_owned()));
-                        if record.inserted() {
-                            let entry = record.entry();
-
-                            // Input was new, also save it to the input list
-                            self.inputs.push(Box::new(entry.clone()));
-
-                            // Mark these inputs as something we should report
-                            // to the server, so it knows we received and
-                            // processed them
-                            let mut pending_inputs = self.pending_inputs
-                                .lock();
-                            pending_inputs.push(InputRecord {
-                                hash:  input.hash,
-                                input: Cow::Owned(entry.clone()),
-                            });
-                        }
-                    }
+                ServerMessage::Inputs(inputs) => self.sync_inputs(&inputs),
+                ServerMessage::Pause => {
+                    self.paused.store(true, Ordering::SeqCst);
+                }
+                ServerMessage::Resume => {
+                    self.paused.store(false, Ordering::SeqCst);
+                }
+                ServerMessage::SoftReboot => {
+                    crate::panic::request_soft_reboot("by server");
+                }
+                ServerMessage::SetTimeout(timeout) => {
+                    self.timeout.store(timeout.unwrap_or(NO_TIMEOUT),
+                                       Ordering::SeqCst);
+                }
+                ServerMessage::SwitchSnapshot(snapshot) => {
+                    // Number the request, such that every worker picks it
+                    // up once
+                    let mut requested = self.requested_snapshot.lock();
+                    let request =
+                        requested.as_ref().map(|x| x.0).unwrap_or(0) + 1;
+                    *requested = Some((request, snapshot.into_owned()));
                 }
                 ServerMessage::SyncComplete => {
                     // Server has released us
@@ -1745,10 +2345,91 @@ impl<'a> FuzzSession<'a> {
         }
     }
 
+    /// Add inputs which were sent by the server to the input database
+    fn sync_inputs(&self, inputs: &[InputRecord]) {
+        for input in inputs.iter() {
+            // Insert the input into the dedup table
+            let record = self.input_dedup.entry_or_insert(
+                    &input.hash, input.hash as usize,
+                    || Box::new(input.input.clone().into_owned()));
+            if record.inserted() {
+                let entry = record.entry();
+
+                // Input was new, also save it to the input list
+                self.inputs.push(Box::new(entry.clone()));
+
+                // Mark these inputs as something we should report to the
+                // server, so it knows we received and processed them
+                let mut pending_inputs = self.pending_inputs.lock();
+                pending_inputs.push(InputRecord {
+                    hash:  input.hash,
+                    input: Cow::Owned(entry.clone()),
+                });
+            }
+        }
+    }
+
+    /// Report the modules in a module list, the first time a module with a
+    /// given name is seen it is queued to be sent to the server, along with
+    /// the symbol information read from its headers in the memory of `worker`
+    pub fn report_modules(&self, worker: &mut Worker,
+                          module_list: &BTreeMap<u64, (u64, Arc<String>)>) {
+        // Find the new modules, we do not hold the lock while reading the
+        // headers as this may have to fetch memory from the server
+        let new_modules: Vec<_> = {
+            let mut modules = self.modules.lock();
+            module_list.iter()
+                .filter(|(_, (_, name))| modules.insert(name.clone()))
+                .map(|(&base, (end, name))| (base, *end, name.clone()))
+                .collect()
+        };
+
+        for (base, end, name) in new_modules {
+            let (exports, codeview) = symbols::module_symbols(worker, base)
+                .unwrap_or_default();
+
+            self.pending_modules.lock().push(ModuleRecord {
+                name:     Cow::Owned(name),
+                base:     base,
+                size:     end - base + 1,
+                exports:  Cow::Owned(exports),
+                codeview: codeview,
+            });
+        }
+    }
+
     /// Log in with the server
     pub fn login(&self, server: &mut BufferedIo<TcpConnection>) {
-        ServerMessage::Login(self.id, core!().id).serialize(server).unwrap();
+        ServerMessage::Login {
+            version:    PROTOCOL_VERSION,
+            session_id: self.id,
+            core_id:    core!().id,
+            target:     Cow::Borrowed(self.target.as_str()),
+        }.serialize(server).unwrap();
         server.flush().unwrap();
+
+        // Wait for the server to acknowledge the login with its version
+        match ServerMessage::deserialize(server)
+                .expect("Failed to deserialize login response") {
+            ServerMessage::LoginResponse { version } => {
+                assert!(version == PROTOCOL_VERSION,
+                    "Server protocol version {} does not match our protocol \
+                     version {}, rebuild the kernel and server from the same \
+                     commit", version, PROTOCOL_VERSION);
+            }
+            _ => panic!("Unexpected packet in response to login"),
+        }
+
+        // The server then sends us the corpus, including any seeds, such
+        // that we have inputs before the first fuzz case
+        loop {
+            match ServerMessage::deserialize(server)
+                    .expect("Failed to deserialize login sync") {
+                ServerMessage::Inputs(inputs) => self.sync_inputs(&inputs),
+                ServerMessage::SyncComplete   => break,
+                _ => panic!("Unexpected server message during login sync"),
+            }
+        }
     }
 
     /// Report coverage
@@ -1783,6 +2464,16 @@ impl<'a> FuzzSession<'a> {
                         input: Cow::Owned(entry.clone()),
                     });
                 }
+
+                // Let the server know which input caused this coverage
+                self.pending_attributions.lock().push(AttributionRecord {
+                    input:    hash,
+                    coverage: CoverageRecord {
+                        module: cr.module.as_ref()
+                            .map(|x| Cow::Owned((**x).clone())),
+                        offset: cr.offset,
+                    },
+                });
             }
 
             // Coverage was new, queue it to be reported to the server
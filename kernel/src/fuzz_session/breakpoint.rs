@@ -0,0 +1,184 @@
+//! Software breakpoints on guest code
+//!
+//! Breakpoints are registered on the `FuzzSession` with
+//! `FuzzSession::breakpoint` and installed by every worker by writing `0xcc`
+//! into its copy-on-write copy of the guest page. They are keyed by guest
+//! physical address, such that a breakpoint is hit regardless of which
+//! virtual mapping of the code is executed.
+//!
+//! The per-case memory reset restores dirtied pages from the master, which
+//! does not have the breakpoints, so the reset re-inserts the breakpoints on
+//! every page it restores.
+//!
+//! To resume from a breakpoint, the original byte is restored and the
+//! original instruction is single stepped with the monitor trap flag, after
+//! which the INT3 is put back.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use crate::vtx::Register;
+use crate::fuzz_session::{Worker, Segment};
+use page_table::{PhysAddr, VirtAddr};
+
+/// The INT3 instruction
+pub(super) const INT3: u8 = 0xcc;
+
+/// Address of a breakpoint
+#[derive(Clone, Debug)]
+pub enum BreakpointAddress {
+    /// A virtual address in the context of the snapshot
+    Virtual(VirtAddr),
+
+    /// An offset into a module, resolved through the enlightenment in the
+    /// context of the snapshot
+    Module(String, u64),
+}
+
+impl From<VirtAddr> for BreakpointAddress {
+    fn from(vaddr: VirtAddr) -> Self {
+        BreakpointAddress::Virtual(vaddr)
+    }
+}
+
+impl<S: Into<String>> From<(S, u64)> for BreakpointAddress {
+    fn from((module, offset): (S, u64)) -> Self {
+        BreakpointAddress::Module(module.into(), offset)
+    }
+}
+
+/// What to do with the guest after a breakpoint callback returns
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BreakpointAction {
+    /// Resume execution of the guest
+    ///
+    /// If the callback did not change `Rip`, the original instruction is
+    /// executed and the breakpoint is re-inserted after it, such that the
+    /// breakpoint is hit again the next time the instruction is executed. If
+    /// the callback changed `Rip`, for example to return from a hooked
+    /// function, the original instruction is not executed.
+    Continue,
+
+    /// End the fuzz case without reporting a crash, `Worker::fuzz_case`
+    /// returns the breakpoint exception
+    Stop,
+
+    /// Handle the breakpoint as an unhandled exception, this is passed to
+    /// the VM exit filter and is reported as a crash if it is not handled
+    Crash,
+}
+
+/// Callback to invoke when a breakpoint is hit
+pub type BreakpointCallback<'a> = fn(&mut Worker<'a>) -> BreakpointAction;
+
+impl<'a> Worker<'a> {
+    /// Install the breakpoints of the session which have not been installed
+    /// yet. This must be called with the snapshot registers loaded.
+    ///
+    /// Breakpoints whose address cannot be resolved, for example as the
+    /// page is not present, are retried on the next fuzz case.
+    pub(super) fn install_breakpoints(&mut self) {
+        // Get access to the session
+        let session = self.session.as_ref().unwrap().clone();
+
+        let pending = core::mem::replace(&mut self.pending_breakpoints,
+                                         Vec::new());
+        for index in pending {
+            // Resolve the virtual address of the breakpoint
+            let vaddr = match &session.breakpoints[index].0 {
+                BreakpointAddress::Virtual(vaddr) => Some(*vaddr),
+                BreakpointAddress::Module(module, offset) => {
+                    self.module_base(module)
+                        .and_then(|x| x.checked_add(*offset))
+                        .map(VirtAddr)
+                }
+            };
+
+            // Insert the breakpoint, saving the original byte
+            let installed = vaddr.and_then(|vaddr| {
+                let paddr = self.translate_virt(vaddr)?;
+                if self.breakpoints.contains_key(&paddr) {
+                    // There already is a breakpoint here, the first one
+                    // registered takes precedence
+                    return Some(());
+                }
+
+                // A basic block coverage breakpoint may already be here
+                let orig = match session.blocks.get(&paddr) {
+                    Some(block) => block.orig,
+                    None        => self.read_phys::<u8>(paddr)?,
+                };
+                self.write_virt(vaddr, INT3)?;
+                self.breakpoints.insert(paddr, (index, orig));
+                Some(())
+            });
+
+            if installed.is_none() {
+                self.pending_breakpoints.push(index);
+            }
+        }
+    }
+
+    /// Re-insert the breakpoints on the guest physical page `paddr`, which
+    /// has just been restored from the master into `page`
+    pub(super) fn reinsert_breakpoints(&self, paddr: PhysAddr,
+                                       page: &mut [u8]) {
+        let end = PhysAddr(paddr.0 + page.len() as u64);
+        for (bp, _) in self.breakpoints.range(paddr..end) {
+            page[(bp.0 - paddr.0) as usize] = INT3;
+        }
+    }
+
+    /// Get the guest physical address of the INT3 which caused a breakpoint
+    /// exception. On a breakpoint exception VM exit `Rip` points at it.
+    pub(super) fn breakpoint_paddr(&mut self) -> Option<PhysAddr> {
+        let rip    = self.reg(Register::Rip);
+        let linear = self.seg_base(Segment::Cs).wrapping_add(rip);
+        self.translate_virt(VirtAddr(linear))
+    }
+
+    /// Handle a breakpoint exception on the INT3 at the guest physical
+    /// address `paddr`, invoking the callback of the breakpoint
+    ///
+    /// Returns `None` if there is none of our breakpoints at `paddr`, as the
+    /// guest executed an INT3 of its own
+    pub(super) fn handle_breakpoint(&mut self, paddr: PhysAddr)
+            -> Option<BreakpointAction> {
+        // Get access to the session
+        let session = self.session.as_ref().unwrap().clone();
+
+        let rip = self.reg(Register::Rip);
+        let &(index, orig) = self.breakpoints.get(&paddr)?;
+
+        // Invoke the callback
+        let action = (session.breakpoints[index].1)(self);
+
+        if action == BreakpointAction::Continue &&
+                self.reg(Register::Rip) == rip {
+            // Restore the original byte and single step the original
+            // instruction, `step_over_done` puts the breakpoint back
+            if self.write_phys(paddr, orig).is_none() {
+                return Some(BreakpointAction::Crash);
+            }
+            self.step_over = Some(paddr);
+            self.backing.vm.monitor_trap = true;
+        }
+
+        Some(action)
+    }
+
+    /// Re-insert the breakpoint which was stepped over, if any, after the
+    /// monitor trap following a `Continue`
+    pub(super) fn step_over_done(&mut self) {
+        let paddr = match self.step_over.take() {
+            Some(paddr) => paddr,
+            None        => return,
+        };
+
+        // Only keep single stepping if we are tracing
+        self.backing.vm.monitor_trap = self.trace.is_some();
+
+        // The page was written to when the original byte was restored, so
+        // the INT3 is in our copy of the page
+        self.write_phys(paddr, INT3);
+    }
+}
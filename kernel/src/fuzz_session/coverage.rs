@@ -0,0 +1,186 @@
+//! Precise basic block coverage through one-shot breakpoints
+//!
+//! The basic blocks to cover are listed in a file on the server, usually
+//! shipped next to the snapshot as `<snapshot>.bbs`. It lists the offsets of
+//! the basic blocks of each module, following a `module <name>` line, in the
+//! format of the `sidecar` crate.
+//!
+//! ```text
+//! # Generated from the disassembly of ntdll.dll
+//! module ntdll.dll
+//! 0x1000
+//! 0x1010
+//! 0x1024
+//! ```
+//!
+//! Coverage is opt-in, by calling `Worker::arm_coverage` on the master from
+//! the `init_master` callback, after setting the enlightenment of the master
+//! which is used to resolve the modules. A breakpoint is armed at every basic
+//! block in the master snapshot. The first time a worker hits one of them the
+//! coverage is reported and the breakpoint is cleared in the master, such
+//! that no worker hits it again after its next reset.
+
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::borrow::Cow;
+use falktp::CoverageRecord;
+use crate::fuzz_session::Worker;
+use crate::fuzz_session::breakpoint::INT3;
+use crate::net::netmapping::NetMapping;
+use page_table::{PhysAddr, VirtAddr};
+
+/// A basic block with a coverage breakpoint on it
+pub struct Block {
+    /// Module the basic block is in
+    module: Arc<String>,
+
+    /// Offset of the basic block into the module
+    offset: u64,
+
+    /// Original byte at the start of the basic block
+    pub(super) orig: u8,
+}
+
+/// Parse a basic block list into the offsets of the basic blocks of each
+/// module
+fn parse(list: &str) -> Option<Vec<(String, Vec<u64>)>> {
+    let mut ret: Vec<(String, Vec<u64>)> = Vec::new();
+
+    for (_, mut fields) in sidecar::lines(list) {
+        let field = fields.next().unwrap();
+
+        if field == "module" {
+            // Start of the basic blocks of a new module
+            ret.push((String::from(fields.next()?), Vec::new()));
+        } else {
+            // Basic blocks must follow a module
+            let offset = sidecar::parse_num(field)?;
+            ret.last_mut()?.1.push(offset);
+        }
+
+        if fields.next().is_some() { return None; }
+    }
+
+    Some(ret)
+}
+
+impl<'a> Worker<'a> {
+    /// Arm a breakpoint at every basic block in the basic block list
+    /// `list_name` on `server`. This must only be called on the master from
+    /// the `init_master` callback, such that the breakpoints are in every
+    /// worker's memory.
+    pub fn arm_coverage(&mut self, server: &str, list_name: &str) {
+        // Get the basic block list
+        let list = match NetMapping::new(server, list_name, true) {
+            Some(list) => list,
+            None => {
+                print!("Basic block list {} not found\n", list_name);
+                return;
+            }
+        };
+        let list = match core::str::from_utf8(&list).ok().and_then(parse) {
+            Some(list) => list,
+            None => {
+                print!("Invalid basic block list {}\n", list_name);
+                return;
+            }
+        };
+
+        let mut skipped = 0;
+        for (module, offsets) in list {
+            // Resolve the module. Coverage is reported with the name of the
+            // module from the enlightenment, which may differ in case from
+            // the name in the list, such that it matches the coverage and
+            // modules reported everywhere else.
+            let (base, module) = match self.find_module(&module) {
+                Some(found) => found,
+                None => {
+                    print!("Module {} of {} not found\n", module, list_name);
+                    skipped += offsets.len();
+                    continue;
+                }
+            };
+
+            for offset in offsets {
+                // Insert the breakpoint, saving the original byte
+                let armed = base.checked_add(offset).and_then(|vaddr| {
+                    let vaddr = VirtAddr(vaddr);
+                    let paddr = self.translate_virt(vaddr)?;
+                    if self.blocks.contains_key(&paddr) { return Some(()); }
+
+                    let orig = self.read_phys::<u8>(paddr)?;
+                    self.write_virt(vaddr, INT3)?;
+                    self.blocks.insert(paddr, Block {
+                        module: module.clone(),
+                        offset: offset,
+                        orig:   orig,
+                    });
+                    Some(())
+                });
+
+                // Code which is not present in the snapshot cannot be
+                // covered
+                if armed.is_none() {
+                    skipped += 1;
+                }
+            }
+        }
+
+        print!("Armed {} basic block breakpoints, skipped {}\n",
+               self.blocks.len(), skipped);
+    }
+
+    /// Report coverage for the basic block breakpoint at the guest physical
+    /// address `paddr` and clear it, if there is one
+    ///
+    /// Returns `true` if there was a basic block breakpoint at `paddr`
+    pub(super) fn hit_coverage(&mut self, paddr: PhysAddr) -> bool {
+        // Get access to the session
+        let session = self.session.as_ref().unwrap().clone();
+
+        let block = match session.blocks.get(&paddr) {
+            Some(block) => block,
+            None        => return false,
+        };
+
+        // Report the coverage, this is a no-op if another worker already
+        // reported it
+        {
+            let input = self.fuzz_input.borrow();
+            session.report_coverage(Some((&*input, &self.hasher)),
+                &CoverageRecord {
+                    module: Some(Cow::Owned(block.module.clone())),
+                    offset: block.offset,
+            });
+        }
+
+        // Clear the breakpoint in the master. Workers which map the page
+        // from the master no longer see it, and workers with a copy of the
+        // page lose it on their next reset.
+        //
+        // Other workers may be executing or reading this page through their
+        // EPT at the same time. This is harmless: the write is a single byte,
+        // so they see either the breakpoint or the original byte, and the
+        // master only ever goes from the breakpoint back to the original
+        // byte. A worker which still hits the breakpoint ends up here, where
+        // the coverage report is a no-op and the byte is written again.
+        let master = self.backing.master.as_ref()
+            .expect("Cannot clear coverage without master");
+        if let Some(page) = master.get_page(PhysAddr(paddr.0 & !0xfff)) {
+            unsafe {
+                core::ptr::write_volatile(
+                    (page.0 + (paddr.0 & 0xfff)) as *mut u8, block.orig);
+            }
+        }
+
+        // Clear the breakpoint in our copy of the page, unless we also have a
+        // breakpoint of our own at this address
+        if !self.breakpoints.contains_key(&paddr) &&
+                self.read_phys::<u8>(paddr) == Some(INT3) {
+            self.write_phys(paddr, block.orig);
+        }
+
+        true
+    }
+}
@@ -0,0 +1,327 @@
+//! Linux enlightenment
+//!
+//! Linux structure layouts depend on the kernel version and configuration, so
+//! the offsets are provided by a config file shipped next to the snapshot as
+//! `<snapshot>.linux`. It has one field per line as `<name> <value>`, in the
+//! format of the `sidecar` crate.
+//!
+//! ```text
+//! # Addresses from /proc/kallsyms
+//! modules        0xffffffffc0a0b010
+//! kernel_start   0xffffffff81000000
+//! kernel_end     0xffffffff83200000
+//! current_task   0x1fbc0
+//!
+//! # Offsets from pahole or gdb `ptype /o`
+//! module_list    0x8
+//! module_name    0x18
+//! module_base    0x150
+//! module_size    0x158
+//! task_mm        0x898
+//! mm_mmap        0x0
+//! vma_start      0x0
+//! vma_end        0x8
+//! vma_next       0x10
+//! vma_file       0xa0
+//! file_dentry    0x18
+//! dentry_name    0x28
+//! ```
+//!
+//! The VMAs are walked through `mm->mmap`, which requires a kernel before
+//! 6.1, where the VMAs moved to a maple tree.
+
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
+use crate::vtx::Register;
+use crate::fuzz_session::{Worker, Primitive};
+use crate::net::netmapping::NetMapping;
+use page_table::VirtAddr;
+
+/// Maximum number of entries to walk in a linked list, to guard against
+/// cycles in corrupted lists
+const MAX_LIST_ENTRIES: usize = 64 * 1024;
+
+/// Size of `struct module.name`
+const MODULE_NAME_LEN: usize = 56;
+
+/// Maximum length of a file name we read from a `struct dentry`
+const MAX_FILE_NAME_LEN: usize = 256;
+
+/// Addresses and structure offsets of the Linux kernel in the snapshot
+#[derive(Clone, Default, Debug)]
+pub struct Config {
+    /// Address of the `modules` list head
+    pub modules: u64,
+
+    /// Start address of the kernel image (`_text`)
+    pub kernel_start: u64,
+
+    /// End address of the kernel image (`_end`)
+    pub kernel_end: u64,
+
+    /// Per-CPU offset of the `current_task` pointer
+    pub current_task: u64,
+
+    /// Offset of `list` in `struct module`
+    pub module_list: u64,
+
+    /// Offset of `name` in `struct module`
+    pub module_name: u64,
+
+    /// Offset of the base of the module's core layout in `struct module`
+    pub module_base: u64,
+
+    /// Offset of the size (`unsigned int`) of the module's core layout in
+    /// `struct module`
+    pub module_size: u64,
+
+    /// Offset of `mm` in `struct task_struct`
+    pub task_mm: u64,
+
+    /// Offset of `mmap` in `struct mm_struct`
+    pub mm_mmap: u64,
+
+    /// Offset of `vm_start` in `struct vm_area_struct`
+    pub vma_start: u64,
+
+    /// Offset of `vm_end` in `struct vm_area_struct`
+    pub vma_end: u64,
+
+    /// Offset of `vm_next` in `struct vm_area_struct`
+    pub vma_next: u64,
+
+    /// Offset of `vm_file` in `struct vm_area_struct`
+    pub vma_file: u64,
+
+    /// Offset of `f_path.dentry` in `struct file`
+    pub file_dentry: u64,
+
+    /// Offset of `d_name.name` in `struct dentry`
+    pub dentry_name: u64,
+}
+
+impl Config {
+    /// Parse a config from its text form. All fields must be present.
+    pub fn parse(config: &str) -> Option<Self> {
+        let mut ret = Config::default();
+        let mut seen = Vec::new();
+
+        for (_, mut fields) in sidecar::lines(config) {
+            let name = fields.next().unwrap();
+            let val  = sidecar::parse_num(fields.next()?)?;
+            if fields.next().is_some() { return None; }
+
+            let field = match name {
+                "modules"      => &mut ret.modules,
+                "kernel_start" => &mut ret.kernel_start,
+                "kernel_end"   => &mut ret.kernel_end,
+                "current_task" => &mut ret.current_task,
+                "module_list"  => &mut ret.module_list,
+                "module_name"  => &mut ret.module_name,
+                "module_base"  => &mut ret.module_base,
+                "module_size"  => &mut ret.module_size,
+                "task_mm"      => &mut ret.task_mm,
+                "mm_mmap"      => &mut ret.mm_mmap,
+                "vma_start"    => &mut ret.vma_start,
+                "vma_end"      => &mut ret.vma_end,
+                "vma_next"     => &mut ret.vma_next,
+                "vma_file"     => &mut ret.vma_file,
+                "file_dentry"  => &mut ret.file_dentry,
+                "dentry_name"  => &mut ret.dentry_name,
+                _ => return None,
+            };
+            *field = val;
+            seen.push(name);
+        }
+
+        // Make sure every field was provided
+        seen.sort();
+        seen.dedup();
+        if seen.len() != 16 { return None; }
+
+        Some(ret)
+    }
+
+    /// Get the config shipped next to the snapshot `name` on `server`
+    pub fn from_server(server: &str, name: &str) -> Option<Self> {
+        let config = NetMapping::new(server, &format!("{}.linux", name), true)?;
+        Config::parse(core::str::from_utf8(&config).ok()?)
+    }
+}
+
+/// Linux enlightenment
+pub struct Enlightenment {
+    /// Addresses and structure offsets of the kernel
+    config: Arc<Config>,
+}
+
+impl Enlightenment {
+    /// Create a new Linux enlightenment using the kernel information in
+    /// `config`
+    pub fn new(config: Arc<Config>) -> Self {
+        Enlightenment { config }
+    }
+
+    /// Read a `T` from kernel memory using the page table `cr3`
+    fn read<T: Primitive>(worker: &mut Worker, addr: u64, cr3: u64)
+            -> Option<T> {
+        worker.read_virt_cr3::<T>(VirtAddr(addr), cr3)
+    }
+
+    /// Read a NUL terminated string of at most `max` bytes from kernel
+    /// memory
+    fn read_cstr(worker: &mut Worker, addr: u64, max: usize, cr3: u64)
+            -> Option<String> {
+        let mut name = Vec::new();
+        for ii in 0..max as u64 {
+            let byte = Self::read::<u8>(worker, addr.checked_add(ii)?, cr3)?;
+            if byte == 0 { break; }
+            name.push(byte);
+        }
+
+        if name.is_empty() { return None; }
+        String::from_utf8(name).ok()
+    }
+
+    /// Get the kernel image and the loaded kernel modules
+    fn get_module_list_kernel(&self, worker: &mut Worker)
+            -> Option<BTreeMap<u64, (u64, Arc<String>)>> {
+        let config = &*self.config;
+        let cr3 = worker.reg(Register::Cr3);
+
+        // Create a new module list
+        let mut module_list = BTreeMap::new();
+
+        // Add the kernel image itself
+        if config.kernel_end > config.kernel_start {
+            module_list.insert(config.kernel_start,
+                (config.kernel_end - 1, Arc::new(String::from("vmlinux"))));
+        }
+
+        // Traverse the linked list
+        let mut entry = Self::read::<u64>(worker, config.modules, cr3)?;
+        for _ in 0..MAX_LIST_ENTRIES {
+            if entry == config.modules { break; }
+
+            let mut get_mod = || {
+                let module = entry.checked_sub(config.module_list)?;
+                let base = Self::read::<u64>(worker,
+                    module.checked_add(config.module_base)?, cr3)?;
+                let size = Self::read::<u32>(worker,
+                    module.checked_add(config.module_size)?, cr3)?;
+                if size == 0 { return None; }
+
+                let name = Self::read_cstr(worker,
+                    module.checked_add(config.module_name)?,
+                    MODULE_NAME_LEN, cr3)?;
+
+                // Save the module information into the module list
+                module_list.insert(base,
+                    (base.checked_add(size as u64 - 1)?, Arc::new(name)));
+                Some(())
+            };
+
+            let _ = get_mod();
+
+            // Go to the next link in the list
+            entry = Self::read::<u64>(worker, entry, cr3)?;
+        }
+
+        // Establish the new module list
+        Some(module_list)
+    }
+
+    /// Get the file backed mappings of the current user process
+    fn get_module_list_user(&self, worker: &mut Worker)
+            -> Option<BTreeMap<u64, (u64, Arc<String>)>> {
+        let config = &*self.config;
+
+        // In user mode the kernel's GS base is swapped out
+        let gs_base = worker.reg(Register::KernelGsBase);
+
+        // With page table isolation, the user page table does not map the
+        // kernel, it is the page following the kernel page table
+        let mut cr3 = worker.reg(Register::Cr3);
+        let current_task = gs_base.checked_add(config.current_task)?;
+        let current = match Self::read::<u64>(worker, current_task, cr3) {
+            Some(current) => current,
+            None => {
+                cr3 &= !(1 << 12);
+                Self::read::<u64>(worker, current_task, cr3)?
+            }
+        };
+
+        // Get the memory map of the task, kernel threads have none
+        let mm = Self::read::<u64>(worker,
+            current.checked_add(config.task_mm)?, cr3)?;
+        if mm == 0 { return None; }
+
+        // Create a new module list
+        let mut module_list: BTreeMap<u64, (u64, Arc<String>)> =
+            BTreeMap::new();
+
+        // Base of the previous file mapping, to merge the mappings of the
+        // same file
+        let mut prev: Option<u64> = None;
+
+        // Traverse the VMAs, these are sorted by address
+        let mut vma = Self::read::<u64>(worker,
+            mm.checked_add(config.mm_mmap)?, cr3)?;
+        for _ in 0..MAX_LIST_ENTRIES {
+            if vma == 0 { break; }
+
+            let mut get_vma = || {
+                let start = Self::read::<u64>(worker,
+                    vma.checked_add(config.vma_start)?, cr3)?;
+                let end = Self::read::<u64>(worker,
+                    vma.checked_add(config.vma_end)?, cr3)?;
+                let file = Self::read::<u64>(worker,
+                    vma.checked_add(config.vma_file)?, cr3)?;
+                if file == 0 || end <= start { return None; }
+
+                // Get the name of the file
+                let dentry = Self::read::<u64>(worker,
+                    file.checked_add(config.file_dentry)?, cr3)?;
+                let name_ptr = Self::read::<u64>(worker,
+                    dentry.checked_add(config.dentry_name)?, cr3)?;
+                let name = Self::read_cstr(worker, name_ptr,
+                    MAX_FILE_NAME_LEN, cr3)?;
+
+                Some((start, end - 1, name))
+            };
+
+            if let Some((start, end, name)) = get_vma() {
+                // Extend the previous mapping if it is the same file, such
+                // that offsets are relative to the first mapping
+                let same = prev.and_then(|x| module_list.get_mut(&x))
+                    .filter(|x| *x.1 == name);
+                if let Some(prev_map) = same {
+                    prev_map.0 = end;
+                } else {
+                    module_list.insert(start, (end, Arc::new(name)));
+                    prev = Some(start);
+                }
+            }
+
+            // Go to the next VMA
+            vma = Self::read::<u64>(worker,
+                vma.checked_add(config.vma_next)?, cr3)?;
+        }
+
+        // Establish the new module list
+        Some(module_list)
+    }
+}
+
+impl crate::fuzz_session::Enlightenment for Enlightenment {
+    fn get_module_list(&mut self, worker: &mut Worker)
+            -> Option<BTreeMap<u64, (u64, Arc<String>)>> {
+        if worker.cpl() == 0 {
+            self.get_module_list_kernel(worker)
+        } else {
+            self.get_module_list_user(worker)
+        }
+    }
+}
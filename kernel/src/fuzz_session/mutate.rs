@@ -0,0 +1,316 @@
+//! A havoc-style mutation engine for fuzz inputs
+//!
+//! `Worker::mutate` takes a random input from the corpus and applies a stack
+//! of randomly picked mutations to it, in the spirit of AFL's havoc stage.
+//! This gives harnesses a reasonable mutator without having to write their
+//! own.
+
+use alloc::vec::Vec;
+use crate::fuzz_session::Worker;
+
+/// Default maximum number of mutations stacked on an input
+pub const DEFAULT_MUTATIONS: usize = 16;
+
+/// Default maximum size of a mutated input (in bytes)
+pub const DEFAULT_MAX_INPUT_SIZE: usize = 64 * 1024;
+
+/// Interesting 8-bit values, likely to hit edge cases
+const INTERESTING_8: &[i64] = &[-128, -1, 0, 1, 16, 32, 64, 100, 127];
+
+/// Interesting 16-bit values
+const INTERESTING_16: &[i64] = &[
+    -32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767,
+];
+
+/// Interesting 32-bit values
+const INTERESTING_32: &[i64] = &[
+    -2147483648, -100663046, -32769, 32768, 65535, 65536, 100663045,
+    2147483647,
+];
+
+/// Maximum value added to or subtracted from integers
+const ARITH_MAX: usize = 35;
+
+/// State for applying mutations to a single input
+struct Mutator<'w, 'a> {
+    /// Worker we are mutating the input of, used for its `Rng` and corpus
+    worker: &'w Worker<'a>,
+
+    /// Input we are mutating
+    input: &'w mut Vec<u8>,
+
+    /// Maximum size the input may grow to
+    max_size: usize,
+
+    /// Tokens to insert into the input
+    dictionary: &'w [Vec<u8>],
+}
+
+impl<'a> Worker<'a> {
+    /// Mutate `fuzz_input` for the next fuzz case
+    ///
+    /// The input starts as a random input from the corpus, or keeps its
+    /// current contents if the corpus is empty, such that a harness can
+    /// provide a seed. Up to `FuzzSession::mutations` random mutations are
+    /// then stacked on it, keeping it within `FuzzSession::max_input_size`.
+    ///
+    /// The input is left alone while replaying an input with `trace_input`.
+    pub fn mutate(&self) {
+        if self.replaying() { return; }
+
+        // Get access to the session
+        let session = self.session.as_ref()
+            .expect("Cannot mutate without a fuzz session");
+
+        let mut input = self.fuzz_input.borrow_mut();
+
+        // Start from a random input from the corpus
+        if let Some(base) = self.rand_input() {
+            input.clear();
+            input.extend_from_slice(base);
+        }
+
+        let mut mutator = Mutator {
+            worker:     self,
+            input:      &mut input,
+            max_size:   session.max_input_size,
+            dictionary: &session.dictionary,
+        };
+
+        // Stack a random number of mutations
+        let mutations = 1 + mutator.rand(core::cmp::max(session.mutations, 1));
+        for _ in 0..mutations {
+            mutator.mutate();
+        }
+
+        // Splicing can grow the input past the maximum size
+        input.truncate(session.max_input_size);
+    }
+}
+
+impl<'w, 'a> Mutator<'w, 'a> {
+    /// Get a random number in the range [0, `max`). `max` must not be zero.
+    fn rand(&self, max: usize) -> usize {
+        self.worker.rng.rand() % max
+    }
+
+    /// Get a random size for a block of at most `limit` bytes, preferring
+    /// small blocks. `limit` must not be zero.
+    fn block_len(&self, limit: usize) -> usize {
+        let max = match self.rand(3) {
+            0 => 16,
+            1 => 128,
+            _ => 1024,
+        };
+
+        1 + self.rand(core::cmp::min(max, limit))
+    }
+
+    /// Get the number of bytes the input may grow by
+    fn space(&self) -> usize {
+        self.max_size.saturating_sub(self.input.len())
+    }
+
+    /// Apply a random mutation to the input
+    fn mutate(&mut self) {
+        // An empty input can only grow
+        if self.input.is_empty() {
+            self.insert_block();
+            return;
+        }
+
+        match self.rand(15) {
+             0 => self.flip_bit(),
+             1 => self.random_byte(),
+             2 => self.interesting(1),
+             3 => self.interesting(2),
+             4 => self.interesting(4),
+             5 => self.interesting(8),
+             6 => self.arithmetic(1),
+             7 => self.arithmetic(2),
+             8 => self.arithmetic(4),
+             9 => self.delete_block(),
+            10 => self.insert_block(),
+            11 => self.duplicate_block(),
+            12 => self.overwrite_block(),
+            13 => self.splice(),
+             _ => self.insert_token(),
+        }
+    }
+
+    /// Flip a random bit
+    fn flip_bit(&mut self) {
+        let bit = self.rand(self.input.len() * 8);
+        self.input[bit / 8] ^= 1 << (bit % 8);
+    }
+
+    /// Set a random byte to a random value, which is never its current value
+    fn random_byte(&mut self) {
+        let offset = self.rand(self.input.len());
+        let flip   = 1 + self.rand(255) as u8;
+        self.input[offset] ^= flip;
+    }
+
+    /// Get a random offset at which a `width` byte integer fits in the input
+    fn int_offset(&self, width: usize) -> Option<usize> {
+        if self.input.len() < width { return None; }
+        Some(self.rand(self.input.len() - width + 1))
+    }
+
+    /// Read the `width` byte integer at `offset`, in big endian if `big` is
+    /// set
+    fn read_int(&self, offset: usize, width: usize, big: bool) -> u64 {
+        let bytes = &self.input[offset..offset + width];
+        let mut val = 0u64;
+        for ii in 0..width {
+            let byte = if big { bytes[ii] } else { bytes[width - 1 - ii] };
+            val = (val << 8) | byte as u64;
+        }
+        val
+    }
+
+    /// Write the low `width` bytes of `val` at `offset`, in big endian if
+    /// `big` is set
+    fn write_int(&mut self, offset: usize, width: usize, big: bool,
+                 val: u64) {
+        let bytes = &mut self.input[offset..offset + width];
+        for ii in 0..width {
+            let byte = (val >> (ii * 8)) as u8;
+            if big {
+                bytes[width - 1 - ii] = byte;
+            } else {
+                bytes[ii] = byte;
+            }
+        }
+    }
+
+    /// Replace a random `width` byte integer with an interesting value
+    fn interesting(&mut self, width: usize) {
+        let offset = match self.int_offset(width) {
+            Some(offset) => offset,
+            None         => return,
+        };
+
+        // Wider integers also get the interesting values of narrower ones
+        let count = match width {
+            1 => INTERESTING_8.len(),
+            2 => INTERESTING_8.len() + INTERESTING_16.len(),
+            _ => INTERESTING_8.len() + INTERESTING_16.len() +
+                INTERESTING_32.len(),
+        };
+        let val = INTERESTING_8.iter()
+            .chain(INTERESTING_16.iter())
+            .chain(INTERESTING_32.iter())
+            .nth(self.rand(count)).unwrap();
+
+        let big = self.rand(2) == 0;
+        self.write_int(offset, width, big, *val as u64);
+    }
+
+    /// Add or subtract a small value from a random `width` byte integer
+    fn arithmetic(&mut self, width: usize) {
+        let offset = match self.int_offset(width) {
+            Some(offset) => offset,
+            None         => return,
+        };
+
+        let big   = self.rand(2) == 0;
+        let delta = 1 + self.rand(ARITH_MAX) as u64;
+        let val   = self.read_int(offset, width, big);
+        let val   = if self.rand(2) == 0 {
+            val.wrapping_add(delta)
+        } else {
+            val.wrapping_sub(delta)
+        };
+
+        self.write_int(offset, width, big, val);
+    }
+
+    /// Delete a random block, never deleting the entire input
+    fn delete_block(&mut self) {
+        if self.input.len() < 2 { return; }
+
+        let len    = self.block_len(self.input.len() - 1);
+        let offset = self.rand(self.input.len() - len + 1);
+        self.input.drain(offset..offset + len);
+    }
+
+    /// Insert `block` at a random offset
+    fn insert(&mut self, block: &[u8]) {
+        let offset = self.rand(self.input.len() + 1);
+        self.input.splice(offset..offset, block.iter().cloned());
+    }
+
+    /// Overwrite the input at a random offset with as much of `block` as fits
+    fn overwrite(&mut self, block: &[u8]) {
+        let len    = core::cmp::min(block.len(), self.input.len());
+        let offset = self.rand(self.input.len() - len + 1);
+        self.input[offset..offset + len].copy_from_slice(&block[..len]);
+    }
+
+    /// Insert a block of random bytes, or of a repeated random byte
+    fn insert_block(&mut self) {
+        let space = self.space();
+        if space == 0 { return; }
+
+        let len = self.block_len(space);
+        let block: Vec<u8> = if self.rand(2) == 0 {
+            (0..len).map(|_| self.rand(256) as u8).collect()
+        } else {
+            vec![self.rand(256) as u8; len]
+        };
+
+        self.insert(&block);
+    }
+
+    /// Get a copy of a random block of the input
+    fn random_block(&self, limit: usize) -> Vec<u8> {
+        let len    = self.block_len(core::cmp::min(limit, self.input.len()));
+        let offset = self.rand(self.input.len() - len + 1);
+        self.input[offset..offset + len].to_vec()
+    }
+
+    /// Insert a copy of a random block of the input elsewhere in the input
+    fn duplicate_block(&mut self) {
+        let space = self.space();
+        if space == 0 { return; }
+
+        let block = self.random_block(space);
+        self.insert(&block);
+    }
+
+    /// Overwrite the input with a copy of a random block of the input
+    fn overwrite_block(&mut self) {
+        let block = self.random_block(self.input.len());
+        self.overwrite(&block);
+    }
+
+    /// Replace the tail of the input with the tail of a random input from
+    /// the corpus
+    fn splice(&mut self) {
+        let worker = self.worker;
+        let other = match worker.rand_input() {
+            Some(other) if !other.is_empty() => other,
+            _ => return,
+        };
+
+        let split = self.rand(self.input.len() + 1);
+        let tail  = &other[self.rand(other.len())..];
+        self.input.truncate(split);
+        self.input.extend_from_slice(tail);
+    }
+
+    /// Insert a random token from the dictionary, or overwrite part of the
+    /// input with it
+    fn insert_token(&mut self) {
+        if self.dictionary.is_empty() { return; }
+
+        let dictionary = self.dictionary;
+        let token = &dictionary[self.rand(dictionary.len())];
+        if self.rand(2) == 0 && token.len() <= self.space() {
+            self.insert(token);
+        } else {
+            self.overwrite(token);
+        }
+    }
+}
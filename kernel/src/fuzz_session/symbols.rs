@@ -0,0 +1,61 @@
+//! Symbol information of guest modules, read from the PE headers of modules
+//! mapped in guest memory
+//!
+//! We collect the exports of a module and its CodeView debug record. The
+//! exports give the server function names for modules without a PDB, and the
+//! CodeView record lets the server look up the PDB's symbols in its local
+//! symbol cache.
+
+use alloc::vec::Vec;
+use alloc::borrow::Cow;
+use falktp::{SymbolRecord, CodeViewRecord};
+use pe_parser::{Pe, ReadFn, Layout, ExportTarget};
+use crate::fuzz_session::Worker;
+use page_table::VirtAddr;
+
+/// Get the exports and the CodeView record of the PE image mapped at `base`
+/// in the current address space of `worker`
+///
+/// Exports without a name are named `#<ordinal>`. Forwarded exports are
+/// skipped, as they have no code in this module.
+///
+/// Returns `None` if the headers could not be read or this is not a PE. Any
+/// directories which are not present, or which are paged out, are left
+/// empty.
+pub fn module_symbols(worker: &mut Worker, base: u64)
+        -> Option<(Vec<SymbolRecord<'static>>,
+                   Option<CodeViewRecord<'static>>)> {
+    // Parse the image, reading it lazily out of guest memory
+    let pe = Pe::parse(ReadFn(|offset: u64, buf: &mut [u8]| {
+        worker.read_virt_into(VirtAddr(base.checked_add(offset)?), buf)
+    }), Layout::Mapped)?;
+
+    // Get the exports which have code in this module
+    let mut exports: Vec<SymbolRecord> = pe.exports().map(|exports| {
+        exports.filter_map(|export| {
+            let rva = match export.target {
+                ExportTarget::Rva(rva)     => rva,
+                ExportTarget::Forwarder(_) => return None,
+            };
+
+            let name = export.name
+                .unwrap_or_else(|| format!("#{}", export.ordinal));
+            Some(SymbolRecord {
+                offset: rva as u64,
+                name:   Cow::Owned(name),
+            })
+        }).collect()
+    }).unwrap_or_default();
+    exports.sort();
+
+    // Get the CodeView record identifying the PDB of the module
+    let codeview = pe.codeview().map(|codeview| {
+        CodeViewRecord {
+            pdb:  Cow::Owned(codeview.pdb),
+            guid: codeview.guid,
+            age:  codeview.age,
+        }
+    });
+
+    Some((exports, codeview))
+}
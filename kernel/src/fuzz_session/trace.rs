@@ -0,0 +1,208 @@
+//! Instruction tracing through the monitor trap flag
+//!
+//! `Worker::trace_input` replays a single input with the monitor trap flag
+//! set, such that the guest exits after every instruction it executes. Each
+//! step records the instruction, the values of a selected set of registers
+//! and the page faults the instruction took. Only the most recent steps are
+//! kept, and the trace is uploaded to the server, which saves it next to the
+//! input under the `traces` directory of the target.
+//!
+//! Memory accesses are not decoded, so a trace only has the page faults of
+//! each step, not every access:
+//!
+//! - Pages the instruction dirtied, which is the first write to the page in
+//!   the case. These come from the page modification log, and from the EPT
+//!   violations of pages which are copied in already dirty.
+//! - Pages the instruction faulted in for a read, which is the first read of
+//!   the page since it was mapped into the worker. Pages mapped by an earlier
+//!   case are not faulted in again.
+
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::borrow::Cow;
+use alloc::collections::VecDeque;
+use crate::vtx::{Register, VmExit};
+use crate::fuzz_session::Worker;
+use falktp::{InputRecord, ServerMessage, TraceRecord, TraceStep};
+use noodle::*;
+use page_table::PhysAddr;
+
+/// Default maximum number of steps kept in a trace
+pub const DEFAULT_MAX_STEPS: usize = 64 * 1024;
+
+/// What to record while tracing an input
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+    /// Registers to record after every instruction
+    pub registers: Vec<Register>,
+
+    /// Resolve every instruction to a module + offset through the
+    /// enlightenment, rather than recording the raw RIP
+    pub modules: bool,
+
+    /// Maximum number of steps kept, older steps are dropped once the trace
+    /// is full
+    pub max_steps: usize,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        TraceConfig {
+            registers: vec![
+                Register::Rax, Register::Rbx, Register::Rcx, Register::Rdx,
+                Register::Rsi, Register::Rdi, Register::Rsp, Register::Rbp,
+                Register::R8,  Register::R9,  Register::R10, Register::R11,
+                Register::R12, Register::R13, Register::R14, Register::R15,
+                Register::Rflags,
+            ],
+            modules:   true,
+            max_steps: DEFAULT_MAX_STEPS,
+        }
+    }
+}
+
+/// State of an in-progress trace
+pub(super) struct Trace {
+    /// What to record
+    config: TraceConfig,
+
+    /// The most recent steps, oldest first
+    steps: VecDeque<TraceStep<'static>>,
+
+    /// Total number of steps recorded, including dropped ones
+    total: u64,
+
+    /// RIP of the instruction the guest is about to execute
+    pub(super) rip: u64,
+
+    /// Pages dirtied by the instruction being traced through EPT violations
+    dirtied: Vec<u64>,
+
+    /// Pages faulted in for a read by the instruction being traced
+    faulted: Vec<u64>,
+}
+
+impl Trace {
+    /// Log a page fault of the instruction being traced, which caused an EPT
+    /// violation on the guest physical address `gpaddr`
+    pub(super) fn log_fault(&mut self, gpaddr: PhysAddr, read: bool,
+                            write: bool) {
+        let page = gpaddr.0 & !0xfff;
+        if write { self.dirtied.push(page); }
+        if read  { self.faulted.push(page); }
+    }
+}
+
+impl<'a> Worker<'a> {
+    /// Returns `true` if the worker is replaying an input with
+    /// `trace_input`, in which case the input must not be changed by the
+    /// injection callback
+    pub fn replaying(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    /// Run a single fuzz case on `input` while recording an instruction
+    /// trace as configured by `config`, and upload the trace to the server
+    ///
+    /// The injection callback of the session is invoked as usual, but
+    /// `mutate` leaves the input alone. Callbacks which produce their own
+    /// input should check `replaying`.
+    pub fn trace_input(&mut self, input: &[u8], config: &TraceConfig)
+            -> VmExit {
+        // Set the input to replay
+        {
+            let mut fuzz_input = self.fuzz_input.borrow_mut();
+            fuzz_input.clear();
+            fuzz_input.extend_from_slice(input);
+        }
+
+        // Run the fuzz case with tracing enabled
+        self.trace = Some(Trace {
+            config: config.clone(),
+            steps:  VecDeque::new(),
+            total:  0,
+            rip:    0,
+            dirtied: Vec::new(),
+            faulted: Vec::new(),
+        });
+        let vmexit = self.fuzz_case();
+        let trace  = self.trace.take().unwrap();
+
+        print!("Traced {} steps, ended with {:x?}\n", trace.total, vmexit);
+
+        // Upload the trace to the server
+        let input = InputRecord {
+            hash:  self.hasher.hash(input),
+            input: Cow::Owned(Arc::new(input.to_vec())),
+        };
+        let registers = trace.config.registers.iter()
+            .map(|x| Cow::Owned(format!("{:?}", x)))
+            .collect::<Vec<_>>();
+
+        let server = self.server.as_mut()
+            .expect("Cannot upload trace without server");
+        ServerMessage::Trace(TraceRecord {
+            input:       input,
+            registers:   Cow::Owned(registers),
+            total_steps: trace.total,
+            steps:       Cow::Owned(trace.steps.into_iter().collect()),
+            vmexit:      Cow::Owned(format!("{:x?}", vmexit)),
+        }).serialize(server).unwrap();
+        server.flush().unwrap();
+
+        vmexit
+    }
+
+    /// Record the instruction which was just single stepped
+    pub(super) fn record_step(&mut self) {
+        let mut trace = self.trace.take()
+            .expect("Monitor trap without a trace");
+
+        // Resolve the instruction
+        let (module, offset) = if trace.config.modules {
+            self.resolve_module_enlightened(trace.rip)
+        } else {
+            (None, trace.rip)
+        };
+
+        // Snapshot the registers
+        let regs = trace.config.registers.iter()
+            .map(|&reg| self.reg(reg))
+            .collect::<Vec<_>>();
+
+        // Get the pages dirtied by the instruction from the PML entries
+        // logged since the last step, and from the EPT violations it caused
+        let pml_index =
+            (self.reg(Register::PmlIndex) as u16).wrapping_add(1);
+        let logged = &self.backing.vm.pml()[pml_index as usize..];
+        self.pml.extend_from_slice(logged);
+        let mut dirtied = core::mem::take(&mut trace.dirtied);
+        dirtied.extend_from_slice(logged);
+        dirtied.sort();
+        dirtied.dedup();
+
+        let mut faulted = core::mem::take(&mut trace.faulted);
+        faulted.sort();
+        faulted.dedup();
+
+        // Rewind the PML, such that the next step only sees its own entries.
+        // The logged pages are in `self.pml` and get restored by the reset
+        // at the end of the case.
+        self.set_reg(Register::PmlIndex, 511);
+
+        // Save the step, dropping the oldest one if the trace is full
+        trace.steps.push_back(TraceStep {
+            module: module.map(|x| Cow::Owned(x)),
+            offset: offset,
+            regs:   Cow::Owned(regs),
+            dirtied: Cow::Owned(dirtied),
+            faulted: Cow::Owned(faulted),
+        });
+        if trace.steps.len() > trace.config.max_steps {
+            trace.steps.pop_front();
+        }
+        trace.total += 1;
+
+        self.trace = Some(trace);
+    }
+}
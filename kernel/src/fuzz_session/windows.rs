@@ -2,20 +2,93 @@ use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::collections::BTreeMap;
 use crate::vtx::Register;
-use crate::fuzz_session::Worker;
+use crate::fuzz_session::{Worker, PagingMode};
 use page_table::VirtAddr;
 
+/// Offsets in `_LDR_DATA_TABLE_ENTRY` and `_KLDR_DATA_TABLE_ENTRY`, which
+/// share the fields we use, for a given pointer size
+struct LdrLayout {
+    /// Set if pointers are 32-bits
+    bits32: bool,
+
+    /// Offset of `InLoadOrderLinks.Blink`
+    blink: u64,
+
+    /// Offset of `DllBase`
+    dll_base: u64,
+
+    /// Offset of `SizeOfImage`
+    size_of_image: u64,
+
+    /// Offset of `BaseDllName.Length`
+    base_dll_name_length: u64,
+
+    /// Offset of `BaseDllName.Buffer`
+    base_dll_name_buffer: u64,
+}
+
+/// Loader data table entry layout for 64-bit processes and kernels
+const LDR_64: LdrLayout = LdrLayout {
+    bits32:               false,
+    blink:                0x08,
+    dll_base:             0x30,
+    size_of_image:        0x40,
+    base_dll_name_length: 0x58,
+    base_dll_name_buffer: 0x60,
+};
+
+/// Loader data table entry layout for 32-bit processes and kernels
+const LDR_32: LdrLayout = LdrLayout {
+    bits32:               true,
+    blink:                0x04,
+    dll_base:             0x18,
+    size_of_image:        0x20,
+    base_dll_name_length: 0x2c,
+    base_dll_name_buffer: 0x30,
+};
+
+/// Offset of the 32-bit TEB from the 64-bit TEB of a WoW64 thread
+const WOW64_TEB32_OFFSET: u64 = 0x2000;
+
+/// UTF-16 name of the 64-bit and non-PAE kernel image
+const NTOSKRNL: &[u8; 0x18] = b"n\0t\0o\0s\0k\0r\0n\0l\0.\0e\0x\0e\0";
+
+/// UTF-16 name of the 32-bit PAE kernel image
+const NTKRNLPA: &[u8; 0x18] = b"n\0t\0k\0r\0n\0l\0p\0a\0.\0e\0x\0e\0";
+
 /// Windows enlightenment
 #[derive(Default)]
 pub struct Enlightenment {
-    /// Address of the kernel module list (flink, blink)
-    kernel_modlist_addr: Option<(VirtAddr, VirtAddr)>,
+    /// Address of the kernel module list (flink, blink), and its layout
+    kernel_modlist_addr: Option<(VirtAddr, VirtAddr, &'static LdrLayout)>,
+}
+
+/// Read a pointer from `addr`, which is 32-bits if `bits32` is set
+fn read_ptr(worker: &mut Worker, addr: u64, bits32: bool) -> Option<u64> {
+    if bits32 {
+        worker.read_virt::<u32>(VirtAddr(addr)).map(|x| x as u64)
+    } else {
+        worker.read_virt::<u64>(VirtAddr(addr))
+    }
+}
+
+/// Read a pointer from `addr` using page table `cr3`, which is 32-bits if
+/// `bits32` is set
+fn read_ptr_cr3(worker: &mut Worker, addr: u64, bits32: bool, cr3: u64)
+        -> Option<u64> {
+    if bits32 {
+        worker.read_virt_cr3::<u32>(VirtAddr(addr), cr3).map(|x| x as u64)
+    } else {
+        worker.read_virt_cr3::<u64>(VirtAddr(addr), cr3)
+    }
 }
 
 impl Enlightenment {
-    /// Get a 64-bit Windows module list
-    fn get_module_list_win64(&mut self, worker: &mut Worker, 
-                             mut mod_flink: VirtAddr, mod_blink: VirtAddr)
+    /// Get a Windows module list by walking the `InLoadOrderLinks` of loader
+    /// data table entries with `layout`
+    fn get_module_list_ldr(&mut self, worker: &mut Worker,
+                           layout: &LdrLayout,
+                           mut mod_flink: VirtAddr, mod_blink: VirtAddr)
             -> Option<BTreeMap<u64, (u64, Arc<String>)>> {
         // Create a new module list
         let mut module_list = BTreeMap::new();
@@ -23,19 +96,20 @@ impl Enlightenment {
         // Traverse the linked list
         while mod_flink.0 != 0 {
             let mut get_mod = || {
-                let base =
-                    worker.read_virt::<u64>(VirtAddr(mod_flink.0 + 0x30))?;
-                let size =
-                    worker.read_virt::<u32>(VirtAddr(mod_flink.0 + 0x40))?;
+                let base = read_ptr(worker, mod_flink.0 + layout.dll_base,
+                                    layout.bits32)?;
+                let size = worker.read_virt::<u32>(
+                    VirtAddr(mod_flink.0 + layout.size_of_image))?;
                 if size <= 0 {
                     return None;
                 }
 
                 // Get the length of the module name unicode string
-                let name_len =
-                    worker.read_virt::<u16>(VirtAddr(mod_flink.0 + 0x58))?;
-                let name_ptr =
-                    worker.read_virt::<u64>(VirtAddr(mod_flink.0 + 0x60))?;
+                let name_len = worker.read_virt::<u16>(
+                    VirtAddr(mod_flink.0 + layout.base_dll_name_length))?;
+                let name_ptr = read_ptr(worker,
+                    mod_flink.0 + layout.base_dll_name_buffer,
+                    layout.bits32)?;
                 if name_ptr == 0 || name_len <= 0 || (name_len % 2) != 0 {
                     return None;
                 }
@@ -59,34 +133,40 @@ impl Enlightenment {
 
             // Go to the next link in the table
             if mod_flink == mod_blink { break; }
-            mod_flink.0 = worker.read_virt::<u64>(VirtAddr(mod_flink.0))?;
+            mod_flink.0 = read_ptr(worker, mod_flink.0, layout.bits32)?;
         }
 
         // Establish the new module list
         Some(module_list)
     }
     
-    /// Find the flink address of the kernel module list
-    fn find_module_list_win64_kernel(&mut self, worker: &mut Worker)
-            -> Option<(VirtAddr, VirtAddr)> {
+    /// Find the flink address of the kernel module list, by scanning around
+    /// the system call handler for the list head of `PsLoadedModuleList`
+    fn find_module_list_kernel(&mut self, worker: &mut Worker)
+            -> Option<(VirtAddr, VirtAddr, &'static LdrLayout)> {
         // Ignore non-kernel states
         if worker.cpl() != 0 { return None; }
 
-        // Get the LStar
-        let lstar = worker.reg(Register::LStar);
+        // 64-bit kernels use `syscall`, 32-bit kernels use `sysenter`
+        let (syscall, layout) = match worker.paging_mode()? {
+            PagingMode::Bits64 => (worker.reg(Register::LStar), &LDR_64),
+            _ => (worker.reg(Register::SysenterEip), &LDR_32),
+        };
 
         // Get the current CR3
         let cr3 = worker.reg(Register::Cr3);
 
-        // Scan a bit around LStar
-        for offset in (0..16 * 1024 * 1024).step_by(16) {
-            let list_addr = VirtAddr(lstar.checked_add(offset)?);
+        // Scan a bit around the system call handler
+        let step = if layout.bits32 { 4 } else { 16 };
+        for offset in (0..16 * 1024 * 1024).step_by(step) {
+            let list_addr = VirtAddr(syscall.checked_add(offset)?);
 
             // Read what might be a pointer at this location
-            if let Some(flink) = worker.read_virt_cr3::<u64>(list_addr, cr3) {
+            if let Some(flink) = read_ptr_cr3(worker, list_addr.0,
+                                              layout.bits32, cr3) {
                 // _KLDR_DATA_TABLE_ENTRY.InLoadOrderLinks.Blink
-                let blink = worker.read_virt_cr3::<u64>(
-                    VirtAddr(flink.wrapping_add(0x08)), cr3);
+                let blink = read_ptr_cr3(worker,
+                    flink.wrapping_add(layout.blink), layout.bits32, cr3);
 
                 // Make sure the blink for the first entry of the list refers
                 // to the list start. If it does not, this is probably not
@@ -95,11 +175,13 @@ impl Enlightenment {
                 
                 // _KLDR_DATA_TABLE_ENTRY.BaseDllName.Length
                 let size = worker.read_virt_cr3::<u16>(
-                    VirtAddr(flink.wrapping_add(0x58)), cr3);
+                    VirtAddr(flink.wrapping_add(layout.base_dll_name_length)),
+                    cr3);
 
                 // _KLDR_DATA_TABLE_ENTRY.BaseDllName.Buffer
-                let nameptr = worker.read_virt_cr3::<u64>(
-                    VirtAddr(flink.wrapping_add(0x60)), cr3);
+                let nameptr = read_ptr_cr3(worker,
+                    flink.wrapping_add(layout.base_dll_name_buffer),
+                    layout.bits32, cr3);
 
                 // Make sure the length is 0x18 and all reads succeeded
                 if let (Some(0x18), Some(nameptr)) = (size, nameptr) {
@@ -109,9 +191,11 @@ impl Enlightenment {
                     // Read the name
                     if worker.read_virt_cr3_into(
                             VirtAddr(nameptr), &mut buf, cr3).is_some() {
-                        // Check if the module name is "ntoskrnl.exe"
-                        if &buf == b"n\0t\0o\0s\0k\0r\0n\0l\0.\0e\0x\0e\0" {
-                            return Some((VirtAddr(flink), VirtAddr(blink?)));
+                        // Check if the module name is "ntoskrnl.exe", or
+                        // "ntkrnlpa.exe" for 32-bit PAE kernels
+                        if &buf == NTOSKRNL || &buf == NTKRNLPA {
+                            return Some((VirtAddr(flink), VirtAddr(blink?),
+                                         layout));
                         }
                     }
                 }
@@ -121,6 +205,60 @@ impl Enlightenment {
         // Couldn't find it
         None
     }
+
+    /// Get the module list of a 32-bit process from its 32-bit `teb`
+    fn get_module_list_user32(&mut self, worker: &mut Worker, teb: u64)
+            -> Option<BTreeMap<u64, (u64, Arc<String>)>> {
+        // Get the address of the `_PEB32`
+        let peb = worker.read_virt::<u32>(VirtAddr(teb + 0x30))? as u64;
+
+        // Get the address of the `_PEB_LDR_DATA32`
+        let peb_ldr_data = worker.read_virt::<u32>(VirtAddr(peb + 0xc))? as u64;
+
+        // Get the in load order module list links
+        let mod_flink =
+            worker.read_virt::<u32>(VirtAddr(peb_ldr_data + 0x0c))? as u64;
+        let mod_blink =
+            worker.read_virt::<u32>(VirtAddr(peb_ldr_data + 0x10))? as u64;
+
+        self.get_module_list_ldr(worker, &LDR_32, VirtAddr(mod_flink),
+            VirtAddr(mod_blink))
+    }
+
+    /// Get the module list of a 64-bit process from its `teb`
+    fn get_module_list_user64(&mut self, worker: &mut Worker, teb: u64)
+            -> Option<BTreeMap<u64, (u64, Arc<String>)>> {
+        // Get the address of the `_PEB`
+        let peb = worker.read_virt::<u64>(VirtAddr(teb + 0x60))?;
+
+        // Get the address of the `_PEB_LDR_DATA`
+        let peb_ldr_data = worker.read_virt::<u64>(VirtAddr(peb + 0x18))?;
+
+        // Get the in load order module list links
+        let mod_flink =
+            worker.read_virt::<u64>(VirtAddr(peb_ldr_data + 0x10))?;
+        let mod_blink =
+            worker.read_virt::<u64>(VirtAddr(peb_ldr_data + 0x18))?;
+
+        self.get_module_list_ldr(worker, &LDR_64, VirtAddr(mod_flink),
+            VirtAddr(mod_blink))
+    }
+
+    /// Get the 32-bit TEB of a WoW64 thread, if the current thread is one
+    fn wow64_teb32(&mut self, worker: &mut Worker) -> Option<u64> {
+        // In compatibility mode the FS base is the 32-bit TEB, otherwise it
+        // is at a fixed offset from the 64-bit TEB
+        let teb32 = if worker.reg(Register::CsAccessRights) & (1 << 13) == 0 {
+            worker.reg(Register::FsBase)
+        } else {
+            worker.reg(Register::GsBase).checked_add(WOW64_TEB32_OFFSET)?
+        };
+
+        // Make sure `_NT_TIB32.Self` refers to the TEB, otherwise this is not
+        // a WoW64 thread
+        let tib_self = worker.read_virt::<u32>(VirtAddr(teb32 + 0x18))?;
+        if tib_self as u64 == teb32 { Some(teb32) } else { None }
+    }
 }
 
 impl crate::fuzz_session::Enlightenment for Enlightenment {
@@ -129,34 +267,44 @@ impl crate::fuzz_session::Enlightenment for Enlightenment {
         if worker.cpl() == 0 {
             if self.kernel_modlist_addr.is_none() {
                 self.kernel_modlist_addr =
-                    Some(self.find_module_list_win64_kernel(worker)?);
+                    Some(self.find_module_list_kernel(worker)?);
             }
 
-            if let Some((flink, blink)) = self.kernel_modlist_addr {
-                let tmp = self.get_module_list_win64(worker, flink, blink)?;
+            if let Some((flink, blink, layout)) = self.kernel_modlist_addr {
+                let tmp =
+                    self.get_module_list_ldr(worker, layout, flink, blink)?;
                 Some(tmp)
             } else {
                 None
             }
         } else {
-            // Get the base of the TEB
-            let gs_base = worker.reg(Register::GsBase);
-
-            // Get the address of the `_PEB`
-            let peb = worker.read_virt::<u64>(VirtAddr(gs_base + 0x60))?;
+            match worker.paging_mode()? {
+                PagingMode::Bits64 => {
+                    // Get the 64-bit module list from the 64-bit TEB
+                    let teb = worker.reg(Register::GsBase);
+                    let modules = self.get_module_list_user64(worker, teb);
 
-            // Get the address of the `_PEB_LDR_DATA`
-            let peb_ldr_data = worker.read_virt::<u64>(VirtAddr(peb + 0x18))?;
+                    // WoW64 processes also have 32-bit modules, merge them
+                    // into the 64-bit module list
+                    let modules32 = self.wow64_teb32(worker)
+                        .and_then(|teb32| {
+                            self.get_module_list_user32(worker, teb32)
+                        });
 
-            // Get the in load order module list links
-            let mod_flink =
-                worker.read_virt::<u64>(VirtAddr(peb_ldr_data + 0x10))?;
-            let mod_blink =
-                worker.read_virt::<u64>(VirtAddr(peb_ldr_data + 0x18))?;
-
-            self.get_module_list_win64(worker, VirtAddr(mod_flink),
-                VirtAddr(mod_blink))
+                    match (modules, modules32) {
+                        (Some(mut modules), Some(modules32)) => {
+                            modules.extend(modules32);
+                            Some(modules)
+                        }
+                        (modules, modules32) => modules.or(modules32),
+                    }
+                }
+                _ => {
+                    // 32-bit guests have the TEB in the FS base
+                    let teb = worker.reg(Register::FsBase);
+                    self.get_module_list_user32(worker, teb)
+                }
+            }
         }
     }
 }
-
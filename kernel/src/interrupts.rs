@@ -51,6 +51,21 @@ impl Drop for FaultReg {
     }
 }
 
+impl FaultReg {
+    /// Invoke `func` with exclusive access to the registered handler. No page
+    /// faults are handled while `func` runs, thus `func` must not touch
+    /// memory which is lazily mapped in by a fault handler.
+    ///
+    /// The caller must make sure the registered handler is of type `T`
+    pub unsafe fn with_handler<T, R, F>(&self, func: F) -> R
+            where F: FnOnce(&mut T) -> R {
+        // Hold the fault handler lock such that no fault is dispatched to
+        // the handler while we are using it
+        let _handlers = PAGE_FAULT_HANDLERS.lock();
+        func(&mut *(self.0 as *const T as *mut T))
+    }
+}
+
 /// Register a page fault handler
 pub fn register_fault_handler(handler: Box<dyn PageFaultHandler>) -> FaultReg {
     let ptr = &*handler as *const dyn PageFaultHandler;
@@ -28,9 +28,9 @@ pub mod net;
 pub mod time;
 pub mod vtx;
 pub mod fuzz_session;
+pub mod minidump;
 pub mod test_fuzzer;
 pub mod ept;
-pub mod paging;
 
 use page_table::PhysAddr;
 
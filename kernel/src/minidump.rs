@@ -18,6 +18,9 @@
 use core::convert::TryInto;
 use alloc::collections::BTreeMap;
 
+use crate::fuzz_session::Worker;
+use page_table::VirtAddr;
+
 /// 64-bit AMD64 `_CONTEXT` structure from Windows
 #[repr(C)]
 pub struct Context64 {
@@ -62,11 +65,92 @@ pub struct Context64 {
     pub fxsave:        [u8; 976],
 }
 
+/// 64-bit AMD64 `_KSPECIAL_REGISTERS` structure from Windows
+#[repr(C)]
+pub struct KSpecialRegisters {
+    pub cr0:                     u64,
+    pub cr2:                     u64,
+    pub cr3:                     u64,
+    pub cr4:                     u64,
+    pub kernel_dr0:              u64,
+    pub kernel_dr1:              u64,
+    pub kernel_dr2:              u64,
+    pub kernel_dr3:              u64,
+    pub kernel_dr6:              u64,
+    pub kernel_dr7:              u64,
+    pub gdtr_pad:                [u16; 3],
+    pub gdtr_limit:              u16,
+    pub gdtr_base:               u64,
+    pub idtr_pad:                [u16; 3],
+    pub idtr_limit:              u16,
+    pub idtr_base:               u64,
+    pub tr:                      u16,
+    pub ldtr:                    u16,
+    pub mxcsr:                   u32,
+    pub debug_control:           u64,
+    pub last_branch_to_rip:      u64,
+    pub last_branch_from_rip:    u64,
+    pub last_exception_to_rip:   u64,
+    pub last_exception_from_rip: u64,
+    pub cr8:                     u64,
+    pub msr_gs_base:             u64,
+    pub msr_gs_swap:             u64,
+    pub msr_star:                u64,
+    pub msr_lstar:               u64,
+    pub msr_cstar:               u64,
+    pub msr_syscall_mask:        u64,
+    pub xcr0:                    u64,
+    pub msr_fs_base:             u64,
+    pub special_padding0:        u64,
+}
+
+impl KSpecialRegisters {
+    /// Parse a `_KSPECIAL_REGISTERS` from the raw bytes read from the guest
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        assert!(core::mem::size_of::<KSpecialRegisters>() == 0xf0,
+            "Whoa, special registers size mismatch");
+
+        let buf = buf.get(..core::mem::size_of::<KSpecialRegisters>())?;
+        Some(unsafe {
+            core::ptr::read_unaligned(buf.as_ptr() as *const KSpecialRegisters)
+        })
+    }
+}
+
+/// Offsets of fields in `nt!_KDDEBUGGER_DATA64`, the structure at
+/// `nt!KdDebuggerDataBlock`
+pub mod kd_debugger_data {
+    /// Offset of the `OwnerTag` in the header, this is `KDBG` unless the
+    /// block is encoded
+    pub const OWNER_TAG: u64 = 0x10;
+
+    /// Offset of `KiProcessorBlock`, the address of the array of `_KPRCB`
+    /// pointers for each processor
+    pub const KI_PROCESSOR_BLOCK: u64 = 0x218;
+
+    /// Offset of `OffsetPrcbProcStateContext`, a `u16` offset in the `_KPRCB`
+    /// to the `_CONTEXT` of the processor
+    pub const OFFSET_PRCB_PROC_STATE_CONTEXT: u64 = 0x2bc;
+
+    /// Offset of `OffsetPrcbProcStateSpecialReg`, a `u16` offset in the
+    /// `_KPRCB` to the `_KSPECIAL_REGISTERS` of the processor
+    pub const OFFSET_PRCB_PROC_STATE_SPECIAL_REG: u64 = 0x2f2;
+}
+
+/// Offset of `Rsp` in a `_CONTEXT`
+pub const CONTEXT_RSP_OFFSET: u64 = 0x98;
+
 /// A parsed minidump
 pub struct MiniDump {
     /// Mapping for physical address bases into the (file offset, size)
     pub phys_ranges: BTreeMap<u64, (usize, usize)>,
 
+    /// Kernel page table at the time of the dump
+    pub directory_table_base: u64,
+
+    /// Number of processors in the system
+    pub num_processors: u32,
+
     /// Address of `nt!KdDebuggerDataBlock`
     pub kd_debugger_data_block: u64,
 
@@ -84,6 +168,20 @@ impl MiniDump {
 
         if buf.get(..8) != Some(b"PAGEDU64") { return None; }
 
+        // Only full dumps have the physical memory described by runs
+        let dump_type = u32::from_le_bytes(
+            buf.get(0xf98..0xf9c)?.try_into().ok()?);
+        if dump_type != 1 { return None; }
+
+        // Get the kernel page table, number of processors, and the address of
+        // the `KdDebuggerDataBlock`
+        let directory_table_base = u64::from_le_bytes(
+            buf.get(0x10..0x18)?.try_into().ok()?);
+        let num_processors = u32::from_le_bytes(
+            buf.get(0x34..0x38)?.try_into().ok()?);
+        let kd_debugger_data_block = u64::from_le_bytes(
+            buf.get(0x80..0x88)?.try_into().ok()?);
+
         // Get the number of physical memory regions in the full memory dump
         let num_runs = u32::from_le_bytes(
             buf.get(0x88..0x8c)?.try_into().ok()?);
@@ -114,6 +212,9 @@ impl MiniDump {
                     .try_into().ok()?;
             let region_size = region_size.checked_mul(4096)?;
 
+            // Skip empty runs
+            if region_size == 0 { continue; }
+
             // Add this range to the minidump database
             phys_ranges.insert(base_paddr, (page_offset, region_size));
 
@@ -123,15 +224,110 @@ impl MiniDump {
 
         // Make sure we consumed the entire file, if we did not, there's some
         // extra trailing data we did not expect
-        assert!(page_offset == buf.len());
+        if page_offset != buf.len() { return None; }
 
         Some(MiniDump {
             phys_ranges,
+            directory_table_base,
+            num_processors,
+            kd_debugger_data_block,
             context,
         })
     }
 }
 
+impl MiniDump {
+    /// Read the `_KSPECIAL_REGISTERS` of the processor which `context`
+    /// belongs to from the guest memory of `worker`
+    ///
+    /// `worker` must have the memory of the minidump, and paging enabled with
+    /// `directory_table_base` as the page table.
+    pub fn special_registers(&self, worker: &mut Worker)
+            -> Option<KSpecialRegisters> {
+        let kdbg = self.kd_debugger_data_block;
+
+        // Make sure the debugger data block is not encoded
+        let mut owner_tag = [0u8; 4];
+        worker.read_virt_into(VirtAddr(kdbg + kd_debugger_data::OWNER_TAG),
+                              &mut owner_tag)?;
+        if &owner_tag != b"KDBG" { return None; }
+
+        // Get the processor block and the offsets into the `_KPRCB`s
+        let processor_block: u64 = worker.read_virt(
+            VirtAddr(kdbg + kd_debugger_data::KI_PROCESSOR_BLOCK))?;
+        let context_offset: u16 = worker.read_virt(VirtAddr(
+            kdbg + kd_debugger_data::OFFSET_PRCB_PROC_STATE_CONTEXT))?;
+        let special_offset: u16 = worker.read_virt(VirtAddr(
+            kdbg + kd_debugger_data::OFFSET_PRCB_PROC_STATE_SPECIAL_REG))?;
+
+        // Find the processor whose saved context has the same stack as the
+        // context of the dump, defaulting to the first processor
+        let mut prcb = None;
+        for cpu in 0..self.num_processors as u64 {
+            let cur: u64 = worker.read_virt(
+                VirtAddr(processor_block + cpu * 8))?;
+            let rsp: Option<u64> = worker.read_virt(VirtAddr(
+                cur + context_offset as u64 + CONTEXT_RSP_OFFSET));
+
+            if prcb.is_none() || rsp == Some(self.context.rsp) {
+                prcb = Some(cur);
+            }
+            if rsp == Some(self.context.rsp) { break; }
+        }
+
+        // Read the special registers
+        let mut special = [0u8; core::mem::size_of::<KSpecialRegisters>()];
+        worker.read_virt_into(VirtAddr(prcb? + special_offset as u64),
+                              &mut special)?;
+        KSpecialRegisters::parse(&special)
+    }
+}
+
+/// Read the descriptor for `selector` from the GDT at `gdtr_base` in the
+/// guest memory of `worker`, in the format QEMU saves segments in
+///
+/// `system` is set for system segments (TR and LDTR), which have 16-byte
+/// descriptors in long mode
+pub fn read_segment(worker: &mut Worker, gdtr_base: u64, gdtr_limit: u16,
+                    selector: u16, system: bool)
+        -> Option<falkdump::Segment> {
+    let mut segment = falkdump::Segment::default();
+    segment.selector = selector as u32;
+
+    // Null selectors, and selectors in the LDT, are left unusable
+    if selector & !3 == 0 || selector & 4 != 0 {
+        return Some(segment);
+    }
+
+    // Make sure the descriptor is in the GDT
+    let offset = (selector & !7) as u64;
+    let size   = if system { 16 } else { 8 };
+    if offset + size - 1 > gdtr_limit as u64 {
+        return None;
+    }
+
+    // Read the descriptor, system descriptors have the high 32 bits of the
+    // base in the next 8 bytes
+    let low: u64 = worker.read_virt(VirtAddr(gdtr_base + offset))?;
+    let high: u64 = if system {
+        worker.read_virt(VirtAddr(gdtr_base + offset + 8))?
+    } else {
+        0
+    };
+
+    // Decode the limit, scaling it if G is set
+    let mut limit = (low & 0xffff) | ((low >> 32) & 0xf0000);
+    if low & (1 << 55) != 0 {
+        limit = (limit << 12) | 0xfff;
+    }
+
+    segment.limit = limit as u32;
+    segment.flags = ((low >> 32) as u32) & 0x00f0ff00;
+    segment.base  = ((low >> 16) & 0xffffff) | ((low >> 32) & 0xff000000) |
+        ((high & 0xffffffff) << 32);
+    Some(segment)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -3,12 +3,14 @@
 use core::ops::{Deref, DerefMut};
 use core::alloc::Layout;
 use core::convert::TryInto;
+use alloc::vec::Vec;
 use alloc::boxed::Box;
 use alloc::borrow::Cow;
 use noodle::*;
+use falkhash::FalkHasher;
 use falktp::ServerMessage;
 use page_table::{VirtAddr, PageType, PhysMem};
-use page_table::{PAGE_NX, PAGE_WRITE, PAGE_PRESENT};
+use page_table::{PhysAddr, PAGE_NX, PAGE_WRITE, PAGE_PRESENT, PAGE_DIRTY};
 use lockcell::LockCell;
 use crate::core_locals::LockInterrupts;
 use crate::mm::{self, PhysicalMemory};
@@ -16,7 +18,18 @@ use crate::net::NetDevice;
 use crate::net::tcp::TcpConnection;
 use crate::interrupts::{register_fault_handler, FaultReg, PageFaultHandler};
 
-/// Structure to handle `NetMapping` page faults
+/// Number of pages to fault in from the server on a single page fault. Pages
+/// following the faulting page are prefetched as long as they are not mapped
+/// yet, saving a round trip to the server for sequential accesses. Must not
+/// exceed `falktp::MAX_READ_PAGES`.
+const READ_AHEAD: usize = 64;
+
+/// Number of times a page which fails its integrity check is requested again
+/// before giving up on it
+const READ_RETRIES: usize = 8;
+
+/// Structure to handle `NetMapping` page faults, and to write back modified
+/// pages of writable mappings
 pub struct NetMapHandler {
     /// Virtual address of the base of the mapping
     vaddr: VirtAddr,
@@ -27,17 +40,151 @@ pub struct NetMapHandler {
     /// File ID of the open file on the server
     file_id: u64,
 
+    /// Generation of the file on the server which we have mapped
+    generation: u64,
+
+    /// Expected hash of each page in the file, verified against the content
+    /// hash of the file
+    page_hashes: Vec<u128>,
+
+    /// Hasher used to verify pages
+    hasher: FalkHasher,
+
     /// Size of the file in bytes
     size: usize,
 
     /// Set to `true` if this is a read only mapping
     read_only: bool,
 
+    /// Core which created the mapping. A writable mapping may only be
+    /// accessed from this core, see `flush()`.
+    core_id: u32,
+
     /// Used to prevent multiple cores from handling the exception at the
     /// same time.
     handling: LockCell<(), LockInterrupts>,
 }
 
+impl NetMapHandler {
+    /// Write all pages which were modified since the last flush back to the
+    /// server, and wait for the server to commit them to disk
+    ///
+    /// Modified pages are found by the dirty bit the CPU sets in the page
+    /// table entry on a write. The dirty bit is cleared before the page is
+    /// sent, thus writes which race with the flush are sent by the next
+    /// flush.
+    ///
+    /// There is no TLB shootdown, so clearing the dirty bit only takes effect
+    /// on the current core. This is why writable mappings are restricted to
+    /// the core which created them: no other core can hold a TLB entry which
+    /// would let it write to a page without setting its dirty bit again.
+    unsafe fn flush(&mut self) {
+        assert!(core!().id == self.core_id,
+                "Writable network mapping flushed from another core");
+
+        // Find all dirty pages and clear their dirty bits, as the offset into
+        // the file and the physical address of the page
+        let dirty: Vec<(usize, PhysAddr)> = {
+            // Get access to physical memory
+            let mut pmem = PhysicalMemory;
+
+            // Get access to virtual memory
+            let mut page_table = core!().boot_args.page_table.lock();
+            let page_table = page_table.as_mut().unwrap();
+
+            let mut dirty = Vec::new();
+            for offset in (0..self.size).step_by(4096) {
+                let vaddr = VirtAddr(self.vaddr.0 + offset as u64);
+
+                // Get the page table entry, skipping pages which were never
+                // faulted in
+                let mapping = page_table.translate(&mut pmem, vaddr);
+                let (pte, (page, _, ent)) = match mapping
+                        .and_then(|x| Some((x.pte?, x.page?))) {
+                    Some(x) => x,
+                    None    => continue,
+                };
+
+                // Skip pages which have not been written to
+                if (ent & PAGE_DIRTY) == 0 { continue; }
+
+                // Clear the dirty bit, and invalidate the TLB entry such
+                // that the next write to the page sets it again
+                mm::write_phys(pte, ent & !PAGE_DIRTY);
+                cpu::invlpg(vaddr.0 as usize);
+
+                dirty.push((offset, page));
+            }
+
+            dirty
+        };
+
+        // Send the dirty pages to the server
+        for (offset, page) in dirty {
+            let mut contents = [0u8; 4096];
+            contents.copy_from_slice(mm::slice_phys(page, 4096));
+
+            ServerMessage::WritePage {
+                id:         self.file_id,
+                generation: self.generation,
+                offset:     offset,
+                contents:   contents,
+            }.serialize(&mut self.tcp).unwrap();
+        }
+
+        // Wait for the server to commit the writes
+        ServerMessage::Flush {
+            id:         self.file_id,
+            generation: self.generation,
+        }.serialize(&mut self.tcp).unwrap();
+        self.tcp.flush();
+
+        match ServerMessage::deserialize(&mut self.tcp) {
+            Some(ServerMessage::FlushResponse) => {}
+            _ => panic!("Unexpected server message during flush"),
+        }
+    }
+}
+
+impl NetMapHandler {
+    /// Receive a `ReadPageResponse` from the server into the physical page
+    /// `page`, and check it against the hash of the page at `page_idx` in
+    /// the file
+    ///
+    /// Returns `true` if the page is the one from the generation we mapped
+    unsafe fn recv_page(&mut self, page: PhysAddr, page_idx: usize) -> bool {
+        // Get a mutable slice to the physical memory backing the page
+        let new_page = mm::slice_phys_mut(page, 4096);
+
+        // Receive the raw payload
+        match ServerMessage::deserialize(&mut self.tcp) {
+            Some(ServerMessage::ReadPageResponse(page)) => {
+                new_page.copy_from_slice(&page);
+            }
+            _ => panic!("Unexpected server message during read page"),
+        }
+
+        self.hasher.hash(new_page) == self.page_hashes[page_idx]
+    }
+
+    /// Map the physical page `page` into the mapping at `vaddr`
+    unsafe fn map_page(&self, vaddr: VirtAddr, page: PhysAddr) {
+        // Get access to physical memory
+        let mut pmem = PhysicalMemory;
+
+        // Get access to virtual memory
+        let mut page_table = core!().boot_args.page_table.lock();
+        let page_table = page_table.as_mut().unwrap();
+
+        // Map in the memory as RW
+        page_table.map_raw(&mut pmem, vaddr, PageType::Page4K,
+                           page.0 | PAGE_NX |
+                           if self.read_only { 0 } else { PAGE_WRITE } |
+                           PAGE_PRESENT)
+            .expect("Failed to map in network mapped memory");
+    }
+}
+
 impl PageFaultHandler for NetMapHandler {
     unsafe fn page_fault(&mut self, fault_addr: VirtAddr, code: u64) -> bool {
         // Compute the ending virtual address for our mapping
@@ -48,14 +195,24 @@ impl PageFaultHandler for NetMapHandler {
             return false;
         }
 
+        // Writable mappings may only be accessed by the core which created
+        // them
+        if !self.read_only && core!().id != self.core_id {
+            return false;
+        }
+
         // Check if this fault happened in our mapping range
         if fault_addr >= self.vaddr && fault_addr <= end {
             // Prevent 2 handlers at the same time
             let _lock = self.handling.lock();
 
-            // Check if someone already mapped in the memory, this is possible
-            // if we lost the race
-            {
+            // Page align the fault address
+            let fault_page = VirtAddr(fault_addr.0 & !0xfff);
+
+            // Determine the number of pages to fault in. This is the faulting
+            // page followed by as many unmapped pages as we allow to read
+            // ahead
+            let count = {
                 // Get access to physical memory
                 let mut pmem = PhysicalMemory;
 
@@ -63,63 +220,97 @@ impl PageFaultHandler for NetMapHandler {
                 let mut page_table = core!().boot_args.page_table.lock();
                 let page_table = page_table.as_mut().unwrap();
 
-                // Map in the memory as RW
-                if page_table.translate(&mut pmem,
-                                        VirtAddr(fault_addr.0 & !0xfff))
-                        .map(|x| x.page).flatten().is_some() {
-                    // This has already been handled by another core
-                    return true;
+                let mut count = 0;
+                while count < READ_AHEAD {
+                    // Stop at the end of the mapping
+                    let vaddr = VirtAddr(fault_page.0 + count as u64 * 4096);
+                    if vaddr > end { break; }
+
+                    // Stop at the first page which is already mapped. If this
+                    // is the faulting page, someone else already handled it
+                    // as we lost the race
+                    if page_table.translate(&mut pmem, vaddr)
+                            .map(|x| x.page).flatten().is_some() {
+                        break;
+                    }
+
+                    count += 1;
                 }
+
+                count
+            };
+
+            // This has already been handled by another core
+            if count == 0 {
+                return true;
             }
 
             // Compute the offset into the mapping that this fault represents
-            // and page align it
-            let offset = ((fault_addr.0 & !0xfff) - self.vaddr.0) as usize;
+            let offset = (fault_page.0 - self.vaddr.0) as usize;
 
             // Request the file contents at this offset
-            ServerMessage::ReadPage {
-                id:     self.file_id,
-                offset: offset,
+            ServerMessage::ReadPages {
+                id:         self.file_id,
+                generation: self.generation,
+                offset:     offset,
+                count:      count,
             }.serialize(&mut self.tcp).unwrap();
             self.tcp.flush();
 
-            // Allocate the backing page for the mapping
-            let page = {
-                // Get access to physical memory
-                let mut pmem = PhysicalMemory;
+            // The server responds with one page at a time
+            let mut corrupt = Vec::new();
+            for ii in 0..count {
+                // Allocate the backing page for the mapping
+                let page = {
+                    // Get access to physical memory
+                    let mut pmem = PhysicalMemory;
+
+                    // Allocate a page
+                    pmem.alloc_phys(
+                        Layout::from_size_align(4096, 4096).unwrap()).unwrap()
+                };
+
+                // Make sure the page is the one from the generation we
+                // mapped, pages which are not are requested again below
+                if !self.recv_page(page, offset / 4096 + ii) {
+                    corrupt.push((ii, page));
+                    continue;
+                }
 
-                // Allocate a page
-                pmem.alloc_phys(Layout::from_size_align(4096, 4096).unwrap())
-                    .unwrap()
-            };
+                self.map_page(VirtAddr(fault_page.0 + ii as u64 * 4096), page);
+            }
 
-            // Get a mutable slice to the physical memory backing the page
-            let new_page = mm::slice_phys_mut(page, 4096);
+            // Request the pages which failed their integrity check again.
+            // Pages which keep failing are left unmapped, and if this is the
+            // faulting page the fault is reported as not handled.
+            for (ii, page) in corrupt {
+                let page_offset = offset + ii * 4096;
+
+                let mut valid = false;
+                for _ in 0..READ_RETRIES {
+                    ServerMessage::ReadPage {
+                        id:         self.file_id,
+                        generation: self.generation,
+                        offset:     page_offset,
+                    }.serialize(&mut self.tcp).unwrap();
+                    self.tcp.flush();
+
+                    if self.recv_page(page, page_offset / 4096) {
+                        valid = true;
+                        break;
+                    }
+                }
 
-            // Receive the raw payload
-            match ServerMessage::deserialize(&mut self.tcp) {
-                Some(ServerMessage::ReadPageResponse(page)) => {
-                    new_page.copy_from_slice(&page);
+                if valid {
+                    self.map_page(
+                        VirtAddr(fault_page.0 + ii as u64 * 4096), page);
+                } else {
+                    print!("Network mapped page at offset {:#x} failed \
+                            integrity check\n", page_offset);
+                    if ii == 0 { return false; }
                 }
-                _ => panic!("Unexpected server message during read page"),
             }
 
-            // Get access to physical memory
-            let mut pmem = PhysicalMemory;
-
-            // Get access to virtual memory
-            let mut page_table = core!().boot_args.page_table.lock();
-            let page_table = page_table.as_mut().unwrap();
-
-            // Map in the memory as RW
-            page_table.map_raw(&mut pmem,
-                               VirtAddr(fault_addr.0 & !0xfff),
-                               PageType::Page4K,
-                               page.0 | PAGE_NX |
-                               if self.read_only { 0 } else { PAGE_WRITE } |
-                               PAGE_PRESENT)
-                .expect("Failed to map in network mapped memory");
-
             true
         } else {
             false
@@ -128,13 +319,19 @@ impl PageFaultHandler for NetMapHandler {
 }
 
 /// A network backed mapping of `u8`s which will be faulted in upon access per
-/// page
+/// page. Writes to a writable mapping are sent back to the server on a
+/// `flush()`, or when the mapping is dropped.
+///
+/// A writable mapping must only be accessed from the core which created it,
+/// as its dirty pages are tracked through that core's TLB. Faults from other
+/// cores are not handled, and writes from other cores to pages which are
+/// already faulted in may be lost. Read only mappings can be shared freely.
 pub struct NetMapping<'a> {
     /// Slice to the raw contents of the mapping
     backing: &'a mut [u8],
  
     /// Registration for the fault handler
-    _fault_reg: FaultReg,
+    fault_reg: FaultReg,
 
     /// Tracks if this network mapping is read only
     read_only: bool,
@@ -143,6 +340,9 @@ pub struct NetMapping<'a> {
 impl<'a> NetMapping<'a> {
     /// Create a network mapped view of `filename`
     /// `server` should be the `ip:port` for the server
+    ///
+    /// Returns `None` if the file cannot be mapped, which includes the file
+    /// not existing on the server, such that optional files can be probed
     pub fn new(server: &str, filename: &str, read_only: bool) -> Option<Self> {
         // Get access to a network device
         let netdev = NetDevice::get()?;
@@ -151,30 +351,58 @@ impl<'a> NetMapping<'a> {
         let mut tcp = BufferedIo::new(NetDevice::tcp_connect(netdev, server)?);
 
         // Send the get file ID request
-        ServerMessage::GetFileId(Cow::Borrowed(filename)).serialize(&mut tcp);
+        ServerMessage::GetFileId {
+            filename: Cow::Borrowed(filename),
+            writable: !read_only,
+        }.serialize(&mut tcp);
         tcp.flush();
 
         // Get the response
-        let (file_id, size) = match ServerMessage::deserialize(&mut tcp)? {
-            ServerMessage::FileId { id, size } => (id, size),
+        let (file_id, generation, hash, size) =
+                match ServerMessage::deserialize(&mut tcp)? {
+            ServerMessage::FileId { id, generation, hash, size } =>
+                (id, generation, hash, size),
+            // The server replies `NoSuchFile` if the file does not exist
             _ => return None,
         };
 
         // Nothing to map
         if size <= 0 { return None; }
 
+        // Get the hashes of each page in the file
+        ServerMessage::GetPageHashes {
+            id:         file_id,
+            generation: generation,
+        }.serialize(&mut tcp);
+        tcp.flush();
+        let page_hashes = match ServerMessage::deserialize(&mut tcp)? {
+            ServerMessage::PageHashes(hashes) => hashes.into_owned(),
+            _ => return None,
+        };
+
+        // Verify the page hashes against the content hash of the file
+        let hasher = FalkHasher::new();
+        if page_hashes.len() != size.checked_add(0xfff)? / 4096 ||
+                falktp::file_hash(&hasher, &page_hashes) != hash {
+            return None;
+        }
+
         // Allocate virtual memory capable of holding the file
         let size_align = size.checked_add(0xfff)? & !0xfff;
         let virt_addr  = crate::mm::alloc_virt_addr_4k(size_align as u64);
 
         // Create a fault handler entry
         let handler = Box::new(NetMapHandler {
-            vaddr:     virt_addr,
-            file_id:   file_id,
-            tcp:       tcp,
-            size:      size,
-            read_only: read_only,
-            handling:  LockCell::new(()),
+            vaddr:       virt_addr,
+            file_id:     file_id,
+            generation:  generation,
+            page_hashes: page_hashes,
+            hasher:      hasher,
+            tcp:         tcp,
+            size:        size,
+            read_only:   read_only,
+            core_id:     core!().id,
+            handling:    LockCell::new(()),
         });
 
         Some(NetMapping {
@@ -182,12 +410,34 @@ impl<'a> NetMapping<'a> {
                 core::slice::from_raw_parts_mut(virt_addr.0 as *mut u8,
                                                 size.try_into().ok()?)
             },
-            _fault_reg: register_fault_handler(handler),
+            fault_reg: register_fault_handler(handler),
             read_only,
         })
     }
 }
 
+impl<'a> NetMapping<'a> {
+    /// Write all modified pages back to the file on the server, returns once
+    /// the server has committed them to disk. Does nothing for read only
+    /// mappings.
+    pub fn flush(&mut self) {
+        if self.read_only { return; }
+
+        unsafe {
+            self.fault_reg.with_handler(|handler: &mut NetMapHandler| {
+                handler.flush();
+            });
+        }
+    }
+}
+
+impl<'a> Drop for NetMapping<'a> {
+    fn drop(&mut self) {
+        // Make sure writes are not lost
+        self.flush();
+    }
+}
+
 impl<'a> Deref for NetMapping<'a> {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
@@ -49,6 +49,15 @@ pub unsafe fn attempt_soft_reboot() {
     }
 }
 
+/// Soft reboot the system, regardless of the serial port
+pub fn request_soft_reboot(reason: &str) -> ! {
+    // Request a soft reboot
+    SOFT_REBOOT_REQUESTED.store(true, Ordering::SeqCst);
+
+    // Force a panic, which will perform the reboot
+    panic!("Soft reboot requested: {}", reason);
+}
+
 /// Disable all cores on the system, making sure they check in when they stop
 pub unsafe fn disable_all_cores(apic: &mut Apic) {
     // Make sure we're on the BSP
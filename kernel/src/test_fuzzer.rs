@@ -1,52 +1,133 @@
 use alloc::sync::Arc;
 use alloc::boxed::Box;
+use alloc::string::String;
 
 //use crate::vtx::Register;
 use crate::core_locals::LockInterrupts;
-use crate::fuzz_session::{Worker, FuzzSession};
+use crate::fuzz_session::{Worker, FuzzSession, Enlightenment};
+use crate::fuzz_session::{linux, windows};
 
 use lockcell::LockCell;
 
+/// Operating systems of the guests which can be fuzzed
+#[allow(dead_code)]
+enum GuestOs {
+    Windows,
+
+    /// Linux snapshots must ship with a `<snapshot>.linux` config of the
+    /// kernel structures
+    Linux,
+}
+
+/// Operating system of the guest in the snapshots being fuzzed
+const GUEST_OS: GuestOs = GuestOs::Windows;
+
+/// Set if the snapshots ship with a `<snapshot>.bbs` basic block list to
+/// collect basic block coverage from
+const BASIC_BLOCKS: bool = false;
+
 pub fn fuzz() {
     if core!().id != 0 { cpu::halt(); }
 
     static SESSION:
-        LockCell<Option<Arc<FuzzSession>>, LockInterrupts> =
+        LockCell<Option<(String, Arc<FuzzSession>)>, LockInterrupts> =
         LockCell::new(None);
 
-    // Create the master sessionshot, and fork from it for all cores
-    let session = {
-        let mut session = SESSION.lock();
-        if session.is_none() {
-            *session = Some(
-                Arc::new(FuzzSession::from_falkdump(
-                        "192.168.101.1:1911", "out.falkdump", |_worker| {
-                    //_worker.set_reg(crate::vtx::Register::Rsp, 0x13371337);
-                    _worker.set_reg(crate::vtx::Register::Cr3, 0x3713371337);
-                })
-                //.timeout(100_000)
-                .inject(inject))
-            );
-        }
-        session.as_ref().unwrap().clone()
-    };
-
-    let mut worker = FuzzSession::worker(session);
-    
-    // Set that this is a Windows guest
-    worker.enlighten(Some(Box::new(crate::fuzz_session::windows::Enlightenment::default())));
+    // Snapshot to fuzz, this may be changed by the server
+    let mut snapshot = String::from("out.falkdump");
 
     loop {
-        let _vmexit = worker.fuzz_case();
-        /*print!("{:#x?}\n", _vmexit);
-        crate::time::sleep(1_000_000);*/
+        // Get the config of the kernel structures of a Linux guest
+        let linux = match GUEST_OS {
+            GuestOs::Windows => None,
+            GuestOs::Linux => {
+                let config = linux::Config::from_server(
+                    "192.168.101.1:1911", &snapshot).unwrap_or_else(|| {
+                        panic!("Missing or invalid config {}.linux",
+                               snapshot)
+                    });
+                Some(Arc::new(config))
+            }
+        };
+
+        // Enlighten the master, such that the basic block coverage
+        // breakpoints can be resolved in its modules
+        let init_master = |master: &mut Worker| {
+            master.enlighten(Some(enlightenment(linux.clone())));
+            if BASIC_BLOCKS {
+                master.arm_coverage("192.168.101.1:1911",
+                                    &format!("{}.bbs", snapshot));
+            }
+        };
+
+        // Create the master sessionshot, and fork from it for all cores
+        let session = {
+            let mut session = SESSION.lock();
+            if session.as_ref().map(|x| &x.0) != Some(&snapshot) {
+                // Full Windows kernel dumps are loaded as minidumps, and
+                // stock QEMU dumps as ELF cores
+                let new_session = if snapshot.ends_with(".dmp") {
+                    FuzzSession::from_minidump(
+                        "192.168.101.1:1911", &snapshot, init_master)
+                } else if snapshot.ends_with(".elf") {
+                    FuzzSession::from_qemu_elf(
+                        "192.168.101.1:1911", &snapshot, init_master)
+                } else {
+                    FuzzSession::from_falkdump(
+                        "192.168.101.1:1911", &snapshot, init_master)
+                };
+
+                match new_session {
+                    Ok(new_session) => {
+                        *session = Some((snapshot.clone(),
+                            Arc::new(new_session
+                                //.timeout(100_000)
+                                .inject(inject))));
+                    }
+                    Err(err) => {
+                        // Keep fuzzing the old snapshot if we cannot switch
+                        let old = session.as_ref().unwrap_or_else(|| {
+                            panic!("Failed to load snapshot {}: {:?}",
+                                   snapshot, err)
+                        });
+                        print!("Failed to load snapshot {}: {:?}, \
+                                keeping {}\n", snapshot, err, old.0);
+                        snapshot = old.0.clone();
+                    }
+                }
+            }
+            session.as_ref().unwrap().1.clone()
+        };
+
+        let mut worker = FuzzSession::worker(session.clone());
+        worker.enlighten(Some(enlightenment(linux)));
+
+        loop {
+            let _vmexit = worker.fuzz_case();
+            /*print!("{:#x?}\n", _vmexit);
+            crate::time::sleep(1_000_000);*/
+
+            // Switch snapshots if the server requested it
+            if let Some(requested) = worker.requested_snapshot() {
+                snapshot = requested;
+                break;
+            }
+        }
+    }
+}
+
+/// Get the enlightenment for a guest, a Linux guest if it has a `config`
+/// and a Windows guest otherwise, see `GUEST_OS`
+fn enlightenment(config: Option<Arc<linux::Config>>)
+        -> Box<dyn Enlightenment> {
+    if let Some(config) = config {
+        Box::new(linux::Enlightenment::new(config))
+    } else {
+        Box::new(windows::Enlightenment::default())
     }
 }
 
 fn inject(worker: &mut Worker) {
-    let mut input = worker.fuzz_input.borrow_mut();
-    input.clear();
-    input.push(worker.rng.rand() as u8);
-    input.push(worker.rng.rand() as u8);
+    worker.mutate();
 }
 
@@ -18,9 +18,11 @@ pub fn tsc_mhz() -> u64 {
     RDTSC_MHZ.load(Ordering::SeqCst)
 }
 
-/// Returns the TSC value upon a future time in microseconds
+/// Returns the TSC value upon a future time in microseconds. Times too far in
+/// the future to represent saturate to `u64::MAX`.
 pub fn future(microseconds: u64) -> u64 {
-	cpu::rdtsc() + (microseconds * RDTSC_MHZ.load(Ordering::SeqCst))
+    cpu::rdtsc().saturating_add(
+        microseconds.saturating_mul(RDTSC_MHZ.load(Ordering::SeqCst)))
 }
 
 /// Returns system uptime in seconds as a float
@@ -898,6 +898,7 @@ pub enum VmExit {
     },
     Exception(Exception),
     ExternalInterrupt,
+    MonitorTrap,
     PreemptionTimer,
     Rdtsc { inst_len: u64 },
     Timeout,
@@ -960,6 +961,13 @@ pub struct Vm {
 
     /// Current setting for the pin-based controls
     pinbased_controls: u64,
+
+    /// Single step the guest, causing a `MonitorTrap` VM exit after every
+    /// instruction
+    pub monitor_trap: bool,
+
+    /// Current setting for the primary processor-based controls
+    procbased_controls: u64,
 }
 
 impl Vm {
@@ -1070,6 +1078,8 @@ impl Vm {
             launched:   false,
             preemption_timer: None,
             pinbased_controls: 0,
+            monitor_trap: false,
+            procbased_controls: 0,
         }
     }
     
@@ -1276,6 +1286,7 @@ impl Vm {
                 self.pinbased_controls = pinbased_minimum | pin_on;
                 vmwrite(Vmcs::ProcBasedControls,
                              procbased_minimum | proc_on);
+                self.procbased_controls = procbased_minimum | proc_on;
                 vmwrite(Vmcs::ProcBasedControls2,
                              proc2based_minimum | proc2_on);
                 vmwrite(Vmcs::ExitControls, 
@@ -1371,6 +1382,20 @@ impl Vm {
                     vmwrite(Vmcs::PinBasedControls, self.pinbased_controls);
                 }
             }
+
+            let monitor_trap = (self.procbased_controls & (1 << 27)) != 0;
+            if self.monitor_trap != monitor_trap {
+                if self.monitor_trap {
+                    // Enable the monitor trap flag
+                    assert!((cpu::rdmsr(IA32_VMX_PROCBASED_CTLS) >> 32) &
+                            (1 << 27) != 0, "Monitor trap flag not supported");
+                    self.procbased_controls |= 1 << 27;
+                } else {
+                    // Disable the monitor trap flag
+                    self.procbased_controls &= !(1 << 27);
+                }
+                vmwrite(Vmcs::ProcBasedControls, self.procbased_controls);
+            }
             
             // Flush any registers which may have changed during execution
             let dirtied = self.guest_regs.dirtied;
@@ -1644,6 +1669,7 @@ impl Vm {
                     }
                 }
             }
+            37 => VmExit::MonitorTrap,
             52 => VmExit::PreemptionTimer,
             62 => VmExit::PmlFull,
             x @ _ => unimplemented!("Unhandled VM exit code {} @ {:#x}\n",
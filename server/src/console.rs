@@ -0,0 +1,80 @@
+//! Operator console on stdin, used to send commands to sessions
+
+use std::io::{self, BufRead};
+use std::sync::Arc;
+use std::borrow::Cow;
+
+use falktp::ServerMessage;
+use crate::Context;
+
+/// Usage of the console
+const USAGE: &str = "\
+Commands, where `target` is a session ID in hex or `all`:
+    pause    <target>
+    resume   <target>
+    reboot   <target>
+    timeout  <target> <microseconds|none>
+    snapshot <target> <filename>";
+
+/// Parse a command and its arguments into the message to send to a session
+fn parse<'a>(cmd: &str, args: &[&str]) -> Option<ServerMessage<'a>> {
+    Some(match (cmd, args) {
+        ("pause",  [])         => ServerMessage::Pause,
+        ("resume", [])         => ServerMessage::Resume,
+        ("reboot", [])         => ServerMessage::SoftReboot,
+        ("timeout", ["none"])  => ServerMessage::SetTimeout(None),
+        ("timeout", [timeout]) => {
+            ServerMessage::SetTimeout(Some(timeout.parse().ok()?))
+        }
+        ("snapshot", [filename]) => {
+            ServerMessage::SwitchSnapshot(Cow::Owned(filename.to_string()))
+        }
+        _ => return None,
+    })
+}
+
+/// Handle a line from the console
+fn command(context: &Context, line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    if args.len() == 0 { return; }
+
+    // Make sure the command is valid before queueing it to any session
+    if args.len() < 2 || parse(args[0], &args[2..]).is_none() {
+        print!("{}\n", USAGE);
+        return;
+    }
+
+    // Get the session to send to, `None` for all sessions
+    let target = if args[1] == "all" {
+        None
+    } else if let Ok(id) = u64::from_str_radix(args[1], 16) {
+        Some(id)
+    } else {
+        print!("Invalid session ID {:?}\n", args[1]);
+        return;
+    };
+
+    // Queue the command, it is sent on the next sync of each session
+    let mut queued = 0;
+    let sessions = context.sessions.read().unwrap();
+    for (id, session) in sessions.iter() {
+        if target.map(|x| x == *id).unwrap_or(true) {
+            session.write().unwrap().commands
+                .push(parse(args[0], &args[2..]).unwrap());
+            queued += 1;
+        }
+    }
+
+    print!("Queued {} for {} session(s)\n", args[0], queued);
+}
+
+/// Handle commands from stdin, forever
+pub fn console(context: Arc<Context>) {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        match line {
+            Ok(line) => command(&context, &line),
+            Err(_)   => break,
+        }
+    }
+}
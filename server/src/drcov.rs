@@ -0,0 +1,134 @@
+//! Export of coverage in the drcov format, as consumed by Lighthouse and
+//! other coverage explorers for IDA, Ghidra, and Binary Ninja
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet};
+
+use falktp::CoverageRecord;
+use crate::Context;
+
+/// Directory drcov files are exported to
+const DRCOV_DIR: &str = "drcov";
+
+/// Time to wait between periodic exports
+const EXPORT_DELAY: Duration = Duration::from_secs(60);
+
+/// Generate a drcov file from `coverage`
+///
+/// `modules` maps module names to their base address and size. Modules which
+/// have not been reported by a worker get a base of zero and a size covering
+/// all of their coverage. Coverage records without a module, or with an
+/// offset which does not fit in the 32-bit drcov offset, cannot be expressed
+/// in drcov and are skipped. As we only know the address of each coverage
+/// record and not the size of the block, every entry has a size of one byte.
+pub fn generate<'a, I>(coverage: I, modules: &BTreeMap<String, (u64, u64)>)
+        -> Vec<u8>
+        where I: Iterator<Item = &'a CoverageRecord<'a>> {
+    // Gather the module-relative offsets for each module
+    let mut blocks: BTreeMap<&str, BTreeSet<u32>> = BTreeMap::new();
+    for record in coverage {
+        let module = match &record.module {
+            Some(module) => module,
+            None         => continue,
+        };
+
+        if record.offset <= u32::MAX as u64 {
+            blocks.entry(module.as_str()).or_default()
+                .insert(record.offset as u32);
+        }
+    }
+
+    // Create the module table
+    let mut ret = format!("DRCOV VERSION: 2\n\
+                           DRCOV FLAVOR: drcov\n\
+                           Module Table: version 2, count {}\n\
+                           Columns: id, base, end, entry, checksum, \
+                           timestamp, path\n", blocks.len());
+    for (id, (&module, offsets)) in blocks.iter().enumerate() {
+        let (base, size) = modules.get(module).copied().unwrap_or_else(|| {
+            (0, *offsets.iter().next_back().unwrap() as u64 + 1)
+        });
+
+        ret += &format!("{:3}, {:#018x}, {:#018x}, {:#018x}, {:#010x}, \
+                         {:#010x}, {}\n",
+                        id, base, base + size, 0, 0, 0, module);
+    }
+
+    // Create the basic block table
+    let total: usize = blocks.values().map(|x| x.len()).sum();
+    ret += &format!("BB Table: {} bbs\n", total);
+
+    let mut ret = ret.into_bytes();
+    for (id, offsets) in blocks.values().enumerate() {
+        for &offset in offsets {
+            // struct { u32 start; u16 size; u16 mod_id; }
+            ret.extend_from_slice(&offset.to_le_bytes());
+            ret.extend_from_slice(&1u16.to_le_bytes());
+            ret.extend_from_slice(&(id as u16).to_le_bytes());
+        }
+    }
+
+    ret
+}
+
+/// Generate a drcov file of the coverage of the target `name`, if the target
+/// exists
+pub fn target(context: &Context, name: &str) -> Option<Vec<u8>> {
+    let target   = context.targets.read().unwrap().get(name)?.clone();
+    let modules  = target.modules.read().unwrap();
+    let coverage = target.coverage.read().unwrap();
+    Some(generate(coverage.iter(), &modules))
+}
+
+/// Generate a drcov file of the coverage of session `id`, if the session
+/// exists
+pub fn session(context: &Context, id: u64) -> Option<Vec<u8>> {
+    let session = context.sessions.read().unwrap().get(&id)?.clone();
+    let session = session.read().unwrap();
+    let modules = session.target.modules.read().unwrap();
+    Some(generate(session.coverage.iter(), &modules))
+}
+
+/// Export drcov files for the coverage of every target and every session into
+/// the `drcov` directory
+pub fn export(context: &Context) -> io::Result<()> {
+    std::fs::create_dir_all(DRCOV_DIR)?;
+
+    // Export each target's coverage
+    let names: Vec<String> =
+        context.targets.read().unwrap().keys().cloned().collect();
+    for name in names {
+        if let Some(drcov) = target(context, &name) {
+            std::fs::write(Path::new(DRCOV_DIR)
+                           .join(format!("target_{}.drcov", name)),
+                           drcov)?;
+        }
+    }
+
+    // Export each session's coverage
+    let ids: Vec<u64> =
+        context.sessions.read().unwrap().keys().copied().collect();
+    for id in ids {
+        if let Some(drcov) = session(context, id) {
+            std::fs::write(Path::new(DRCOV_DIR)
+                           .join(format!("session_{:016x}.drcov", id)),
+                           drcov)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Periodically export drcov files, forever
+pub fn periodic_export(context: Arc<Context>) {
+    loop {
+        std::thread::sleep(EXPORT_DELAY);
+
+        if let Err(err) = export(&context) {
+            print!("Failed to export drcov: {}\n", err);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Time-series history of campaign statistics, kept in memory as a rolling
+//! window and logged to the `stats` directory as CSV
+
+use std::io::{self, Write};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{Context, Session};
+
+/// Directory the history is logged to
+pub const STATS_DIR: &str = "stats";
+
+/// Time between samples
+const SAMPLE_DELAY: Duration = Duration::from_secs(10);
+
+/// Time span of the rolling history kept in memory, one day
+const HISTORY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of samples to keep in memory for each history
+const MAX_SAMPLES: usize =
+    (HISTORY_WINDOW.as_secs() / SAMPLE_DELAY.as_secs()) as usize;
+
+/// Get the current time as seconds since the unix epoch
+pub fn unix_time() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs_f64()).unwrap_or(0.)
+}
+
+/// Open a CSV file in the stats directory for appending, writing `header` if
+/// the file is new
+pub fn open_csv(name: &str, header: &str) -> io::Result<File> {
+    std::fs::create_dir_all(STATS_DIR)?;
+
+    let mut file = OpenOptions::new()
+        .create(true).append(true).open(Path::new(STATS_DIR).join(name))?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(header.as_bytes())?;
+    }
+
+    Ok(file)
+}
+
+/// A sample of the statistics of a session, or of the whole campaign
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Sample {
+    /// Time of the sample, in seconds since the unix epoch
+    pub time: f64,
+
+    /// Number of workers
+    pub workers: u64,
+
+    /// Number of fuzz cases performed
+    pub fuzz_cases: u64,
+
+    /// Number of coverage records
+    pub coverage: u64,
+
+    /// Number of inputs
+    pub inputs: u64,
+
+    /// Number of crash buckets
+    pub crashes: u64,
+
+    /// Fraction of cycles spent inside the VM
+    pub vm_ratio: f64,
+
+    /// Fraction of cycles spent resetting the VM
+    pub reset_ratio: f64,
+
+    /// Number of physical memory bytes in use
+    pub phys_used: u64,
+
+    /// Total number of physical memory bytes
+    pub phys_total: u64,
+}
+
+impl Sample {
+    /// Header of the CSV files samples are logged to
+    const CSV_HEADER: &'static str =
+        "time,workers,fuzz_cases,coverage,inputs,crashes,vm_ratio,\
+         reset_ratio,phys_used,phys_total\n";
+
+    /// Create a sample from a session
+    fn from_session(session: &Session, time: f64) -> Self {
+        let mut sample = Sample {
+            time:       time,
+            workers:    session.workers.len() as u64,
+            fuzz_cases: session.fuzz_cases,
+            coverage:   session.coverage.len() as u64,
+            inputs:     session.inputs.len() as u64,
+            crashes:    session.crashes,
+            phys_used:  session.phys_total.saturating_sub(session.phys_free),
+            phys_total: session.phys_total,
+            ..Default::default()
+        };
+
+        if session.total_cycles > 0 {
+            sample.vm_ratio =
+                session.vm_cycles as f64 / session.total_cycles as f64;
+            sample.reset_ratio =
+                session.reset_cycles as f64 / session.total_cycles as f64;
+        }
+
+        sample
+    }
+
+    /// Format the sample as a CSV line
+    fn csv(&self) -> String {
+        format!("{:.3},{},{},{},{},{},{:.6},{:.6},{},{}\n",
+                self.time, self.workers, self.fuzz_cases, self.coverage,
+                self.inputs, self.crashes, self.vm_ratio, self.reset_ratio,
+                self.phys_used, self.phys_total)
+    }
+
+    /// Format the sample as a JSON object
+    pub fn json(&self) -> String {
+        format!("{{\"time\":{:.3},\"workers\":{},\"fuzz_cases\":{},\
+                 \"coverage\":{},\"inputs\":{},\"crashes\":{},\
+                 \"vm_ratio\":{:.6},\"reset_ratio\":{:.6},\
+                 \"phys_used\":{},\"phys_total\":{}}}",
+                self.time, self.workers, self.fuzz_cases, self.coverage,
+                self.inputs, self.crashes, self.vm_ratio, self.reset_ratio,
+                self.phys_used, self.phys_total)
+    }
+}
+
+/// Rolling history of samples for the campaign and each session
+#[derive(Default)]
+pub struct History {
+    /// Samples of the whole campaign
+    pub global: VecDeque<Sample>,
+
+    /// Samples of each session, by session ID. Sessions which have been idle
+    /// for longer than `HISTORY_WINDOW` are no longer sampled, and are
+    /// dropped once their last sample is out of the window.
+    pub sessions: BTreeMap<u64, VecDeque<Sample>>,
+}
+
+/// Push a sample to a rolling history, dropping the oldest sample if the
+/// history is full
+fn push(history: &mut VecDeque<Sample>, sample: Sample) {
+    if history.len() >= MAX_SAMPLES {
+        history.pop_front();
+    }
+    history.push_back(sample);
+}
+
+/// Take a sample of every session and the whole campaign, and log them
+fn sample(context: &Context) -> io::Result<()> {
+    let time = unix_time();
+
+    // Sample all sessions, noting which of them have been idle for longer
+    // than the rolling window
+    let sessions: Vec<(u64, Sample, bool)> = {
+        let sessions = context.sessions.read().unwrap();
+        sessions.iter().map(|(&id, session)| {
+            let session = session.read().unwrap();
+            (id, Sample::from_session(&session, time),
+             session.last_packet.elapsed() > HISTORY_WINDOW)
+        }).collect()
+    };
+
+    // Sum up the sessions into the global sample, coverage, inputs, and
+    // crashes come from the target databases as sessions overlap
+    let mut global = Sample {
+        time: time,
+        ..Default::default()
+    };
+    for target in context.targets.read().unwrap().values() {
+        global.coverage += target.coverage.read().unwrap().len() as u64;
+        global.inputs   += target.inputs.read().unwrap().len() as u64;
+        global.crashes  += target.crashes.read().unwrap().len() as u64;
+    }
+    for (_, sample, _) in sessions.iter() {
+        global.workers     += sample.workers;
+        global.fuzz_cases  += sample.fuzz_cases;
+        global.phys_used   += sample.phys_used;
+        global.phys_total  += sample.phys_total;
+        global.vm_ratio    += sample.vm_ratio;
+        global.reset_ratio += sample.reset_ratio;
+    }
+
+    // Cycle ratios are averaged over the sessions
+    if sessions.len() > 0 {
+        global.vm_ratio    /= sessions.len() as f64;
+        global.reset_ratio /= sessions.len() as f64;
+    }
+
+    // Update the in-memory history
+    {
+        let mut history = context.history.write().unwrap();
+        push(&mut history.global, global);
+        for &(id, sample, _) in sessions.iter().filter(|x| !x.2) {
+            push(history.sessions.entry(id).or_default(), sample);
+        }
+
+        // Drop sessions which are gone or idle once their last sample is
+        // out of the window, like the samples of the campaign
+        let window = HISTORY_WINDOW.as_secs_f64();
+        history.sessions.retain(|_, samples| {
+            samples.back().map(|x| time - x.time <= window).unwrap_or(false)
+        });
+    }
+
+    // Log the samples to disk
+    open_csv("global.csv", Sample::CSV_HEADER)?
+        .write_all(global.csv().as_bytes())?;
+    for (id, sample, _) in sessions.iter().filter(|x| !x.2) {
+        open_csv(&format!("session_{:016x}.csv", id), Sample::CSV_HEADER)?
+            .write_all(sample.csv().as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Sample the statistics on an interval, forever
+pub fn periodic_sample(context: Arc<Context>) {
+    loop {
+        std::thread::sleep(SAMPLE_DELAY);
+
+        if let Err(err) = sample(&context) {
+            print!("Failed to log stats history: {}\n", err);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! A minimal HTTP server exposing campaign statistics as JSON and in the
+//! Prometheus text exposition format
+
+use std::io::{self, Read, Write};
+use std::fmt::Write as FmtWrite;
+use std::sync::Arc;
+use std::time::{Instant, Duration};
+use std::net::{TcpStream, TcpListener};
+
+use crate::{Context, drcov, minimize, symbols};
+use crate::history::Sample;
+
+/// Maximum size of an HTTP request header we are willing to buffer
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// Escape a string for use in a JSON string or a Prometheus label value
+fn escape(val: &str) -> String {
+    let mut ret = String::with_capacity(val.len());
+    for chr in val.chars() {
+        match chr {
+            '"'  => ret += "\\\"",
+            '\\' => ret += "\\\\",
+            '\n' => ret += "\\n",
+            chr if (chr as u32) < 0x20 => {
+                write!(ret, "\\u{:04x}", chr as u32).unwrap();
+            }
+            chr => ret.push(chr),
+        }
+    }
+    ret
+}
+
+/// Generate the `/sessions` response, a JSON array of all sessions
+fn sessions(context: &Context) -> String {
+    let mut ret = String::from("[");
+
+    let sessions = context.sessions.read().unwrap();
+    for (ii, session) in sessions.values().enumerate() {
+        let session = session.read().unwrap();
+
+        if ii != 0 { ret += ","; }
+        write!(ret, "{{\"id\":\"{:016x}\",\"target\":\"{}\",\
+                     \"workers\":{},\"idle\":{},\
+                     \"uptime\":{},\"fuzz_cases\":{},\"total_cycles\":{},\
+                     \"vm_cycles\":{},\"reset_cycles\":{},\"vm_exits\":{},\
+                     \"allocs\":{},\"frees\":{},\"phys_free\":{},\
+                     \"phys_total\":{},\"coverage\":{},\
+                     \"unique_coverage\":{},\"inputs\":{},\
+                     \"unique_inputs\":{},\"crashes\":{},\
+                     \"unique_crashes\":{}}}",
+               session.id,
+               escape(&session.target.name),
+               session.workers.len(),
+               session.idle,
+               (Instant::now() - session.first_packet).as_secs_f64(),
+               session.fuzz_cases,
+               session.total_cycles,
+               session.vm_cycles,
+               session.reset_cycles,
+               session.vm_exits,
+               session.allocs,
+               session.frees,
+               session.phys_free,
+               session.phys_total,
+               session.coverage.len(),
+               session.unique_coverage,
+               session.inputs.len(),
+               session.unique_inputs,
+               session.crashes,
+               session.unique_crashes).unwrap();
+    }
+
+    ret += "]";
+    ret
+}
+
+/// Generate the `/coverage` response, a JSON object containing an array of
+/// all coverage records of each target, symbolized if we have symbols for
+/// the module
+fn coverage(context: &Context) -> String {
+    let mut ret = String::from("{");
+
+    let targets = context.targets.read().unwrap();
+    for (ii, (name, target)) in targets.iter().enumerate() {
+        if ii != 0 { ret += ","; }
+        write!(ret, "\"{}\":[", escape(name)).unwrap();
+
+        let coverage = target.coverage.read().unwrap();
+        let symbols  = target.symbols.read().unwrap();
+        for (ii, record) in coverage.iter().enumerate() {
+            if ii != 0 { ret += ","; }
+
+            if let Some(module) = &record.module {
+                let symbol =
+                    symbols::symbolize(&symbols, module, record.offset)
+                    .map(|x| format!("\"{}\"", escape(&x)))
+                    .unwrap_or_else(|| String::from("null"));
+                write!(ret, "{{\"module\":\"{}\",\"offset\":{},\
+                             \"symbol\":{}}}",
+                       escape(module), record.offset, symbol).unwrap();
+            } else {
+                write!(ret, "{{\"module\":null,\"offset\":{},\
+                             \"symbol\":null}}",
+                       record.offset).unwrap();
+            }
+        }
+
+        ret += "]";
+    }
+
+    ret += "}";
+    ret
+}
+
+/// Generate the `/inputs` response, a JSON object containing an array of the
+/// hash and size of all inputs of each target
+fn inputs(context: &Context) -> String {
+    let mut ret = String::from("{");
+
+    let targets = context.targets.read().unwrap();
+    for (ii, (name, target)) in targets.iter().enumerate() {
+        if ii != 0 { ret += ","; }
+        write!(ret, "\"{}\":[", escape(name)).unwrap();
+
+        let inputs = target.inputs.read().unwrap();
+        for (ii, input) in inputs.iter().enumerate() {
+            if ii != 0 { ret += ","; }
+            write!(ret, "{{\"hash\":\"{:032x}\",\"size\":{}}}",
+                   input.hash, input.input.len()).unwrap();
+        }
+
+        ret += "]";
+    }
+
+    ret += "}";
+    ret
+}
+
+/// Generate the `/history` response, a JSON object containing the rolling
+/// history of the campaign and of each session
+fn history(context: &Context) -> String {
+    /// Format a list of samples as a JSON array
+    fn samples<'a>(samples: impl Iterator<Item = &'a Sample>) -> String {
+        let samples: Vec<String> = samples.map(|x| x.json()).collect();
+        format!("[{}]", samples.join(","))
+    }
+
+    let history = context.history.read().unwrap();
+
+    let mut ret = format!("{{\"global\":{},\"sessions\":{{",
+                          samples(history.global.iter()));
+    for (ii, (id, session)) in history.sessions.iter().enumerate() {
+        if ii != 0 { ret += ","; }
+        write!(ret, "\"{:016x}\":{}", id, samples(session.iter())).unwrap();
+    }
+
+    ret += "}}";
+    ret
+}
+
+/// Generate the `/metrics` response in the Prometheus text format
+fn metrics(context: &Context) -> String {
+    let mut ret = String::new();
+
+    // Per-target metrics
+    let targets = context.targets.read().unwrap();
+    for &(name, help) in &[
+        ("chocolate_milk_coverage", "Unique coverage records"),
+        ("chocolate_milk_inputs",   "Unique inputs"),
+        ("chocolate_milk_crashes",  "Unique crash buckets"),
+    ] {
+        write!(ret, "# HELP {} {}\n# TYPE {} gauge\n",
+               name, help, name).unwrap();
+
+        for (target_name, target) in targets.iter() {
+            let val = match name {
+                "chocolate_milk_coverage" =>
+                    target.coverage.read().unwrap().len(),
+                "chocolate_milk_inputs" =>
+                    target.inputs.read().unwrap().len(),
+                _ => target.crashes.read().unwrap().len(),
+            };
+
+            write!(ret, "{}{{target=\"{}\"}} {}\n",
+                   name, escape(target_name), val).unwrap();
+        }
+    }
+
+    // Snapshot the per-session metrics such that each metric family can be
+    // emitted as one group, as the format requires
+    let sessions = context.sessions.read().unwrap();
+    let sessions: Vec<_> = sessions.values().map(|session| {
+        let session = session.read().unwrap();
+        (session.id, session.target.name.clone(), [
+            session.workers.len() as u64,
+            if session.idle { 1 } else { 0 },
+            session.fuzz_cases,
+            session.total_cycles,
+            session.vm_cycles,
+            session.reset_cycles,
+            session.vm_exits,
+            session.allocs,
+            session.frees,
+            session.phys_free,
+            session.phys_total,
+            session.coverage.len() as u64,
+            session.unique_coverage,
+            session.inputs.len() as u64,
+            session.unique_inputs,
+            session.crashes,
+            session.unique_crashes,
+        ])
+    }).collect();
+
+    // Per-session metrics, in the same order as the snapshot above
+    const SESSION_METRICS: [(&str, &str, &str); 17] = [
+        ("workers",         "gauge",   "Number of workers"),
+        ("idle",            "gauge",   "Set if all workers disconnected"),
+        ("fuzz_cases",      "counter", "Number of fuzz cases"),
+        ("total_cycles",    "counter", "Total cycles spent fuzzing"),
+        ("vm_cycles",       "counter", "Cycles spent inside the VM"),
+        ("reset_cycles",    "counter", "Cycles spent resetting the VM"),
+        ("vm_exits",        "counter", "Number of VM exits"),
+        ("allocs",          "counter", "Number of allocations"),
+        ("frees",           "counter", "Number of frees"),
+        ("phys_free",       "gauge",   "Free physical memory in bytes"),
+        ("phys_total",      "gauge",   "Total physical memory in bytes"),
+        ("coverage",        "gauge",   "Coverage records of the session"),
+        ("unique_coverage", "counter", "Coverage first found by the session"),
+        ("inputs",          "gauge",   "Inputs of the session"),
+        ("unique_inputs",   "counter", "Inputs first found by the session"),
+        ("crashes",         "counter", "Crash buckets hit by the session"),
+        ("unique_crashes",  "counter", "Crash buckets first found by the \
+                                        session"),
+    ];
+
+    for (ii, &(name, kind, help)) in SESSION_METRICS.iter().enumerate() {
+        write!(ret, "# HELP chocolate_milk_session_{} {}\n\
+                     # TYPE chocolate_milk_session_{} {}\n",
+               name, help, name, kind).unwrap();
+
+        for (id, target, vals) in sessions.iter() {
+            write!(ret,
+                   "chocolate_milk_session_{}{{session=\"{:016x}\",\
+                    target=\"{}\"}} {}\n",
+                   name, id, escape(target), vals[ii]).unwrap();
+        }
+    }
+
+    ret
+}
+
+/// Handle a single HTTP request and close the connection
+fn handle_request(mut stream: TcpStream, context: Arc<Context>)
+        -> io::Result<()> {
+    // Don't let a stalled client hold on to the thread forever
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // Read until the end of the request header
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+        let bread = stream.read(&mut buf)?;
+        if bread == 0 || request.len() + bread > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..bread]);
+    }
+
+    // Parse the request line, we only care about the method and path
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path   = parts.next().unwrap_or("");
+
+    // Strip the query string
+    let path = path.split('?').next().unwrap();
+
+    // Get the coverage for `/drcov/target/<target name>` and
+    // `/drcov/<session id>` requests
+    let drcov = if path.starts_with("/drcov/target/") {
+        drcov::target(&context, &path[14..])
+    } else if path.starts_with("/drcov/") {
+        u64::from_str_radix(&path[7..], 16).ok()
+            .and_then(|x| drcov::session(&context, x))
+    } else {
+        None
+    };
+
+    // Minimizing rewrites the corpus, so it must not be triggered by a plain
+    // `GET` from a crawler, a prefetcher, or a refreshing dashboard
+    let allowed = if path == "/minimize" { "POST" } else { "GET" };
+
+    // Generate the response
+    let (status, content_type, body): (_, _, Vec<u8>) = if method != allowed {
+        ("405 Method Not Allowed", "text/plain",
+         b"Method not allowed\n".to_vec())
+    } else {
+        match path {
+            "/sessions" => ("200 OK", "application/json",
+                            sessions(&context).into_bytes()),
+            "/coverage" => ("200 OK", "application/json",
+                            coverage(&context).into_bytes()),
+            "/inputs"   => ("200 OK", "application/json",
+                            inputs(&context).into_bytes()),
+            "/minimize" => match minimize::run(&context) {
+                Ok(()) => ("200 OK", "text/plain", b"Minimized\n".to_vec()),
+                Err(err) => ("500 Internal Server Error", "text/plain",
+                             format!("{}\n", err).into_bytes()),
+            },
+            "/history"  => ("200 OK", "application/json",
+                            history(&context).into_bytes()),
+            "/metrics"  => ("200 OK", "text/plain; version=0.0.4",
+                            metrics(&context).into_bytes()),
+            _ if drcov.is_some() =>
+                ("200 OK", "application/octet-stream", drcov.unwrap()),
+            _ => ("404 Not Found", "text/plain", b"Not found\n".to_vec()),
+        }
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\n\
+                    Content-Length: {}\r\nAllow: {}\r\n\
+                    Connection: close\r\n\r\n",
+           status, content_type, body.len(), allowed)?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+/// Serve HTTP requests on `addr` forever
+pub fn serve(addr: &str, context: Arc<Context<'static>>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+
+    for stream in listener.incoming() {
+        // Accept errors are transient (out of file descriptors, aborted
+        // connections), keep serving
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                print!("HTTP accept error: {}\n", err);
+                continue;
+            }
+        };
+        let context = context.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = handle_request(stream, context) {
+                print!("HTTP error: {}\n", err);
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -3,29 +3,182 @@
 #[allow(unused)]
 #[macro_use] extern crate noodle;
 
-use std::io::{self, Write};
-use std::fs::File;
-use std::path::Path;
+mod http;
+mod drcov;
+mod history;
+mod minimize;
+mod console;
+mod symbols;
+
+use std::io::{self, Write, Seek, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Instant, SystemTime, Duration};
 use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, TcpStream, TcpListener};
 use std::borrow::Cow;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::collections::hash_map::DefaultHasher;
 
 use noodle::*;
-use falktp::{CoverageRecord, InputRecord, ServerMessage};
+use falkhash::FalkHasher;
+use falktp::{CoverageRecord, InputRecord, CrashRecord, ServerMessage};
+use falktp::{AttributionRecord, TraceRecord};
+use falktp::{PROTOCOL_VERSION, MAX_READ_PAGES};
 
 /// If `true` prints some extra spew
 const VERBOSE: bool = false;
 
+/// If `true` newly logged in sessions are only sent the minimized corpus
+/// rather than every input, once the corpus has been minimized
+const PUSH_MINIMIZED: bool = true;
+
+/// Address to serve the HTTP stats endpoints on
+const HTTP_ADDR: &str = "0.0.0.0:1912";
+
+/// Directory containing the databases of each target, in a directory named
+/// after the target
+const TARGETS_DIR: &str = "targets";
+
+/// Databases which older servers kept in the working directory, before each
+/// target had its own databases in `TARGETS_DIR`
+const LEGACY_DATABASES: &[&str] = &[
+    "inputs", "seeds", "coverage.txt", "attribution.txt", "crashes",
+];
+
+/// Target the legacy databases are migrated to. Older servers only had one
+/// campaign, which fuzzed the default snapshot, and workers report the name
+/// of their snapshot as their target.
+const LEGACY_TARGET: &str = "out.falkdump";
+
+/// Move the databases of an older server from the working directory into
+/// the directory of `LEGACY_TARGET`, such that the campaign keeps its corpus
+/// across the upgrade. Databases which the target already has are never
+/// overwritten.
+fn migrate_legacy_databases() -> io::Result<()> {
+    let dir = Path::new(TARGETS_DIR).join(LEGACY_TARGET);
+
+    for &database in LEGACY_DATABASES {
+        let legacy = Path::new(database);
+        if !legacy.exists() { continue; }
+
+        let migrated = dir.join(database);
+        if migrated.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("Cannot migrate {} to {:?}, which already exists. \
+                    Merge them by hand and restart the server.",
+                    database, migrated)));
+        }
+
+        print!("Migrating {} to {:?}\n", database, migrated);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::rename(legacy, &migrated)?;
+    }
+
+    Ok(())
+}
+
+/// A fuzz target. Each target has its own corpus, coverage, and crash
+/// databases, such that sessions fuzzing different snapshots at the same time
+/// do not mix their corpora
+struct Target<'a> {
+    /// Name of the target, as reported by workers at login
+    name: String,
+
+    /// Directory the databases of this target are stored in
+    dir: PathBuf,
+
+    coverage:      RwLock<BTreeSet<CoverageRecord<'a>>>,
+    inputs:        RwLock<BTreeSet<InputRecord<'a>>>,
+
+    /// Unique crash buckets as (kind, module, offset)
+    crashes:       RwLock<BTreeSet<(String, Option<String>, u64)>>,
+
+    /// Base address and size of modules, by module name, as first reported
+    /// by a worker
+    modules:       RwLock<BTreeMap<String, (u64, u64)>>,
+
+    /// Symbols of modules, by module name, loaded when the module is first
+    /// reported by a worker
+    symbols:       RwLock<BTreeMap<String, symbols::Symbols>>,
+
+    coverage_file: Mutex<File>,
+
+    /// Coverage attributed to the input which first hit it, by input hash
+    attribution:      RwLock<BTreeMap<u128, BTreeSet<CoverageRecord<'a>>>>,
+
+    /// Log of the attribution of coverage to inputs
+    attribution_file: Mutex<File>,
+
+    /// Hashes of the inputs in the minimized corpus, if the corpus has been
+    /// minimized
+    minimized:        RwLock<Option<BTreeSet<u128>>>,
+}
+
+impl<'a> Target<'a> {
+    /// Check if `name` is a valid target name. Target names are used as
+    /// directory names, thus they are restricted to characters which cannot
+    /// escape `TARGETS_DIR`
+    fn valid_name(name: &str) -> bool {
+        name.len() > 0 && name.len() <= 128 && !name.starts_with('.') &&
+            name.chars().all(|x| x.is_ascii_alphanumeric() || "._-".contains(x))
+    }
+
+    /// Load the target `name`, reloading its corpus and coverage from prior
+    /// runs of the server. A new target starts out empty.
+    fn load(hasher: &FalkHasher, name: &str) -> io::Result<Self> {
+        let dir = Path::new(TARGETS_DIR).join(name);
+        std::fs::create_dir_all(&dir)?;
+
+        // Reload the corpus and coverage from prior runs of the server
+        let mut inputs  = load_inputs(hasher, &dir.join("inputs"))?;
+        let coverage    = load_coverage(&dir.join("coverage.txt"))?;
+        let attribution = load_attribution(&dir.join("attribution.txt"))?;
+        let crashes     = load_crashes(&dir.join("crashes"))?;
+
+        // Load the seed corpus, these are sent to workers as they log in
+        let seeds = load_inputs(hasher, &dir.join("seeds"))?;
+        print!("Loaded target {}: {} inputs, {} seeds, {} coverage records, \
+                and {} crash buckets\n", name, inputs.len(), seeds.len(),
+               coverage.len(), crashes.len());
+        inputs.extend(seeds);
+
+        // Open the attribution log for appending
+        let attribution_file = OpenOptions::new()
+            .create(true).append(true).open(dir.join("attribution.txt"))?;
+
+        // Open the coverage log for appending, such that we keep the coverage
+        // from prior runs
+        let coverage_file = OpenOptions::new()
+            .create(true).append(true).open(dir.join("coverage.txt"))?;
+
+        Ok(Target {
+            name:          name.to_string(),
+            dir:           dir,
+            coverage:      RwLock::new(coverage),
+            inputs:        RwLock::new(inputs),
+            crashes:       RwLock::new(crashes),
+            modules:       Default::default(),
+            symbols:       Default::default(),
+            coverage_file: Mutex::new(coverage_file),
+
+            attribution:      RwLock::new(attribution),
+            attribution_file: Mutex::new(attribution_file),
+            minimized:        Default::default(),
+        })
+    }
+}
+
 /// A fuzzing session. This represents a unique `FuzzSession` on a server and
 /// may span multiple cores and IPs (in the case of multiple NICs)
 struct Session<'a> {
     /// Session ID, a "unique" identifier passed by the client
     id: u64,
 
+    /// Target this session is fuzzing
+    target: Arc<Target<'a>>,
+
     /// Unique core IDs of the workers on this session
     workers: BTreeSet<u32>,
 
@@ -35,6 +188,9 @@ struct Session<'a> {
     /// Time of the last packet reciept from this client
     last_packet: Instant,
 
+    /// Set when all workers of this session have disconnected
+    idle: bool,
+
     /// Number of fuzz cases performed on this client
     fuzz_cases: u64,
     
@@ -69,11 +225,68 @@ struct Session<'a> {
     /// Number of inputs uniquely reported by this session
     unique_inputs: u64,
 
+    /// Number of crash buckets reported by this session
+    crashes: u64,
+
+    /// Number of crash buckets which were first reported by this session
+    unique_crashes: u64,
+
     /// Set of coverage for this session
     coverage: BTreeSet<CoverageRecord<'a>>,
 
     /// Input stored on this session
     inputs: BTreeSet<InputRecord<'a>>,
+
+    /// Hashes of inputs which were minimized out of the corpus when this
+    /// session was created, these are never sent to this session
+    excluded_inputs: BTreeSet<u128>,
+
+    /// Commands from the operator which are sent to the session on its next
+    /// sync
+    commands: Vec<ServerMessage<'a>>,
+}
+
+/// A generation of a file being served to clients. A new generation is
+/// loaded every time the file changes on disk, old generations are kept alive
+/// by the connections which still have them mapped.
+struct FileGeneration {
+    /// Path of the file on disk
+    path: PathBuf,
+
+    /// Generation number, incremented on every reload of the file
+    generation: u64,
+
+    /// Content hash of the file, see `falktp::file_hash()`
+    hash: u128,
+
+    /// Hash of each page of the file
+    page_hashes: Vec<u128>,
+
+    /// Raw contents of the file
+    contents: Vec<u8>,
+}
+
+impl FileGeneration {
+    /// Load and hash `path` as generation `generation`
+    fn load(hasher: &FalkHasher, path: &Path, generation: u64)
+            -> io::Result<Self> {
+        let contents = std::fs::read(path)?;
+
+        // Hash each page, zero padding the final page
+        let page_hashes: Vec<u128> = contents.chunks(4096).map(|chunk| {
+            let mut page = [0u8; 4096];
+            page[..chunk.len()].copy_from_slice(chunk);
+            hasher.hash(&page)
+        }).collect();
+
+        Ok(FileGeneration {
+            path:        path.to_path_buf(),
+            generation:  generation,
+            hash:        falktp::file_hash(hasher, &page_hashes),
+            page_hashes: page_hashes,
+            contents:    contents,
+        })
+    }
 }
 
 /// A client (a unique IP address), which may be part of a set of IP addresses
@@ -84,6 +297,9 @@ struct Client<'a> {
 
     /// The session identifier of the session we belong to
     session_id: u64,
+
+    /// Target of the session we belong to
+    target: Arc<Target<'a>>,
 }
 
 fn stats(context: Arc<Context>) {
@@ -100,69 +316,311 @@ fn stats(context: Arc<Context>) {
         let mut total_workers  = 0usize;
         let mut total_sessions = 0u64;
 
+        // Total coverage and crashes over all targets
+        let mut total_coverage = 0usize;
+        let mut total_crashes  = 0usize;
+
+        // Count the coverage, inputs, and crashes of every target. This
+        // locks the targets, so it is done before locking the sessions.
+        let targets: BTreeMap<String, (usize, usize, usize)> = context
+            .targets.read().unwrap().iter().map(|(name, target)| {
+                (name.clone(), (target.coverage.read().unwrap().len(),
+                                target.inputs.read().unwrap().len(),
+                                target.crashes.read().unwrap().len()))
+            }).collect();
+
+        // Group the sessions by their target, including targets which
+        // currently have no sessions
         let sessions = context.sessions.read().unwrap();
-        for (_, session) in sessions.iter() {
+        let mut by_target: BTreeMap<String, Vec<_>> = targets.keys()
+            .map(|x| (x.clone(), Vec::new())).collect();
+        for session in sessions.values() {
             let session = session.read().unwrap();
+            by_target.entry(session.target.name.clone()).or_default()
+                .push(session);
+        }
+
+        for (name, sessions) in by_target.iter() {
+            if let Some(&(coverage, inputs, crashes)) = targets.get(name) {
+                print!("\x1b[33;1mTARGET {} | sessions {:3} | cov {:8} | \
+                        inp {:8} | crash {:6}\x1b[0m\n",
+                       name, sessions.len(), coverage, inputs, crashes);
 
-            // Compute the duration of time since the last report
-            let tsl = Instant::now() - session.last_packet;
-            let unresponsive = tsl > Duration::from_secs(5);
-
-            let uptime = (Instant::now() - session.first_packet).as_secs_f64();
-
-            let reset_pct =
-                session.reset_cycles as f64 / session.total_cycles as f64;
-            let vm_pct =
-                session.vm_cycles as f64 / session.total_cycles as f64;
-
-            print!("\x1b[34;1m    workers {:3} | cov {:8} ({:8}) | \
-                        inp {:8} ({:8}) | \
-                        cases {:14} [{:12.2} / s] | vm {:8.4} | \
-                        reset {:8.4} | {:016x} {}\x1b[0m\n",
-                   session.workers.len(),
-                   session.coverage.len(),
-                   session.unique_coverage,
-                   session.inputs.len(),
-                   session.unique_inputs,
-                   session.fuzz_cases,
-                   session.fuzz_cases as f64 / uptime,
-                   vm_pct,
-                   reset_pct,
-                   session.id,
-                   if unresponsive { "???" } else { "" });
-
-            print!("\x1b[34;1m    >>> Allocs {:10} | Frees {:10} | \
-                   Physical {:10.2} MiB / {:10.2} MiB | \
-                   VME/fc {:12.3}\x1b[0m\n",
-                   session.allocs,
-                   session.frees,
-                   (session.phys_total - session.phys_free) as f64 /
-                       1024. / 1024.,
-                   session.phys_total as f64 / 1024. / 1024.,
-                   session.vm_exits as f64 / session.fuzz_cases as f64);
-
-            if !unresponsive {
-                total_cases    += session.fuzz_cases;
-                total_workers  += session.workers.len();
-                total_sessions += 1;
+                total_coverage += coverage;
+                total_crashes  += crashes;
+            }
+
+            for session in sessions.iter() {
+                // Compute the duration of time since the last report
+                let tsl = Instant::now() - session.last_packet;
+                let unresponsive =
+                    session.idle || tsl > Duration::from_secs(5);
+
+                let uptime =
+                    (Instant::now() - session.first_packet).as_secs_f64();
+
+                let reset_pct =
+                    session.reset_cycles as f64 / session.total_cycles as f64;
+                let vm_pct =
+                    session.vm_cycles as f64 / session.total_cycles as f64;
+
+                print!("\x1b[34;1m    workers {:3} | cov {:8} ({:8}) | \
+                            inp {:8} ({:8}) | \
+                            cases {:14} [{:12.2} / s] | vm {:8.4} | \
+                            reset {:8.4} | {:016x} {}\x1b[0m\n",
+                       session.workers.len(),
+                       session.coverage.len(),
+                       session.unique_coverage,
+                       session.inputs.len(),
+                       session.unique_inputs,
+                       session.fuzz_cases,
+                       session.fuzz_cases as f64 / uptime,
+                       vm_pct,
+                       reset_pct,
+                       session.id,
+                       if session.idle {
+                           "idle"
+                       } else if unresponsive {
+                           "???"
+                       } else {
+                           ""
+                       });
+
+                print!("\x1b[34;1m    >>> Allocs {:10} | Frees {:10} | \
+                       Physical {:10.2} MiB / {:10.2} MiB | \
+                       VME/fc {:12.3} | crash {:6} ({:6})\x1b[0m\n",
+                       session.allocs,
+                       session.frees,
+                       (session.phys_total - session.phys_free) as f64 /
+                           1024. / 1024.,
+                       session.phys_total as f64 / 1024. / 1024.,
+                       session.vm_exits as f64 / session.fuzz_cases as f64,
+                       session.crashes,
+                       session.unique_crashes);
+
+                if !unresponsive {
+                    total_cases    += session.fuzz_cases;
+                    total_workers  += session.workers.len();
+                    total_sessions += 1;
+                }
             }
         }
 
         let cases_delta = total_cases.saturating_sub(last_cases);
-        let coverage = context.coverage.read().unwrap().len();
         print!("\x1b[32;1mTOTALS: workers {:5} ({:3}) | cases {:14} \
                 [{:12.2} / s] | \
-                cov {:8}\x1b[0m\n\n",
+                cov {:8} | crash {:6}\x1b[0m\n\n",
                total_workers, total_sessions,
                total_cases,
                cases_delta as f64 / PRINT_DELAY.as_secs_f64(),
-               coverage);
+               total_coverage, total_crashes);
 
         // Update last cases
         last_cases = total_cases;
     }
 }
 
+/// A `TcpStream` which tracks whether the peer has closed the connection, such
+/// that a disconnect can be told apart from a malformed packet
+struct ClientStream {
+    /// Underlying TCP stream
+    stream: TcpStream,
+
+    /// Set once a read has hit EOF or failed
+    closed: bool,
+}
+
+impl io::Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = io::Read::read(&mut self.stream, buf);
+        match ret {
+            Ok(0) if buf.len() > 0 => self.closed = true,
+            Err(_)                 => self.closed = true,
+            _                      => {}
+        }
+        ret
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::Write::write(&mut self.stream, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.stream)
+    }
+}
+
+/// Create an error for a misbehaving client
+fn protocol_error<E>(err: E) -> io::Error
+        where E: Into<Box<dyn std::error::Error + Send + Sync>> {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Serialize a message into the send buffer of a client without flushing it
+fn queue(stream: &mut BufferedIo<ClientStream>, msg: ServerMessage)
+        -> io::Result<()> {
+    msg.serialize(stream).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::BrokenPipe, "Failed to send packet")
+    })
+}
+
+/// Flush all queued messages to a client
+fn flush(stream: &mut BufferedIo<ClientStream>) -> io::Result<()> {
+    stream.flush().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::BrokenPipe, "Failed to send packet")
+    })
+}
+
+/// Serialize and send a message to a client
+fn send(stream: &mut BufferedIo<ClientStream>, msg: ServerMessage)
+        -> io::Result<()> {
+    queue(stream, msg)?;
+    flush(stream)
+}
+
+/// Stream `count` pages of `file` starting at `offset` to a client, as one
+/// `ReadPageResponse` per page. Pages past the end of the file are zero
+/// filled.
+fn read_pages(stream: &mut BufferedIo<ClientStream>, file: &FileGeneration,
+              offset: usize, count: usize) -> io::Result<()> {
+    if VERBOSE {
+        print!("Read {} pages generation {} offset {}\n",
+               count, file.generation, offset);
+    }
+
+    // Refuse unbounded reads
+    if count > MAX_READ_PAGES {
+        return Err(protocol_error("Too many pages requested"));
+    }
+
+    let contents = &file.contents;
+    if offset >= contents.len() {
+        return Err(protocol_error("Read past end of file"));
+    }
+
+    // Copy whatever is present in the file, the rest remains zeroed
+    let mut pages = vec![0u8; count * 4096];
+    let avail = (contents.len() - offset).min(pages.len());
+    pages[..avail].copy_from_slice(&contents[offset..offset + avail]);
+
+    // Stream the pages to the client
+    for page in pages.chunks(4096) {
+        let mut tmp = [0u8; 4096];
+        tmp.copy_from_slice(page);
+        queue(stream, ServerMessage::ReadPageResponse(tmp))?;
+    }
+
+    flush(stream)
+}
+
+/// Write a page from a client back to the file on disk. Bytes past the end of
+/// the file are dropped, as a mapping never changes the size of a file.
+///
+/// The write goes to the file on disk rather than to `file`, such that the
+/// next `GetFileId` of the file loads the written contents as a new
+/// generation, while clients which have `file` mapped keep seeing the
+/// contents they verified.
+fn write_page(file: &FileGeneration, offset: usize, contents: &[u8; 4096])
+        -> io::Result<()> {
+    if offset % 4096 != 0 || offset >= file.contents.len() {
+        return Err(protocol_error(format!(
+            "Invalid write at offset {:#x}", offset)));
+    }
+
+    // Only write the part of the page within the file
+    let size = std::cmp::min(4096, file.contents.len() - offset);
+
+    let mut fd = OpenOptions::new().write(true).open(&file.path)?;
+    fd.seek(SeekFrom::Start(offset as u64))?;
+    fd.write_all(&contents[..size])
+}
+
+/// Get the current generation of `filename` in the `files` directory
+/// `cur_dir`, loading it if it has not been loaded yet or has changed on disk
+///
+/// Returns the file ID along with the generation. Fails if the file does not
+/// exist or is outside of `cur_dir`.
+fn open_file(context: &Context, cur_dir: &Path, filename: &str)
+        -> io::Result<(u64, Arc<FileGeneration>)> {
+    // Normalize the filename
+    let filename = std::fs::canonicalize(cur_dir.join(filename))?;
+
+    // Jail the filename to the current directory
+    if !filename.starts_with(cur_dir) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  "File outside of the files directory"));
+    }
+
+    // Compute the file ID by hashing the file path
+    let mut hasher = DefaultHasher::new();
+    filename.to_str().unwrap().hash(&mut hasher);
+    let file_id = hasher.finish();
+
+    // Get the modified time of the file
+    let modified = filename.metadata()?.modified()?;
+
+    // Get access to the file database
+    let mut file_db = context.file_db.write().unwrap();
+
+    // Get the current generation of the file
+    let current = file_db.get(&file_id)
+        .map(|(loaded, file)| (*loaded, file.clone()));
+
+    let file = match current {
+        Some((loaded, file)) if loaded >= modified => file,
+        current => {
+            // The file has not been loaded or has been modified, load it as
+            // a new generation
+            let generation = if let Some((_, old)) = current {
+                print!("Reloading {:?}\n", filename);
+                old.generation + 1
+            } else {
+                print!("Loading {:?}\n", filename);
+                0
+            };
+
+            let file = Arc::new(FileGeneration::load(
+                &context.hasher, &filename, generation)?);
+            file_db.insert(file_id, (modified, file.clone()));
+            file
+        }
+    };
+
+    Ok((file_id, file))
+}
+
+/// Remove a worker from its session. Once the last worker of a session is
+/// gone the session is marked idle and the client is dropped, the session
+/// itself is kept such that its stats and coverage are not lost.
+fn disconnect(context: &Context, src_ip: IpAddr, session_id: u64,
+              core_id: u32) {
+    // Locks are taken in the same order as `Login` to prevent deadlocks
+    let sessions    = context.sessions.read().unwrap();
+    let mut clients = context.clients.write().unwrap();
+
+    // Get the session, it may already have been replaced by a new session
+    let session = if let Some(session) = sessions.get(&session_id) {
+        session
+    } else {
+        return;
+    };
+
+    // Remove the worker from the session
+    let mut session = session.write().unwrap();
+    session.workers.remove(&core_id);
+
+    if session.workers.is_empty() {
+        session.idle = true;
+
+        // Drop the client, as long as it still belongs to this session
+        if clients.get(&src_ip).map(|x| x.session_id) == Some(session_id) {
+            clients.remove(&src_ip);
+        }
+    }
+}
+
 fn handle_client(stream: TcpStream,
                  context: Arc<Context>) -> io::Result<()> {
     // Disable Nagle's algoritm
@@ -172,15 +630,88 @@ fn handle_client(stream: TcpStream,
     let src_ip = stream.peer_addr()?.ip();
 
     // Convert the stream to a buffered I/O stream
-    let mut stream = BufferedIo::new(stream);
+    let mut stream = BufferedIo::new(ClientStream {
+        stream: stream,
+        closed: false,
+    });
+
+    // Session ID and core ID this connection has logged in as
+    let mut login = None;
 
+    // Handle packets until the client disconnects or misbehaves
+    if let Err(err) = serve_client(&mut stream, &context, src_ip, &mut login) {
+        print!("Protocol error from {}: {}\n", src_ip, err);
+    } else if VERBOSE {
+        print!("Client {} disconnected\n", src_ip);
+    }
+
+    // Remove the worker from its session
+    if let Some((session_id, core_id)) = login {
+        disconnect(&context, src_ip, session_id, core_id);
+    }
+
+    Ok(())
+}
+
+/// Send all `inputs` of the session's target which `session` does not have
+/// yet, other than the inputs which were excluded from the session
+///
+/// `inputs` is the locked input database of the target, which must be
+/// locked before the session, see `Context`
+fn send_inputs(stream: &mut BufferedIo<ClientStream>,
+               inputs: &BTreeSet<InputRecord>, session: &Session)
+        -> io::Result<()> {
+    // Check if the session is behind on inputs
+    if inputs.len() > session.inputs.len() {
+        // Get a list of everything that we need to inform the client of
+        let delta: Vec<InputRecord> =
+            inputs.difference(&session.inputs)
+            .filter(|x| !session.excluded_inputs.contains(&x.hash))
+            .map(|x| InputRecord {
+                hash:  x.hash,
+                input: x.input.clone(),
+            }).collect();
+
+        // Send the input deltas to the worker
+        if delta.len() > 0 {
+            send(stream, ServerMessage::Inputs(
+                Cow::Borrowed(delta.as_slice())))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle packets from a client until it disconnects. Returns an error if the
+/// client sent something we do not understand
+fn serve_client(stream: &mut BufferedIo<ClientStream>, context: &Context,
+                src_ip: IpAddr, login: &mut Option<(u64, u32)>)
+        -> io::Result<()> {
     // Get the current directory
     let cur_dir = std::fs::canonicalize("files")?;
 
+    // Generations of files mapped by this client, indexed by file ID and
+    // generation. Holding a reference here keeps a generation alive even
+    // once the file has been reloaded.
+    let mut mapped: HashMap<(u64, u64), Arc<FileGeneration>> =
+        HashMap::new();
+
+    // Generations in `mapped` which the client mapped writable, only these
+    // may be written to
+    let mut writable_mapped: HashSet<(u64, u64)> = HashSet::new();
+
     loop {
         // Deserialize the message
-        let msg = ServerMessage::deserialize(&mut stream)
-            .expect("Failed to deserialize ServerMessage");
+        let msg = match ServerMessage::deserialize(stream) {
+            Some(msg) => msg,
+            None if stream.get_ref().closed => {
+                // Client has disconnected
+                return Ok(());
+            }
+            None => {
+                return Err(protocol_error("Malformed or unknown packet"));
+            }
+        };
 
         // Insert the client record if one does not exist
         let mut client = {
@@ -197,7 +728,12 @@ fn handle_client(stream: TcpStream,
                     vm_cycles, reset_cycles, allocs, frees,
                     phys_free, phys_total, vm_exits } => {
                 // Get access to the client and session
-                let client = client.unwrap();
+                let client = client.ok_or_else(
+                    || protocol_error("Statistics sent before login"))?;
+
+                // Get access to the target's databases before the session
+                let inputs   = client.target.inputs.read().unwrap();
+                let coverage = client.target.coverage.read().unwrap();
                 let mut session = client.session.write().unwrap();
 
                 // Update the client statistics
@@ -211,58 +747,58 @@ fn handle_client(stream: TcpStream,
                 session.phys_free    = phys_free;
                 session.phys_total   = phys_total;
 
-                {
-                    // Get access to the global input database
-                    let inputs = context.inputs.read().unwrap();
-
-                    // Check if the session is behind on inputs
-                    if inputs.len() > session.inputs.len() {
-                        // Get a list of everything that we need to inform the
-                        // client of
-                        let delta: Vec<InputRecord> =
-                            inputs.difference(&session.inputs)
-                            .map(|x| InputRecord {
-                                hash:  x.hash,
-                                input: x.input.clone(),
-                            }).collect();
-
-                        // Send the input deltas to the worker
-                        ServerMessage::Inputs(
-                            Cow::Borrowed(delta.as_slice()))
-                            .serialize(&mut stream).unwrap();
-                        stream.flush().unwrap();
-                    }
+                // Send any inputs the session is missing
+                send_inputs(stream, &inputs, &session)?;
+
+                // Check if the session is behind on coverage
+                if coverage.len() > session.coverage.len() {
+                    // Get a list of everything that we need to inform the
+                    // client of
+                    let delta: Vec<CoverageRecord> =
+                        coverage.difference(&session.coverage)
+                        .map(|x| CoverageRecord {
+                            module: x.module.as_ref()
+                                .map(|x| Cow::Owned((**x).clone())),
+                            offset: x.offset,
+                        }).collect();
+
+                    // Send the coverage deltas to the worker
+                    send(stream, ServerMessage::Coverage(
+                        Cow::Borrowed(delta.as_slice())))?;
                 }
 
-                {
-                    // Get access to the global coverage database
-                    let coverage = context.coverage.read().unwrap();
-
-                    // Check if the session is behind on coverage
-                    if coverage.len() > session.coverage.len() {
-                        // Get a list of everything that we need to inform the
-                        // client of
-                        let delta: Vec<CoverageRecord> =
-                            coverage.difference(&session.coverage)
-                            .map(|x| CoverageRecord {
-                                module: x.module.as_ref()
-                                    .map(|x| Cow::Owned((**x).clone())),
-                                offset: x.offset,
-                            }).collect();
-
-                        // Send the coverage deltas to the worker
-                        ServerMessage::Coverage(
-                            Cow::Borrowed(delta.as_slice()))
-                            .serialize(&mut stream).unwrap();
-                        stream.flush().unwrap();
-                    }
+                // Send any pending commands from the operator
+                for command in session.commands.drain(..) {
+                    queue(stream, command)?;
                 }
 
                 // Done syncing
-                ServerMessage::SyncComplete.serialize(&mut stream).unwrap();
-                stream.flush().unwrap();
+                send(stream, ServerMessage::SyncComplete)?;
             }
-            ServerMessage::Login(session_id, core_id) => {
+            ServerMessage::Login { version, session_id, core_id,
+                                   target } => {
+                // Let the client know which protocol we speak, such that it
+                // can report a mismatch as well
+                send(stream, ServerMessage::LoginResponse {
+                    version: PROTOCOL_VERSION,
+                })?;
+
+                // Refuse clients built with a different protocol version
+                if version != PROTOCOL_VERSION {
+                    return Err(protocol_error(format!(
+                        "Protocol version mismatch, client is version {} \
+                         but server is version {}",
+                        version, PROTOCOL_VERSION)));
+                }
+
+                // Get the target, loading it if this is the first time it is
+                // fuzzed
+                if !Target::valid_name(&target) {
+                    return Err(protocol_error(format!(
+                        "Invalid target name {:?}", target)));
+                }
+                let target = get_target(context, &target)?;
+
                 // If there is no existing client or the session ID has changed
                 // create a new client
                 if let Some(ref cl) = client {
@@ -287,15 +823,21 @@ fn handle_client(stream: TcpStream,
                 }
                 
                 if client.is_none() {
+                    // Inputs a new session starts out without, this locks
+                    // the target so it must happen before locking `sessions`
+                    let excluded = excluded_inputs(&target);
+
                     // New client, potentially new session
                     let mut sessions = context.sessions.write().unwrap();
                     let session = sessions.entry(session_id)
                             .or_insert_with(|| {
                         Arc::new(RwLock::new(Session {
                             id:              session_id,
+                            target:          target.clone(),
                             workers:         BTreeSet::new(),
                             first_packet:    Instant::now(),
                             last_packet:     Instant::now(),
+                            idle:            false,
                             fuzz_cases:      0,
                             total_cycles:    0,
                             reset_cycles:    0,
@@ -303,12 +845,16 @@ fn handle_client(stream: TcpStream,
                             vm_exits:        0,
                             unique_coverage: 0,
                             unique_inputs:   0,
+                            crashes:         0,
+                            unique_crashes:  0,
                             allocs:          0,
                             frees:           0,
                             phys_free:       0,
                             phys_total:      0,
                             coverage:        BTreeSet::new(),
                             inputs:          BTreeSet::new(),
+                            excluded_inputs: excluded,
+                            commands:        Vec::new(),
                         }))
                     });
 
@@ -318,6 +864,8 @@ fn handle_client(stream: TcpStream,
                         clients.insert(src_ip, Arc::new(Client {
                             session:    session.clone(),
                             session_id: session_id,
+                            target:     session.read().unwrap()
+                                .target.clone(),
                         }));
                         clients.get_mut(&src_ip).map(|x| x.clone())
                     };
@@ -326,12 +874,35 @@ fn handle_client(stream: TcpStream,
                 // Client is always valid at this point
                 let client = client.unwrap();
 
-                // Insert our core ID into the session
+                // A session only ever fuzzes a single target
+                if !Arc::ptr_eq(&client.target, &target) {
+                    return Err(protocol_error(format!(
+                        "Session {:016x} is fuzzing {}, not {}",
+                        session_id, client.target.name, target.name)));
+                }
+
+                // Insert our core ID into the session, after getting access
+                // to the target's inputs
+                let inputs = client.target.inputs.read().unwrap();
                 let mut session = client.session.write().unwrap();
                 session.workers.insert(core_id);
+                session.idle = false;
+
+                // Save the login such that we can remove the worker from the
+                // session on disconnect
+                *login = Some((session_id, core_id));
+
+                // Send the corpus (including seeds) to the worker, such that
+                // it has inputs before the first fuzz case
+                send_inputs(stream, &inputs, &session)?;
+                send(stream, ServerMessage::SyncComplete)?;
             }
             ServerMessage::Inputs(new_inputs) => {
-                let mut inputs = context.inputs.write().unwrap();
+                // Get access to the client
+                let client = client.as_ref().ok_or_else(
+                    || protocol_error("Inputs sent before login"))?;
+
+                let mut inputs = client.target.inputs.write().unwrap();
 
                 // Go through each reported input
                 for input in new_inputs.iter() {
@@ -340,29 +911,32 @@ fn handle_client(stream: TcpStream,
                         inputs.insert(input.clone());
                         
                         // Update unique inputs stats for this session
-                        let mut session = client.as_ref().unwrap()
-                            .session.write().unwrap();
+                        let mut session = client.session.write().unwrap();
                         session.unique_inputs += 1;
 
                         // Save the input to disk
-                        std::fs::create_dir_all("inputs")?;
-                        std::fs::write(Path::new("inputs")
-                                       .join(format!("{:032x}", input.hash)),
+                        let dir = client.target.dir.join("inputs");
+                        std::fs::create_dir_all(&dir)?;
+                        std::fs::write(dir.join(format!("{:032x}",
+                                                        input.hash)),
                                        &**input.input)?;
                     }
 
                     // Update the per-client inputs
-                    if let Some(ref mut client) = client {
-                        let mut session = client.session.write().unwrap();
-                        if !session.inputs.contains(input) {
-                            session.inputs.insert(input.clone());
-                        }
+                    let mut session = client.session.write().unwrap();
+                    if !session.inputs.contains(input) {
+                        session.inputs.insert(input.clone());
                     }
                 }
             }
             ServerMessage::Coverage(records) => {
-                let mut coverage = context.coverage.write().unwrap();
-                let mut coverage_file = context.coverage_file.lock().unwrap();
+                // Get access to the client
+                let client = client.as_ref().ok_or_else(
+                    || protocol_error("Coverage sent before login"))?;
+
+                let mut coverage = client.target.coverage.write().unwrap();
+                let mut coverage_file =
+                    client.target.coverage_file.lock().unwrap();
 
                 // Go through each coverage record that was reported
                 for record in records.iter() {
@@ -372,114 +946,556 @@ fn handle_client(stream: TcpStream,
                             write!(coverage_file, "{}+", module)?;
                         }
                         write!(coverage_file, "{:#x}\n", record.offset)?;
+
+                        // Log the time it took to first hit this coverage
+                        let mut first_hit_file =
+                            context.first_hit_file.lock().unwrap();
+                        write!(first_hit_file, "{:.3},{:.3},{},{:016x},",
+                               context.start.elapsed().as_secs_f64(),
+                               history::unix_time(), client.target.name,
+                               client.session_id)?;
+                        if let Some(module) = &record.module {
+                            write!(first_hit_file, "{}+", module)?;
+                        }
+                        write!(first_hit_file, "{:#x}\n", record.offset)?;
                         coverage.insert(record.clone());
                         
                         // Update unique coverage stats for this session
-                        let mut session = client.as_ref().unwrap()
-                            .session.write().unwrap();
+                        let mut session = client.session.write().unwrap();
                         session.unique_coverage += 1;
                     }
 
                     // Update the per-client coverage records
-                    if let Some(ref mut client) = client {
-                        let mut session = client.session.write().unwrap();
-                        if !session.coverage.contains(&record) {
-                            session.coverage.insert(record.clone());
+                    let mut session = client.session.write().unwrap();
+                    if !session.coverage.contains(&record) {
+                        session.coverage.insert(record.clone());
+                    }
+                }
+            }
+            ServerMessage::Attributions(records) => {
+                // Get access to the client
+                let client = client.as_ref().ok_or_else(
+                    || protocol_error("Attributions sent before login"))?;
+
+                let mut attribution =
+                    client.target.attribution.write().unwrap();
+                let mut attribution_file =
+                    client.target.attribution_file.lock().unwrap();
+
+                for AttributionRecord { input, coverage } in records.iter() {
+                    // Update the attribution database
+                    if attribution.entry(*input).or_default()
+                            .insert(coverage.clone()) {
+                        write!(attribution_file, "{:032x} ", input)?;
+                        if let Some(module) = &coverage.module {
+                            write!(attribution_file, "{}+", module)?;
                         }
+                        write!(attribution_file, "{:#x}\n", coverage.offset)?;
                     }
                 }
             }
-            ServerMessage::GetFileId(filename) => {
-                // Normalize the filename
-                if let Ok(filename) =
-                        std::fs::canonicalize(Path::new("files")
-                                              .join(&*filename)) {
-                    // Jail the filename to the current directory
-                    if !filename.starts_with(&cur_dir) {
+            ServerMessage::Modules(new_modules) => {
+                // Get access to the client
+                let client = client.as_ref().ok_or_else(
+                    || protocol_error("Modules sent before login"))?;
+
+                let mut modules = client.target.modules.write().unwrap();
+                let mut symbols = client.target.symbols.write().unwrap();
+
+                // Keep the first base and size reported for each module, it
+                // is only used to give tooling a rough layout
+                for module in new_modules.iter() {
+                    modules.entry(module.name.to_string())
+                        .or_insert((module.base, module.size));
+                    symbols.entry(module.name.to_string())
+                        .or_insert_with(|| symbols::Symbols::load(module));
+                }
+            }
+            ServerMessage::Crash(crash) => {
+                // Get access to the session
+                let client = client.as_ref().ok_or_else(
+                    || protocol_error("Crash sent before login"))?;
+
+                // Crashes are bucketed by their kind and location
+                let bucket = (
+                    crash.kind.to_string(),
+                    crash.module.as_ref().map(|x| x.to_string()),
+                    crash.offset,
+                );
+
+                // Check if this is a unique crash for the target
+                let mut crashes = client.target.crashes.write().unwrap();
+                let unique = !crashes.contains(&bucket);
+                if unique {
+                    print!("New crash {} in {}\n", crash_bucket_name(&crash),
+                           client.target.name);
+
+                    // Save the input and a crash report to disk
+                    let dir = client.target.dir.join("crashes")
+                        .join(crash_bucket_name(&crash));
+                    std::fs::create_dir_all(&dir)?;
+                    std::fs::write(
+                        dir.join(format!("{:032x}", crash.input.hash)),
+                        &**crash.input.input)?;
+                    std::fs::write(
+                        dir.join(format!("{:032x}.txt", crash.input.hash)),
+                        crash_report(&crash, &client.target,
+                                     client.session_id, src_ip))?;
+
+                    crashes.insert(bucket);
+                }
+
+                // Update the crash stats of the session
+                let mut session = client.session.write().unwrap();
+                session.crashes += 1;
+                if unique {
+                    session.unique_crashes += 1;
+                }
+            }
+            ServerMessage::Trace(trace) => {
+                // Get access to the session
+                let client = client.as_ref().ok_or_else(
+                    || protocol_error("Trace sent before login"))?;
+
+                print!("New trace of {:032x} ({} steps) in {}\n",
+                       trace.input.hash, trace.total_steps,
+                       client.target.name);
+
+                // Save the input and the rendered trace to disk
+                let dir = client.target.dir.join("traces");
+                std::fs::create_dir_all(&dir)?;
+                std::fs::write(
+                    dir.join(format!("{:032x}", trace.input.hash)),
+                    &**trace.input.input)?;
+                std::fs::write(
+                    dir.join(format!("{:032x}.txt", trace.input.hash)),
+                    trace_report(&trace, &client.target))?;
+            }
+            ServerMessage::GetFileId { filename, writable } => {
+                // Look up the file, letting the client know if there is no
+                // such file rather than leaving it waiting for a reply
+                let (file_id, file) =
+                        match open_file(context, &cur_dir, &filename) {
+                    Ok(file) => file,
+                    Err(_) => {
+                        send(stream, ServerMessage::NoSuchFile)?;
                         continue;
                     }
+                };
 
-                    // Compute the file ID by hashing the file path
-                    let mut hasher = DefaultHasher::new();
-                    filename.to_str().unwrap().hash(&mut hasher);
-                    let file_id = hasher.finish();
-
-                    // Get the modified time of the file
-                    let modified = filename.metadata().unwrap()
-                        .modified().unwrap();
-
-                    // Get access to the file database
-                    let mut file_db = context.file_db.write().unwrap();
-
-                    // Insert into the file database if needed
-                    let file = file_db.entry(file_id)
-                        .or_insert_with(|| {
-                            print!("Loading {:?}\n", filename);
-                            (modified, std::fs::read(&filename).unwrap())
-                        });
-
-                    // Check if we should reload the file since it has been
-                    // modified
-                    if file.0 < modified {
-                        print!("Reloading {:?}\n", filename);
-                        *file = (modified, std::fs::read(&filename).unwrap());
-                    }
+                // Map this generation for the client
+                mapped.insert((file_id, file.generation), file.clone());
+                if writable {
+                    writable_mapped.insert((file_id, file.generation));
+                }
 
-                    // Send the ID response
-                    ServerMessage::FileId {
-                        id:   file_id,
-                        size: file.1.len(),
-                    }.serialize(&mut stream).unwrap();
-                    stream.flush().unwrap();
+                // Send the ID response
+                send(stream, ServerMessage::FileId {
+                    id:         file_id,
+                    generation: file.generation,
+                    hash:       file.hash,
+                    size:       file.contents.len(),
+                })?;
+            },
+            ServerMessage::GetPageHashes { id, generation } => {
+                let file = mapped.get(&(id, generation)).ok_or_else(
+                    || protocol_error("Page hashes of unmapped file"))?;
+                send(stream, ServerMessage::PageHashes(
+                    Cow::Borrowed(file.page_hashes.as_slice())))?;
+            },
+            ServerMessage::ReadPage { id, generation, offset } => {
+                let file = mapped.get(&(id, generation)).ok_or_else(
+                    || protocol_error("Read of unmapped file"))?;
+                read_pages(stream, file, offset, 1)?;
+            },
+            ServerMessage::ReadPages { id, generation, offset, count } => {
+                let file = mapped.get(&(id, generation)).ok_or_else(
+                    || protocol_error("Read of unmapped file"))?;
+                read_pages(stream, file, offset, count)?;
+            },
+            ServerMessage::WritePage { id, generation, offset, contents } => {
+                let file = mapped.get(&(id, generation)).ok_or_else(
+                    || protocol_error("Write of unmapped file"))?;
+                if !writable_mapped.contains(&(id, generation)) {
+                    return Err(protocol_error("Write of read only file"));
                 }
+                write_page(file, offset, &contents)?;
             },
-            ServerMessage::ReadPage { id, offset } => {
-                if VERBOSE {
-                    print!("Read page {:016x} offset {}\n",
-                           id, offset);
+            ServerMessage::Flush { id, generation } => {
+                let file = mapped.get(&(id, generation)).ok_or_else(
+                    || protocol_error("Flush of unmapped file"))?;
+                if !writable_mapped.contains(&(id, generation)) {
+                    return Err(protocol_error("Flush of read only file"));
                 }
 
-                // Get access to the file database
-                let file_db = context.file_db.read().unwrap();
+                // Writes are done as they arrive, make sure they are on disk
+                OpenOptions::new().write(true).open(&file.path)?
+                    .sync_data()?;
+                send(stream, ServerMessage::FlushResponse)?;
+            },
+            _ => return Err(protocol_error("Unexpected packet")),
+        }
+    }
+}
 
-                // Attempt to get access to the file contents at the requested
-                // location
-                let sliced = file_db.get(&id).and_then(|(_, x)| {
-                    x.get(offset..offset + 4096)
-                });
+/// Load all inputs from the directory `dir`, used both for inputs which were
+/// previously saved to a target's `inputs` directory and for its `seeds`
+/// directory
+///
+/// Inputs are re-hashed rather than trusting their filenames, such that a
+/// renamed or hand-placed file still gets the hash a worker would compute
+fn load_inputs<'a>(hasher: &FalkHasher, dir: &Path)
+        -> io::Result<BTreeSet<InputRecord<'a>>> {
+    let mut inputs = BTreeSet::new();
+
+    // If there is no input directory, there is nothing to load
+    if !dir.is_dir() {
+        return Ok(inputs);
+    }
 
-                if let Some(sliced) = sliced {
-                    let mut tmp = [0u8; 4096];
-                    tmp.copy_from_slice(sliced);
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() { continue; }
 
-                    ServerMessage::ReadPageResponse(tmp)
-                        .serialize(&mut stream).unwrap();
-                    stream.flush().unwrap();
-                } else {
-                }
-            },
-            _ => panic!("Unhandled packet\n"),
+        // Read and hash the input
+        let input = std::fs::read(&path)?;
+        inputs.insert(InputRecord {
+            hash:  hasher.hash(&input),
+            input: Cow::Owned(Arc::new(input)),
+        });
+    }
+
+    Ok(inputs)
+}
+
+/// Parse a line from `coverage.txt` in the form of `module+0xoffset` or
+/// `0xoffset` back into a `CoverageRecord`
+fn parse_coverage_record<'a>(line: &str) -> Option<CoverageRecord<'a>> {
+    // Split the module and the offset. Module names may themselves contain
+    // a `+`, but offsets never will, thus we split on the last one
+    let (module, offset) = match line.rfind('+') {
+        Some(idx) => (Some(&line[..idx]), &line[idx + 1..]),
+        None      => (None, line),
+    };
+
+    // Parse the offset
+    let offset = offset.trim();
+    if !offset.starts_with("0x") { return None; }
+    let offset = u64::from_str_radix(&offset[2..], 16).ok()?;
+
+    Some(CoverageRecord {
+        module: module.map(|x| Cow::Owned(Arc::new(x.to_string()))),
+        offset: offset,
+    })
+}
+
+/// Load the input to coverage attribution which was previously logged to
+/// the attribution log at `path`
+fn load_attribution<'a>(path: &Path)
+        -> io::Result<BTreeMap<u128, BTreeSet<CoverageRecord<'a>>>> {
+    let mut attribution: BTreeMap<_, BTreeSet<_>> = BTreeMap::new();
+
+    // If there is no attribution file, there is nothing to load
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(attribution);
         }
+        Err(err) => return Err(err),
+    };
+
+    for line in contents.lines() {
+        if line.trim().is_empty() { continue; }
+
+        // Lines are in the form of `hash coverage`
+        let mut parts = line.splitn(2, ' ');
+        let hash   = parts.next()
+            .and_then(|x| u128::from_str_radix(x, 16).ok());
+        let record = parts.next().and_then(parse_coverage_record);
+
+        if let (Some(hash), Some(record)) = (hash, record) {
+            attribution.entry(hash).or_default().insert(record);
+        } else {
+            print!("Ignoring malformed attribution record {:?}\n", line);
+        }
+    }
+
+    Ok(attribution)
+}
+
+/// Get the hashes of inputs which should not be sent to a new session of
+/// `target` as they were minimized out of the corpus
+fn excluded_inputs(target: &Target) -> BTreeSet<u128> {
+    if !PUSH_MINIMIZED {
+        return BTreeSet::new();
+    }
+
+    if let Some(minimized) = &*target.minimized.read().unwrap() {
+        target.inputs.read().unwrap().iter()
+            .map(|x| x.hash)
+            .filter(|x| !minimized.contains(x))
+            .collect()
+    } else {
+        BTreeSet::new()
     }
 }
 
+/// Load all coverage which was previously logged to the coverage log at
+/// `path`
+fn load_coverage<'a>(path: &Path)
+        -> io::Result<BTreeSet<CoverageRecord<'a>>> {
+    let mut coverage = BTreeSet::new();
+
+    // If there is no coverage file, there is nothing to load
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(coverage);
+        }
+        Err(err) => return Err(err),
+    };
+
+    for line in contents.lines() {
+        if line.trim().is_empty() { continue; }
+
+        if let Some(record) = parse_coverage_record(line) {
+            coverage.insert(record);
+        } else {
+            // It's possible the server was killed mid-write, don't let a
+            // partial line prevent a restart
+            print!("Ignoring malformed coverage record {:?}\n", line);
+        }
+    }
+
+    Ok(coverage)
+}
+
+/// Load the crash buckets which were previously saved to the crash directory
+/// `dir`, from the `kind` and `rip` lines of their crash reports
+fn load_crashes(dir: &Path)
+        -> io::Result<BTreeSet<(String, Option<String>, u64)>> {
+    let mut crashes = BTreeSet::new();
+
+    // If there is no crash directory, there is nothing to load
+    if !dir.is_dir() {
+        return Ok(crashes);
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let bucket = entry?.path();
+        if !bucket.is_dir() { continue; }
+
+        // Every report in a bucket has the same kind and location, use the
+        // first one
+        let mut report = None;
+        for entry in std::fs::read_dir(&bucket)? {
+            let path = entry?.path();
+            if path.extension().map(|x| x == "txt").unwrap_or(false) {
+                report = Some(std::fs::read_to_string(&path)?);
+                break;
+            }
+        }
+
+        let mut kind = None;
+        let mut rip  = None;
+        for line in report.as_deref().unwrap_or("").lines() {
+            if let Some(val) = line.strip_prefix("kind:") {
+                kind = Some(val.trim().to_string());
+            } else if let Some(val) = line.strip_prefix("rip:") {
+                rip = parse_coverage_record(val.trim());
+            }
+        }
+
+        if let (Some(kind), Some(rip)) = (kind, rip) {
+            crashes.insert((kind, rip.module.map(|x| x.to_string()),
+                            rip.offset));
+        } else {
+            print!("Ignoring crash bucket without a report {:?}\n", bucket);
+        }
+    }
+
+    Ok(crashes)
+}
+
+/// Get the directory name of the bucket for a crash, in the form of
+/// `kind_module+0xoffset`
+fn crash_bucket_name(crash: &CrashRecord) -> String {
+    let name = if let Some(module) = &crash.module {
+        format!("{}_{}+{:#x}", crash.kind, module, crash.offset)
+    } else {
+        format!("{}_{:#x}", crash.kind, crash.offset)
+    };
+
+    // Make sure the name is safe to use as a single directory name
+    name.chars().map(|x| {
+        if x.is_ascii_alphanumeric() || "+._-".contains(x) { x } else { '_' }
+    }).collect()
+}
+
+/// Create a human readable report for a crash
+fn crash_report(crash: &CrashRecord, target: &Target, session_id: u64,
+                src_ip: IpAddr) -> String {
+    let regs = &crash.regs;
+
+    let mut report = String::new();
+    report += &format!("kind:    {}\n", crash.kind);
+    report += &format!("vmexit:  {}\n", crash.vmexit);
+    if let Some(module) = &crash.module {
+        report += &format!("rip:     {}+{:#x}\n", module, crash.offset);
+
+        let symbols = target.symbols.read().unwrap();
+        if let Some(symbol) =
+                symbols::symbolize(&symbols, module, crash.offset) {
+            report += &format!("symbol:  {}\n", symbol);
+        }
+    } else {
+        report += &format!("rip:     {:#x}\n", crash.offset);
+    }
+    report += &format!("target:  {}\n", target.name);
+    report += &format!("session: {:016x}\n", session_id);
+    report += &format!("client:  {}\n", src_ip);
+    report += &format!("input:   {:032x} ({} bytes)\n\n",
+                       crash.input.hash, crash.input.input.len());
+
+    report += &format!("rax {:016x} rbx {:016x} rcx {:016x} rdx {:016x}\n",
+                       regs.rax, regs.rbx, regs.rcx, regs.rdx);
+    report += &format!("rsi {:016x} rdi {:016x} rsp {:016x} rbp {:016x}\n",
+                       regs.rsi, regs.rdi, regs.rsp, regs.rbp);
+    report += &format!("r8  {:016x} r9  {:016x} r10 {:016x} r11 {:016x}\n",
+                       regs.r8, regs.r9, regs.r10, regs.r11);
+    report += &format!("r12 {:016x} r13 {:016x} r14 {:016x} r15 {:016x}\n",
+                       regs.r12, regs.r13, regs.r14, regs.r15);
+    report += &format!("rip {:016x} rfl {:016x} cs  {:016x} ss  {:016x}\n",
+                       regs.rip, regs.rflags, regs.cs, regs.ss);
+    report += &format!("cr0 {:016x} cr2 {:016x} cr3 {:016x} cr4 {:016x}\n",
+                       regs.cr0, regs.cr2, regs.cr3, regs.cr4);
+    report
+}
+
+/// Render an instruction trace as text, with one line per step
+fn trace_report(trace: &TraceRecord, target: &Target) -> String {
+    let symbols = target.symbols.read().unwrap();
+
+    let mut report = String::new();
+    report += &format!("vmexit:  {}\n", trace.vmexit);
+    report += &format!("target:  {}\n", target.name);
+    report += &format!("input:   {:032x} ({} bytes)\n",
+                       trace.input.hash, trace.input.input.len());
+    report += &format!("steps:   {} ({} recorded)\n\n",
+                       trace.total_steps, trace.steps.len());
+
+    for step in trace.steps.iter() {
+        // Symbolize the instruction if we can
+        let location = match &step.module {
+            Some(module) => {
+                symbols::symbolize(&symbols, module, step.offset)
+                    .unwrap_or_else(|| format!("{}+{:#x}", module,
+                                               step.offset))
+            }
+            None => format!("{:#x}", step.offset),
+        };
+        report += &location;
+
+        for (name, val) in trace.registers.iter().zip(step.regs.iter()) {
+            report += &format!(" {}={:x}", name, val);
+        }
+        for page in step.dirtied.iter() {
+            report += &format!(" dirtied={:#x}", page);
+        }
+        for page in step.faulted.iter() {
+            report += &format!(" faulted={:#x}", page);
+        }
+        report += "\n";
+    }
+
+    report
+}
+
+/// Get the target `name`, loading it if it has not been loaded yet
+fn get_target<'a>(context: &Context<'a>, name: &str)
+        -> io::Result<Arc<Target<'a>>> {
+    if let Some(target) = context.targets.read().unwrap().get(name) {
+        return Ok(target.clone());
+    }
+
+    // Check again with the write lock held, such that two workers logging
+    // in at the same time don't both load the target
+    let mut targets = context.targets.write().unwrap();
+    if let Some(target) = targets.get(name) {
+        return Ok(target.clone());
+    }
+
+    let target = Arc::new(Target::load(&context.hasher, name)?);
+    targets.insert(name.to_string(), target.clone());
+    Ok(target)
+}
+
+/// State shared by all client threads
+///
+/// To prevent deadlocks, locks are always taken in this order: `targets`,
+/// the databases and logs of a `Target`, `sessions`, `clients`, and finally
+/// a `Session`. Every handler which holds a `Session` and needs a database of
+/// its target must lock the database first.
 struct Context<'a> {
-    file_db:       RwLock<HashMap<u64, (SystemTime, Vec<u8>)>>,
-    coverage:      RwLock<BTreeSet<CoverageRecord<'a>>>,
-    inputs:        RwLock<BTreeSet<InputRecord<'a>>>,
+    hasher:        FalkHasher,
+
+    /// Current generation of each loaded file, along with the modified time
+    /// of the file when it was loaded
+    file_db:       RwLock<HashMap<u64, (SystemTime, Arc<FileGeneration>)>>,
+
+    /// Targets which have been fuzzed, by name
+    targets:       RwLock<BTreeMap<String, Arc<Target<'a>>>>,
+
     clients:       RwLock<HashMap<IpAddr, Arc<Client<'a>>>>,
     sessions:      RwLock<HashMap<u64, Arc<RwLock<Session<'a>>>>>,
-    coverage_file: Mutex<File>,
+
+    /// Time the server was started
+    start:          Instant,
+
+    /// Log of the time each coverage record was first hit
+    first_hit_file: Mutex<File>,
+
+    /// Rolling history of the statistics
+    history:        RwLock<history::History>,
 }
 
 fn main() -> io::Result<()> {
+    let hasher = FalkHasher::new();
+
+    // Pick up the databases of an older server, they would otherwise be
+    // silently ignored and the campaign would lose its corpus
+    migrate_legacy_databases()?;
+
+    // Reload the targets from prior runs of the server, such that their
+    // corpora are minimized and reported before a worker logs in
+    let mut targets = BTreeMap::new();
+    if Path::new(TARGETS_DIR).is_dir() {
+        for entry in std::fs::read_dir(TARGETS_DIR)? {
+            let entry = entry?;
+            if !entry.path().is_dir() { continue; }
+
+            if let Some(name) = entry.file_name().to_str()
+                    .filter(|x| Target::valid_name(x)) {
+                targets.insert(name.to_string(),
+                               Arc::new(Target::load(&hasher, name)?));
+            }
+        }
+    }
+
+    // Open the log of the time to first hit each coverage record
+    let first_hit_file = history::open_csv("coverage_first_hit.csv",
+        "elapsed,time,target,session,coverage\n")?;
+
     let context = Arc::new(Context {
+        hasher:        hasher,
         file_db:       Default::default(),
-        coverage:      Default::default(),
-        inputs:        Default::default(),
+        targets:       RwLock::new(targets),
         clients:       Default::default(),
         sessions:      Default::default(),
-        coverage_file: Mutex::new(File::create("coverage.txt")?),
+
+        start:          Instant::now(),
+        first_hit_file: Mutex::new(first_hit_file),
+        history:        Default::default(),
     });
 
     // Bind to all network devices on TCP port 1911
@@ -490,6 +1506,40 @@ fn main() -> io::Result<()> {
         std::thread::spawn(move || stats(context));
     }
 
+    {
+        // Handle commands from the operator
+        let context = context.clone();
+        std::thread::spawn(move || console::console(context));
+    }
+
+    {
+        // Periodically log the statistics history
+        let context = context.clone();
+        std::thread::spawn(move || history::periodic_sample(context));
+    }
+
+    {
+        // Periodically minimize the corpora
+        let context = context.clone();
+        std::thread::spawn(move || minimize::periodic_minimize(context));
+    }
+
+    {
+        // Periodically export coverage as drcov
+        let context = context.clone();
+        std::thread::spawn(move || drcov::periodic_export(context));
+    }
+
+    {
+        // Serve stats over HTTP
+        let context = context.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = http::serve(HTTP_ADDR, context) {
+                print!("Failed to serve HTTP on {}: {}\n", HTTP_ADDR, err);
+            }
+        });
+    }
+
     let mut threads = Vec::new();
     for stream in listener.incoming() {
         let context = context.clone();
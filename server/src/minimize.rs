@@ -0,0 +1,140 @@
+//! Coverage-preserving corpus minimization
+
+use std::io;
+use std::time::Duration;
+use std::sync::Arc;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
+
+use falktp::CoverageRecord;
+use crate::{Context, Target};
+
+/// Directory in each target's directory the minimized corpus is written to
+const MINIMIZED_DIR: &str = "minimized";
+
+/// Time to wait between periodic minimizations
+const MINIMIZE_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Compute a minimal set of inputs of `target` which still hits all coverage
+/// which was attributed to inputs
+///
+/// This is a greedy set cover: we repeatedly pick the input which hits the
+/// most coverage not yet hit by the picked inputs, preferring smaller inputs
+/// when there is a tie. Inputs which have no attributed coverage (eg. inputs
+/// from before attribution was tracked) are always kept, as we do not know
+/// what they cover.
+///
+/// Returns the hashes of the inputs in the minimized set
+pub fn minimize(target: &Target) -> BTreeSet<u128> {
+    let inputs      = target.inputs.read().unwrap();
+    let attribution = target.attribution.read().unwrap();
+
+    let mut minimized = BTreeSet::new();
+
+    // Candidate inputs, as (coverage, size, hash)
+    let mut candidates: Vec<(&BTreeSet<CoverageRecord>, usize, u128)> =
+        Vec::new();
+    for input in inputs.iter() {
+        match attribution.get(&input.hash) {
+            Some(coverage) => {
+                candidates.push((coverage, input.input.len(), input.hash));
+            }
+            None => {
+                minimized.insert(input.hash);
+            }
+        }
+    }
+
+    // Lazy greedy set cover. The heap holds an upper bound on the amount of
+    // new coverage each candidate adds, which only ever decreases as more
+    // inputs are picked. Thus if a popped candidate's bound is still exact,
+    // it is the best pick.
+    let mut heap: BinaryHeap<(usize, Reverse<usize>, usize)> = candidates
+        .iter().enumerate()
+        .map(|(idx, (coverage, size, _))| {
+            (coverage.len(), Reverse(*size), idx)
+        }).collect();
+
+    let mut covered: BTreeSet<&CoverageRecord> = BTreeSet::new();
+    while let Some((bound, size, idx)) = heap.pop() {
+        // Nothing left adds coverage
+        if bound == 0 { break; }
+
+        // Compute the actual amount of new coverage from this input
+        let (coverage, _, hash) = candidates[idx];
+        let new = coverage.iter().filter(|x| !covered.contains(x)).count();
+
+        if new < bound {
+            // Bound was stale, re-queue with the updated bound
+            heap.push((new, size, idx));
+            continue;
+        }
+
+        // Pick this input
+        minimized.insert(hash);
+        covered.extend(coverage.iter());
+    }
+
+    minimized
+}
+
+/// Minimize the corpus of `target`, save the minimized set for pushing to new
+/// sessions, and write the minimized inputs to the target's `minimized`
+/// directory
+pub fn run_target(target: &Target) -> io::Result<()> {
+    let minimized = minimize(target);
+
+    // Rewrite the minimized corpus directory
+    let dir = target.dir.join(MINIMIZED_DIR);
+    std::fs::create_dir_all(&dir)?;
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+
+        // Remove inputs which are no longer part of the minimized set
+        let keep = path.file_name().and_then(|x| x.to_str())
+            .and_then(|x| u128::from_str_radix(x, 16).ok())
+            .map(|x| minimized.contains(&x))
+            .unwrap_or(false);
+        if !keep && path.is_file() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    {
+        let inputs = target.inputs.read().unwrap();
+        for input in inputs.iter().filter(|x| minimized.contains(&x.hash)) {
+            let path = dir.join(format!("{:032x}", input.hash));
+            if !path.exists() {
+                std::fs::write(path, &**input.input)?;
+            }
+        }
+    }
+
+    print!("Minimized corpus of {} from {} to {} inputs\n", target.name,
+           target.inputs.read().unwrap().len(), minimized.len());
+
+    *target.minimized.write().unwrap() = Some(minimized);
+    Ok(())
+}
+
+/// Minimize the corpus of every target
+pub fn run(context: &Context) -> io::Result<()> {
+    let targets: Vec<_> =
+        context.targets.read().unwrap().values().cloned().collect();
+    for target in targets {
+        run_target(&target)?;
+    }
+
+    Ok(())
+}
+
+/// Periodically minimize the corpora, forever
+pub fn periodic_minimize(context: Arc<Context>) {
+    loop {
+        if let Err(err) = run(&context) {
+            print!("Failed to minimize corpora: {}\n", err);
+        }
+
+        std::thread::sleep(MINIMIZE_DELAY);
+    }
+}
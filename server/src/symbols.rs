@@ -0,0 +1,117 @@
+//! Symbolization of module offsets into `module!symbol+0xoffset`
+//!
+//! Workers report the exports of each module, along with the CodeView record
+//! identifying the PDB of the module. If the local symbol cache has symbols
+//! for that PDB they are used on top of the exports.
+//!
+//! The symbol cache uses the layout of a symbol store, keyed by the PDB name
+//! and the GUID and age of the PDB, with one text file per PDB:
+//!
+//! ```text
+//! symbols/ntkrnlmp.pdb/3844DBB920174967BE7AA4A2C20430FA1/symbols.txt
+//! ```
+//!
+//! The file has one symbol per line as `<offset> <name>`, where the offset is
+//! the hex offset (RVA) of the symbol into the module, with `#` comments. It
+//! can be generated with any tool which dumps the public symbols of a PDB
+//! along with their RVAs.
+
+use std::path::{Path, PathBuf};
+use std::convert::TryInto;
+use std::collections::BTreeMap;
+
+use falktp::{ModuleRecord, CodeViewRecord};
+
+/// Directory containing the symbol cache
+const SYMBOLS_DIR: &str = "symbols";
+
+/// Symbols of a module, by their offset into the module
+#[derive(Default)]
+pub struct Symbols(BTreeMap<u64, String>);
+
+impl Symbols {
+    /// Get the symbols of a module from its exports, and from the symbol
+    /// cache if it has the PDB of the module
+    pub fn load(module: &ModuleRecord) -> Self {
+        let mut symbols = BTreeMap::new();
+        for export in module.exports.iter() {
+            symbols.insert(export.offset, export.name.to_string());
+        }
+
+        // Add the symbols from the cache, these take precedence over the
+        // exports as they are usually more descriptive
+        let path = module.codeview.as_ref().and_then(cache_path);
+        if let Some(contents) =
+                path.as_ref().and_then(|x| std::fs::read_to_string(x).ok()) {
+            let mut loaded = 0;
+            for line in contents.lines() {
+                if let Some((offset, name)) = parse_symbol(line) {
+                    symbols.insert(offset, name.to_string());
+                    loaded += 1;
+                }
+            }
+
+            print!("Loaded {} symbols for {} from {:?}\n",
+                   loaded, module.name, path.unwrap());
+        }
+
+        Symbols(symbols)
+    }
+
+    /// Resolve `offset` into the closest symbol at or before it, as the
+    /// symbol name and the offset into the symbol
+    pub fn resolve(&self, offset: u64) -> Option<(&str, u64)> {
+        self.0.range(..=offset).next_back()
+            .map(|(start, name)| (name.as_str(), offset - start))
+    }
+}
+
+/// Parse a `<offset> <name>` line of a symbol cache file
+fn parse_symbol(line: &str) -> Option<(u64, &str)> {
+    let line = line.split('#').next().unwrap();
+    let mut fields = line.split_whitespace();
+
+    let offset = fields.next()?;
+    let offset = offset.trim_start_matches("0x");
+    let offset = u64::from_str_radix(offset, 16).ok()?;
+    let name   = fields.next()?;
+    if fields.next().is_some() { return None; }
+
+    Some((offset, name))
+}
+
+/// Get the path of the symbol cache file for the PDB in `codeview`
+fn cache_path(codeview: &CodeViewRecord) -> Option<PathBuf> {
+    // The PDB name may be a full path on the machine the module was built on
+    let pdb = codeview.pdb.rsplit(|x| x == '\\' || x == '/').next()?;
+
+    // Make sure the name cannot escape the symbol cache
+    if pdb.is_empty() || pdb.starts_with('.') ||
+            !pdb.chars().all(|x| {
+                x.is_ascii_alphanumeric() || "._-".contains(x)
+            }) {
+        return None;
+    }
+
+    // The key is the GUID in its canonical form without dashes, followed by
+    // the age
+    let guid  = &codeview.guid;
+    let data1 = u32::from_le_bytes(guid[0..4].try_into().unwrap());
+    let data2 = u16::from_le_bytes(guid[4..6].try_into().unwrap());
+    let data3 = u16::from_le_bytes(guid[6..8].try_into().unwrap());
+    let mut key = format!("{:08X}{:04X}{:04X}", data1, data2, data3);
+    for byte in &guid[8..] {
+        key += &format!("{:02X}", byte);
+    }
+    key += &format!("{:X}", codeview.age);
+
+    Some(Path::new(SYMBOLS_DIR).join(pdb).join(key).join("symbols.txt"))
+}
+
+/// Symbolize `offset` into `module` as `module!symbol+0xoffset`, using the
+/// symbols of each module in `symbols`
+pub fn symbolize(symbols: &BTreeMap<String, Symbols>, module: &str,
+                 offset: u64) -> Option<String> {
+    let (name, offset) = symbols.get(module)?.resolve(offset)?;
+    Some(format!("{}!{}+{:#x}", module, name, offset))
+}
@@ -0,0 +1,442 @@
+//! Loading of snapshots from the ELF cores written by `dump-guest-memory` in
+//! a stock QEMU
+//!
+//! Without the patches in `SNAPSHOTTING.md`, QEMU only saves the general
+//! purpose registers, segments, and control registers in its CPU state note.
+//! The registers it does not save can be provided by a sidecar file, which
+//! has one register per line as `<name> <value>` in the format of the
+//! `sidecar` crate. For example:
+//!
+//! ```text
+//! # MSRs from `rdmsr` in the guest
+//! efer  0xd01
+//! lstar 0xfffff80012345678
+//! ```
+//!
+//! Registers which are neither in the ELF core nor in the sidecar file are
+//! inferred, and reported in `QemuElf::inferred`.
+
+use core::cmp::min;
+use alloc::vec::Vec;
+
+use crate::{Error, FalkDump, PhysRange, Reader, Registers, REGISTERS_SIZE};
+use crate::validate_phys_ranges;
+
+/// ELF core file type
+const ET_CORE: u16 = 4;
+
+/// 32-bit x86 ELF machine type
+const EM_386: u16 = 3;
+
+/// 64-bit x86 ELF machine type
+const EM_X86_64: u16 = 62;
+
+/// Loadable segment program header type
+const PT_LOAD: u32 = 1;
+
+/// Note segment program header type
+const PT_NOTE: u32 = 4;
+
+/// Size of the CPU state saved by a stock QEMU, up to and including CR4.
+/// Newer versions of QEMU also save the kernel GS base.
+const QEMU_STATE_MIN_SIZE: usize = 8 + 18 * 8 + 10 * 24 + 5 * 8;
+
+/// Registers which a stock QEMU may not save, as (name, offset in the
+/// register state)
+const OPTIONAL_REGISTERS: &[(&str, usize)] = &[
+    ("kernel_gs_base", 432),
+    ("cr8",            440),
+    ("cstar",          448),
+    ("lstar",          456),
+    ("fmask",          464),
+    ("star",           472),
+    ("sysenter_cs",    480),
+    ("sysenter_esp",   488),
+    ("sysenter_eip",   496),
+    ("efer",           504),
+    ("dr0",            512),
+    ("dr1",            520),
+    ("dr2",            528),
+    ("dr3",            536),
+    ("dr6",            560),
+    ("dr7",            568),
+];
+
+/// A snapshot loaded from a QEMU ELF core
+pub struct QemuElf<'a> {
+    /// The snapshot, with the physical ranges referencing the ELF core
+    pub dump: FalkDump<'a>,
+
+    /// Names of the registers which were not in the ELF core or the sidecar
+    /// file, and were inferred
+    pub inferred: Vec<&'static str>,
+}
+
+/// Get a mutable reference to the register `name` which may be set from a
+/// sidecar file
+fn register_mut<'a>(regs: &'a mut Registers, name: &str)
+        -> Option<&'a mut u64> {
+    Some(match name {
+        "kernel_gs_base" => &mut regs.kernel_gs_base,
+        "cr8"            => &mut regs.cr8,
+        "cstar"          => &mut regs.cstar,
+        "lstar"          => &mut regs.lstar,
+        "fmask"          => &mut regs.fmask,
+        "star"           => &mut regs.star,
+        "sysenter_cs"    => &mut regs.sysenter_cs,
+        "sysenter_esp"   => &mut regs.sysenter_esp,
+        "sysenter_eip"   => &mut regs.sysenter_eip,
+        "efer"           => &mut regs.efer,
+        "dr0"            => &mut regs.dr[0],
+        "dr1"            => &mut regs.dr[1],
+        "dr2"            => &mut regs.dr[2],
+        "dr3"            => &mut regs.dr[3],
+        "dr6"            => &mut regs.dr[6],
+        "dr7"            => &mut regs.dr[7],
+        _ => return None,
+    })
+}
+
+/// Find the CPU state note of the first CPU in the note segment `notes`
+fn find_qemu_note(notes: &[u8]) -> Result<Option<&[u8]>, Error> {
+    let mut reader = Reader(notes);
+    while !reader.0.is_empty() {
+        let name_size = reader.u32()? as usize;
+        let desc_size = reader.u32()? as usize;
+        let _typ      = reader.u32()?;
+
+        // Names and descriptors are padded to 4 bytes
+        let name = reader.bytes(name_size)?;
+        reader.bytes(min((4 - name_size % 4) % 4, reader.0.len()))?;
+        let desc = reader.bytes(desc_size)?;
+        reader.bytes(min((4 - desc_size % 4) % 4, reader.0.len()))?;
+
+        if name == b"QEMU\0" {
+            return Ok(Some(desc));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Load a snapshot from the contents of a QEMU ELF core, with the registers
+/// QEMU does not save optionally provided by the contents of a `sidecar`
+/// file
+pub fn parse<'a>(contents: &'a [u8], sidecar: Option<&str>)
+        -> Result<QemuElf<'a>, Error> {
+    // Check the ELF identification, we only support little endian
+    let ident = contents.get(..16).ok_or(Error::Truncated)?;
+    if &ident[..4] != b"\x7fELF" || ident[5] != 1 {
+        return Err(Error::InvalidElf);
+    }
+    let bits64 = match ident[4] {
+        1 => false,
+        2 => true,
+        _ => return Err(Error::InvalidElf),
+    };
+
+    // Parse the ELF header
+    let mut reader = Reader(&contents[16..]);
+    let typ      = reader.u16()?;
+    let machine  = reader.u16()?;
+    let _version = reader.u32()?;
+    let (phoff, phentsize) = if bits64 {
+        let _entry = reader.u64()?;
+        let phoff  = reader.u64()?;
+        let _shoff = reader.u64()?;
+        (phoff, 56)
+    } else {
+        let _entry = reader.u32()?;
+        let phoff  = reader.u32()? as u64;
+        let _shoff = reader.u32()?;
+        (phoff, 32)
+    };
+    let _flags  = reader.u32()?;
+    let _ehsize = reader.u16()?;
+    if typ != ET_CORE || (machine != EM_X86_64 && machine != EM_386) ||
+            reader.u16()? != phentsize {
+        return Err(Error::InvalidElf);
+    }
+    let phnum = reader.u16()?;
+
+    // Parse the program headers
+    let mut phys_ranges = Vec::new();
+    let mut qemu_note   = None;
+    for ii in 0..phnum as u64 {
+        let offset = phoff.checked_add(ii * phentsize as u64)
+            .ok_or(Error::InvalidElf)?;
+        let phdr = contents.get(offset as usize..).ok_or(Error::Truncated)?;
+        let mut reader = Reader(phdr);
+
+        let (typ, offset, paddr, filesz) = if bits64 {
+            let typ     = reader.u32()?;
+            let _flags  = reader.u32()?;
+            let offset  = reader.u64()?;
+            let _vaddr  = reader.u64()?;
+            let paddr   = reader.u64()?;
+            let filesz  = reader.u64()?;
+            (typ, offset, paddr, filesz)
+        } else {
+            let typ     = reader.u32()?;
+            let offset  = reader.u32()? as u64;
+            let _vaddr  = reader.u32()?;
+            let paddr   = reader.u32()? as u64;
+            let filesz  = reader.u32()? as u64;
+            (typ, offset, paddr, filesz)
+        };
+
+        match typ {
+            PT_LOAD if filesz > 0 => {
+                let end = paddr.checked_add(filesz - 1)
+                    .ok_or(Error::InvalidPhysRange {
+                        start: paddr,
+                        end:   paddr.wrapping_add(filesz - 1),
+                    })?;
+
+                phys_ranges.push(PhysRange {
+                    start: paddr,
+                    end,
+                    offset,
+                });
+            }
+            PT_NOTE if qemu_note.is_none() => {
+                let notes = offset.checked_add(filesz)
+                    .and_then(|end| contents.get(offset as usize..
+                                                 end as usize))
+                    .ok_or(Error::Truncated)?;
+                qemu_note = find_qemu_note(notes)?;
+            }
+            _ => {}
+        }
+    }
+    validate_phys_ranges(&mut phys_ranges, contents.len())?;
+
+    // Parse the CPU state, registers beyond the end of the note are left
+    // zero
+    let note = qemu_note.ok_or(Error::MissingQemuNote)?;
+    if note.len() < QEMU_STATE_MIN_SIZE {
+        return Err(Error::InvalidRegistersSize(note.len() as u64));
+    }
+    let saved = min(note.len(), REGISTERS_SIZE);
+    let mut raw = [0u8; REGISTERS_SIZE];
+    raw[..saved].copy_from_slice(&note[..saved]);
+    let mut regs = Registers::parse(&raw)?;
+
+    // Determine which registers were not saved
+    let mut inferred: Vec<&'static str> = OPTIONAL_REGISTERS.iter()
+        .filter(|&&(_, offset)| offset + 8 > saved)
+        .map(|&(name, _)| name).collect();
+    if saved < REGISTERS_SIZE {
+        inferred.push("fxsave");
+    }
+
+    // Load the registers from the sidecar file
+    for (line, mut fields) in sidecar::lines(sidecar.unwrap_or("")) {
+        let name = fields.next().unwrap();
+        let val  = fields.next().and_then(sidecar::parse_num);
+        let reg  = register_mut(&mut regs, name);
+        match (reg, val, fields.next()) {
+            (Some(reg), Some(val), None) => *reg = val,
+            _ => return Err(Error::InvalidSidecar { line }),
+        }
+        inferred.retain(|&x| x != name);
+    }
+
+    // Infer the remaining registers
+    for &name in &inferred {
+        match name {
+            "efer" => {
+                // Assume a long mode guest sets SCE and NXE, and otherwise
+                // EFER is clear
+                let long_mode = regs.cs.flags & (1 << 21) != 0 || (bits64 &&
+                    regs.cr0 & (1 << 31) != 0 && regs.cr4 & (1 << 5) != 0);
+                regs.efer = if long_mode { 0xd01 } else { 0 };
+            }
+            "fxsave" => {
+                // Default FCW, MXCSR, and MXCSR mask after `fninit`
+                regs.fxsave = [0; 512];
+                regs.fxsave[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+                regs.fxsave[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+                regs.fxsave[28..32].copy_from_slice(&0xffffu32.to_le_bytes());
+            }
+            _ => {
+                // All other registers are zero, as they were not saved
+            }
+        }
+    }
+
+    Ok(QemuElf {
+        dump: FalkDump {
+            version: 0,
+            regs,
+            phys_ranges,
+            contents,
+        },
+        inferred,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    extern crate std;
+    use std::vec;
+
+    /// Append a note with `name` and `desc` to `out`
+    fn note(out: &mut Vec<u8>, name: &[u8], typ: u32, desc: &[u8]) {
+        out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        out.extend_from_slice(&typ.to_le_bytes());
+        out.extend_from_slice(name);
+        out.resize((out.len() + 3) & !3, 0);
+        out.extend_from_slice(desc);
+        out.resize((out.len() + 3) & !3, 0);
+    }
+
+    /// Create a 64-bit ELF core in the layout of `dump-guest-memory`, with
+    /// the CPU state note truncated to `state_size` bytes, and `memory` as
+    /// (physical address, contents)
+    fn qemu_elf(state_size: usize, memory: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut regs = Registers {
+            qemu_version:   1,
+            qemu_size:      state_size as u32,
+            rip:            0xfffff80012345678,
+            cr0:            0x80050033,
+            cr4:            0x6f8,
+            kernel_gs_base: 0x1337,
+            lstar:          0x4141,
+            ..Default::default()
+        };
+        regs.cs.flags = 0x209b00;
+        let mut state = Vec::new();
+        regs.write(&mut state);
+        state.truncate(state_size);
+
+        // Notes, the `CORE` note contents do not matter
+        let mut notes = Vec::new();
+        note(&mut notes, b"CORE\0", 1, &[0u8; 336]);
+        note(&mut notes, b"QEMU\0", 0, &state);
+
+        // ELF header
+        let phnum = 1 + memory.len();
+        let mut out = Vec::new();
+        out.extend_from_slice(b"\x7fELF\x02\x01\x01");
+        out.resize(16, 0);
+        out.extend_from_slice(&4u16.to_le_bytes());
+        out.extend_from_slice(&62u16.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&64u64.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&64u16.to_le_bytes());
+        out.extend_from_slice(&56u16.to_le_bytes());
+        out.extend_from_slice(&(phnum as u16).to_le_bytes());
+        out.extend_from_slice(&[0u8; 6]);
+
+        // Program headers, QEMU does not page align the memory
+        let phdr = |out: &mut Vec<u8>, typ: u32, offset: usize, paddr: u64,
+                    size: usize| {
+            out.extend_from_slice(&typ.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&(offset as u64).to_le_bytes());
+            out.extend_from_slice(&0u64.to_le_bytes());
+            out.extend_from_slice(&paddr.to_le_bytes());
+            out.extend_from_slice(&(size as u64).to_le_bytes());
+            out.extend_from_slice(&(size as u64).to_le_bytes());
+            out.extend_from_slice(&0u64.to_le_bytes());
+        };
+        let mut offset = 64 + phnum * 56;
+        phdr(&mut out, 4, offset, 0, notes.len());
+        offset += notes.len();
+        for &(paddr, contents) in memory {
+            phdr(&mut out, 1, offset, paddr, contents.len());
+            offset += contents.len();
+        }
+
+        out.extend_from_slice(&notes);
+        for &(_, contents) in memory {
+            out.extend_from_slice(contents);
+        }
+        out
+    }
+
+    #[test]
+    fn stock_qemu() {
+        let low  = vec![0x41u8; 0x2000];
+        let high = vec![0x42u8; 0x1000];
+        let core = qemu_elf(0x1b8, &[(0, &low), (0x100000, &high)]);
+
+        let elf = elf::parse(&core, None).unwrap();
+        assert!(elf.dump.version == 0);
+        assert!(elf.dump.regs.rip == 0xfffff80012345678);
+        assert!(elf.dump.regs.kernel_gs_base == 0x1337);
+        assert!(elf.dump.phys_ranges.len() == 2);
+        assert!(elf.dump.phys_page(0x1800).unwrap() == &low[..0x800]);
+        assert!(elf.dump.phys_page(0x100000).unwrap() == &high[..]);
+        assert!(elf.dump.phys_page(0x2000).is_none());
+
+        // Everything after the kernel GS base was inferred
+        assert!(!elf.inferred.contains(&"kernel_gs_base"));
+        assert!(elf.inferred.contains(&"lstar"));
+        assert!(elf.inferred.contains(&"fxsave"));
+        assert!(elf.dump.regs.lstar == 0);
+        assert!(elf.dump.regs.efer == 0xd01);
+        assert!(elf.dump.regs.fxsave[24..28] == 0x1f80u32.to_le_bytes());
+    }
+
+    #[test]
+    fn sidecar() {
+        let core = qemu_elf(0x1b0, &[(0, &[0u8; 0x1000])]);
+        let elf = elf::parse(&core, Some("# From the guest\n\
+                                          efer 0x501\n\
+                                          \n\
+                                          lstar   1234 # comment\n"))
+            .unwrap();
+        assert!(elf.dump.regs.efer == 0x501);
+        assert!(elf.dump.regs.lstar == 1234);
+        assert!(elf.dump.regs.kernel_gs_base == 0);
+        assert!(elf.inferred.contains(&"kernel_gs_base"));
+        assert!(!elf.inferred.contains(&"efer"));
+        assert!(!elf.inferred.contains(&"lstar"));
+
+        assert!(elf::parse(&core, Some("efer")).err() ==
+                Some(Error::InvalidSidecar { line: 1 }));
+        assert!(elf::parse(&core, Some("\nrip 0x1234")).err() ==
+                Some(Error::InvalidSidecar { line: 2 }));
+    }
+
+    #[test]
+    fn patched_qemu() {
+        // The full register state leaves nothing to infer
+        let core = qemu_elf(REGISTERS_SIZE, &[(0, &[0u8; 0x1000])]);
+        let elf = elf::parse(&core, None).unwrap();
+        assert!(elf.inferred.is_empty());
+        assert!(elf.dump.regs.lstar == 0x4141);
+    }
+
+    #[test]
+    fn errors() {
+        let core = qemu_elf(0x1b8, &[(0, &[0u8; 0x1000])]);
+
+        // Every truncation must be an error, not a panic
+        for len in 0..core.len() {
+            assert!(elf::parse(&core[..len], None).is_err());
+        }
+
+        let mut bad = core.clone();
+        bad[18] = 40;
+        assert!(elf::parse(&bad, None).err() == Some(Error::InvalidElf));
+
+        // A CPU state note which is too small
+        let core = qemu_elf(0x100, &[(0, &[0u8; 0x1000])]);
+        assert!(elf::parse(&core, None).err() ==
+                Some(Error::InvalidRegistersSize(0x100)));
+
+        // Memory which is not page sized
+        let core = qemu_elf(0x1b8, &[(0, &[0u8; 0x800])]);
+        assert!(elf::parse(&core, None).err() ==
+                Some(Error::InvalidPhysRange { start: 0, end: 0x7ff }));
+    }
+}
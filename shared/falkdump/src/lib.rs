@@ -0,0 +1,767 @@
+//! Parsing and writing of falkdumps, the snapshot format of a guest's
+//! registers and physical memory, as produced by QEMU's `dump-guest-memory`
+//! with the patches in `SNAPSHOTTING.md`
+//!
+//! The format is:
+//!
+//! ```text
+//! b"FALKDUMP"
+//! u32 version, currently `VERSION`
+//! u32 reserved, zero
+//! u64 size of the register state, `REGISTERS_SIZE`
+//! Register state, see `Registers`
+//! u64 number of physical ranges
+//! For each physical range:
+//!     u64 start physical address, page aligned
+//!     u64 end physical address (inclusive), the last byte of a page
+//!     u64 offset in the file of the contents of the range
+//! Contents of the physical ranges
+//! ```
+//!
+//! Version 1 falkdumps predate the version field and have the size of the
+//! register state directly after the signature. As the register state is far
+//! larger than any version number, they are still recognized and parsed.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod elf;
+
+use core::convert::TryInto;
+use alloc::vec::Vec;
+
+/// Signature at the start of every falkdump
+pub const SIGNATURE: &[u8; 8] = b"FALKDUMP";
+
+/// Version of the falkdump format which is written by `write()`
+pub const VERSION: u32 = 2;
+
+/// Size of the register state in bytes, including the FXSAVE area
+pub const REGISTERS_SIZE: usize = 8 + 18 * 8 + 10 * 24 + 5 * 8 + 10 * 8 +
+    8 * 8 + 512;
+
+/// Errors from parsing or writing a falkdump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The falkdump ended before all of its fields or contents
+    Truncated,
+
+    /// The falkdump does not start with `SIGNATURE`
+    InvalidSignature,
+
+    /// The falkdump has a version we do not know how to parse
+    UnsupportedVersion(u32),
+
+    /// The register state is not `REGISTERS_SIZE` bytes
+    InvalidRegistersSize(u64),
+
+    /// A physical range is not page aligned, or is empty
+    InvalidPhysRange { start: u64, end: u64 },
+
+    /// Physical ranges overlap each other
+    OverlappingPhysRanges { start: u64, end: u64 },
+
+    /// The file is not a little endian x86 ELF core
+    InvalidElf,
+
+    /// The ELF core has no QEMU CPU state note
+    MissingQemuNote,
+
+    /// A line of a register sidecar file could not be parsed
+    InvalidSidecar { line: usize },
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "Truncated falkdump"),
+            Error::InvalidSignature =>
+                write!(f, "Invalid signature for falkdump"),
+            Error::UnsupportedVersion(version) =>
+                write!(f, "Unsupported falkdump version {}", version),
+            Error::InvalidRegistersSize(size) =>
+                write!(f, "Register state is {} bytes, expected {}",
+                       size, REGISTERS_SIZE),
+            Error::InvalidPhysRange { start, end } =>
+                write!(f, "Invalid physical range {:#x}-{:#x}", start, end),
+            Error::OverlappingPhysRanges { start, end } =>
+                write!(f, "Overlapping physical range {:#x}-{:#x}",
+                       start, end),
+            Error::InvalidElf => write!(f, "Invalid x86 ELF core"),
+            Error::MissingQemuNote =>
+                write!(f, "ELF core has no QEMU CPU state note"),
+            Error::InvalidSidecar { line } =>
+                write!(f, "Invalid register sidecar line {}", line),
+        }
+    }
+}
+
+/// A segment register as saved by QEMU
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Segment {
+    /// Segment selector
+    pub selector: u32,
+
+    /// Segment limit
+    pub limit: u32,
+
+    /// Descriptor flags, these are the high 32-bits of the descriptor
+    pub flags: u32,
+
+    /// Segment base
+    pub base: u64,
+}
+
+impl Segment {
+    /// Get the access rights of the segment in the VT-x format
+    pub fn access_rights(&self) -> u64 {
+        (self.flags as u64) >> 8
+    }
+}
+
+/// A segment register in the form it is loaded into VT-x
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmxSegment {
+    pub selector:      u64,
+    pub base:          u64,
+    pub limit:         u64,
+    pub access_rights: u64,
+}
+
+/// The segment registers, in the form they are loaded into VT-x
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmxSegments {
+    pub es:   VmxSegment,
+    pub cs:   VmxSegment,
+    pub ss:   VmxSegment,
+    pub ds:   VmxSegment,
+    pub fs:   VmxSegment,
+    pub gs:   VmxSegment,
+    pub ldtr: VmxSegment,
+    pub tr:   VmxSegment,
+}
+
+/// Register state of a falkdump. This is QEMU's `QEMUCPUState`, extended
+/// with the fields added by the patches in `SNAPSHOTTING.md`
+#[derive(Clone, Copy)]
+pub struct Registers {
+    /// Version of the `QEMUCPUState`
+    pub qemu_version: u32,
+
+    /// Size of the `QEMUCPUState`, as reported by QEMU
+    pub qemu_size: u32,
+
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub r8:  u64,
+    pub r9:  u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+
+    pub cs:   Segment,
+    pub ds:   Segment,
+    pub es:   Segment,
+    pub fs:   Segment,
+    pub gs:   Segment,
+    pub ss:   Segment,
+    pub ldtr: Segment,
+    pub tr:   Segment,
+    pub gdtr: Segment,
+    pub idtr: Segment,
+
+    pub cr0: u64,
+    pub cr1: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+
+    pub kernel_gs_base: u64,
+    pub cr8:            u64,
+    pub cstar:          u64,
+    pub lstar:          u64,
+    pub fmask:          u64,
+    pub star:           u64,
+    pub sysenter_cs:    u64,
+    pub sysenter_esp:   u64,
+    pub sysenter_eip:   u64,
+    pub efer:           u64,
+
+    /// Debug registers DR0 through DR7
+    pub dr: [u64; 8],
+
+    /// Raw FXSAVE area
+    pub fxsave: [u8; 512],
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::parse(&[0u8; REGISTERS_SIZE]).unwrap()
+    }
+}
+
+/// Reads little endian values from a byte slice
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// Read `size` bytes
+    pub(crate) fn bytes(&mut self, size: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < size {
+            return Err(Error::Truncated);
+        }
+
+        let (ret, rest) = self.0.split_at(size);
+        self.0 = rest;
+        Ok(ret)
+    }
+
+    /// Read a `u16`
+    pub(crate) fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    /// Read a `u32`
+    pub(crate) fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Read a `u64`
+    pub(crate) fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Read a `Segment`
+    fn segment(&mut self) -> Result<Segment, Error> {
+        let selector = self.u32()?;
+        let limit    = self.u32()?;
+        let flags    = self.u32()?;
+        let _pad     = self.u32()?;
+        let base     = self.u64()?;
+
+        Ok(Segment {
+            selector,
+            limit,
+            flags,
+            base,
+        })
+    }
+}
+
+/// Append a `Segment` to `out`
+fn write_segment(out: &mut Vec<u8>, segment: &Segment) {
+    out.extend_from_slice(&segment.selector.to_le_bytes());
+    out.extend_from_slice(&segment.limit.to_le_bytes());
+    out.extend_from_slice(&segment.flags.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&segment.base.to_le_bytes());
+}
+
+impl Registers {
+    /// Parse the register state from `bytes`, which must be exactly
+    /// `REGISTERS_SIZE` bytes
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != REGISTERS_SIZE {
+            return Err(Error::InvalidRegistersSize(bytes.len() as u64));
+        }
+
+        let mut reader = Reader(bytes);
+        let mut ret = Registers {
+            qemu_version: reader.u32()?,
+            qemu_size:    reader.u32()?,
+
+            rax:    reader.u64()?,
+            rbx:    reader.u64()?,
+            rcx:    reader.u64()?,
+            rdx:    reader.u64()?,
+            rsi:    reader.u64()?,
+            rdi:    reader.u64()?,
+            rsp:    reader.u64()?,
+            rbp:    reader.u64()?,
+            r8:     reader.u64()?,
+            r9:     reader.u64()?,
+            r10:    reader.u64()?,
+            r11:    reader.u64()?,
+            r12:    reader.u64()?,
+            r13:    reader.u64()?,
+            r14:    reader.u64()?,
+            r15:    reader.u64()?,
+            rip:    reader.u64()?,
+            rflags: reader.u64()?,
+
+            cs:   reader.segment()?,
+            ds:   reader.segment()?,
+            es:   reader.segment()?,
+            fs:   reader.segment()?,
+            gs:   reader.segment()?,
+            ss:   reader.segment()?,
+            ldtr: reader.segment()?,
+            tr:   reader.segment()?,
+            gdtr: reader.segment()?,
+            idtr: reader.segment()?,
+
+            cr0: reader.u64()?,
+            cr1: reader.u64()?,
+            cr2: reader.u64()?,
+            cr3: reader.u64()?,
+            cr4: reader.u64()?,
+
+            kernel_gs_base: reader.u64()?,
+            cr8:            reader.u64()?,
+            cstar:          reader.u64()?,
+            lstar:          reader.u64()?,
+            fmask:          reader.u64()?,
+            star:           reader.u64()?,
+            sysenter_cs:    reader.u64()?,
+            sysenter_esp:   reader.u64()?,
+            sysenter_eip:   reader.u64()?,
+            efer:           reader.u64()?,
+
+            dr:     [0; 8],
+            fxsave: [0; 512],
+        };
+
+        for dr in ret.dr.iter_mut() {
+            *dr = reader.u64()?;
+        }
+        ret.fxsave.copy_from_slice(reader.bytes(512)?);
+
+        Ok(ret)
+    }
+
+    /// Append the register state to `out`
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.qemu_version.to_le_bytes());
+        out.extend_from_slice(&self.qemu_size.to_le_bytes());
+
+        for val in &[self.rax, self.rbx, self.rcx, self.rdx, self.rsi,
+                     self.rdi, self.rsp, self.rbp, self.r8, self.r9,
+                     self.r10, self.r11, self.r12, self.r13, self.r14,
+                     self.r15, self.rip, self.rflags] {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+
+        for segment in &[self.cs, self.ds, self.es, self.fs, self.gs,
+                         self.ss, self.ldtr, self.tr, self.gdtr, self.idtr] {
+            write_segment(out, segment);
+        }
+
+        for val in &[self.cr0, self.cr1, self.cr2, self.cr3, self.cr4,
+                     self.kernel_gs_base, self.cr8, self.cstar, self.lstar,
+                     self.fmask, self.star, self.sysenter_cs,
+                     self.sysenter_esp, self.sysenter_eip, self.efer] {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+
+        for val in &self.dr {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+        out.extend_from_slice(&self.fxsave);
+    }
+
+    /// Get the segment registers in the form they should be loaded into
+    /// VT-x. QEMU and VT-x have slightly different expectations for the
+    /// limits and access rights, this performs the needed fixups.
+    pub fn vmx_segments(&self) -> VmxSegments {
+        // Long mode, QEMU gives some non-zero limits, zero them out
+        let long_mode = self.efer & (1 << 8) != 0;
+
+        let convert = |segment: &Segment, zero_limit: bool| {
+            let limit = if zero_limit && long_mode {
+                0
+            } else {
+                segment.limit as u64
+            };
+
+            // Mark any non-present segment as inactive
+            let mut access_rights = segment.access_rights();
+            if access_rights & (1 << 7) == 0 {
+                access_rights = 0x10000;
+            }
+
+            // If any bit in the bottom 12 bits of the limit is zero, then G
+            // must be zero
+            if limit & 0xfff != 0xfff {
+                access_rights &= !(1 << 15);
+            }
+
+            VmxSegment {
+                selector: segment.selector as u64,
+                base:     segment.base,
+                limit,
+                access_rights,
+            }
+        };
+
+        VmxSegments {
+            es:   convert(&self.es, true),
+            cs:   convert(&self.cs, true),
+            ss:   convert(&self.ss, true),
+            ds:   convert(&self.ds, true),
+            fs:   convert(&self.fs, true),
+            gs:   convert(&self.gs, true),
+            ldtr: convert(&self.ldtr, false),
+            tr:   convert(&self.tr, false),
+        }
+    }
+}
+
+/// A range of physical memory in a falkdump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysRange {
+    /// Starting physical address, page aligned
+    pub start: u64,
+
+    /// Ending physical address (inclusive), the last byte of a page
+    pub end: u64,
+
+    /// Offset in the falkdump of the contents of the range. This is page
+    /// aligned in falkdumps, but may not be in QEMU ELF cores.
+    pub offset: u64,
+}
+
+impl PhysRange {
+    /// Size of the range in bytes
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Validate that `phys_ranges` are page aligned, within a file of `len`
+/// bytes, and do not overlap. Sorts the ranges by starting address.
+pub(crate) fn validate_phys_ranges(phys_ranges: &mut [PhysRange], len: usize)
+        -> Result<(), Error> {
+    for range in phys_ranges.iter() {
+        if range.end <= range.start || range.start & 0xfff != 0 ||
+                range.end & 0xfff != 0xfff {
+            return Err(Error::InvalidPhysRange {
+                start: range.start,
+                end:   range.end,
+            });
+        }
+
+        // Make sure the contents are within the file
+        if range.offset.checked_add(range.size())
+                .map(|x| x > len as u64).unwrap_or(true) {
+            return Err(Error::Truncated);
+        }
+    }
+
+    // Make sure the ranges do not overlap
+    phys_ranges.sort_by_key(|x| x.start);
+    for pair in phys_ranges.windows(2) {
+        if pair[1].start <= pair[0].end {
+            return Err(Error::OverlappingPhysRanges {
+                start: pair[1].start,
+                end:   pair[1].end,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// A parsed falkdump
+pub struct FalkDump<'a> {
+    /// Version of the falkdump format, 0 if the snapshot was loaded from a
+    /// QEMU ELF core
+    pub version: u32,
+
+    /// Register state
+    pub regs: Registers,
+
+    /// Physical memory ranges, sorted by starting address
+    pub phys_ranges: Vec<PhysRange>,
+
+    /// Raw contents of the falkdump
+    pub contents: &'a [u8],
+}
+
+impl<'a> FalkDump<'a> {
+    /// Parse a falkdump from its raw contents
+    ///
+    /// Only the header is validated against the length of `contents`, the
+    /// contents of the physical ranges are not touched. This allows the
+    /// falkdump to be lazily mapped in.
+    pub fn parse(contents: &'a [u8]) -> Result<Self, Error> {
+        let mut reader = Reader(contents);
+
+        // Check the signature
+        if reader.bytes(SIGNATURE.len())? != SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+
+        // Get the version and the size of the register state. Version 1
+        // has no version field, instead the low 32 bits of the register
+        // state size are where the version would be.
+        let version = reader.u32()?;
+        let regs_size = match version {
+            2 => {
+                let _reserved = reader.u32()?;
+                reader.u64()?
+            }
+            _ if version as usize == REGISTERS_SIZE => {
+                ((reader.u32()? as u64) << 32) | version as u64
+            }
+            _ => return Err(Error::UnsupportedVersion(version)),
+        };
+        let version = if version == 2 { 2 } else { 1 };
+
+        // Parse the register state
+        if regs_size != REGISTERS_SIZE as u64 {
+            return Err(Error::InvalidRegistersSize(regs_size));
+        }
+        let regs = Registers::parse(reader.bytes(REGISTERS_SIZE)?)?;
+
+        // Parse the physical ranges
+        let count = reader.u64()?;
+        if count > (reader.0.len() / 24) as u64 {
+            return Err(Error::Truncated);
+        }
+
+        let mut phys_ranges = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            phys_ranges.push(PhysRange {
+                start:  reader.u64()?,
+                end:    reader.u64()?,
+                offset: reader.u64()?,
+            });
+        }
+        validate_phys_ranges(&mut phys_ranges, contents.len())?;
+
+        Ok(FalkDump {
+            version,
+            regs,
+            phys_ranges,
+            contents,
+        })
+    }
+
+    /// Get the contents of physical memory from `paddr` to the end of the
+    /// page containing it, if the page is present in the falkdump
+    pub fn phys_page(&self, paddr: u64) -> Option<&'a [u8]> {
+        // Find the last range starting at or before the address
+        let idx = match self.phys_ranges.binary_search_by_key(&paddr,
+                                                              |x| x.start) {
+            Ok(idx)  => idx,
+            Err(idx) => idx.checked_sub(1)?,
+        };
+
+        let range = &self.phys_ranges[idx];
+        if paddr > range.end { return None; }
+
+        let offset = (range.offset + (paddr - range.start)) as usize;
+        let size   = 4096 - (paddr & 0xfff) as usize;
+        Some(&self.contents[offset..offset + size])
+    }
+}
+
+/// Create a falkdump from the register state `regs` and the physical memory
+/// `memory`, given as (starting physical address, contents) of each range
+///
+/// Ranges must start on a page boundary, be a non-zero multiple of the page
+/// size, and may not overlap. The contents of each range are page aligned in
+/// the falkdump, such that the falkdump can be mapped in page by page.
+pub fn write(regs: &Registers, memory: &[(u64, &[u8])])
+        -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+
+    out.extend_from_slice(SIGNATURE);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(REGISTERS_SIZE as u64).to_le_bytes());
+    regs.write(&mut out);
+
+    // Compute where the contents of the ranges go, page aligned after the
+    // range table
+    let table_end = out.len() + 8 + memory.len() * 24;
+    let mut offset = (table_end + 0xfff) & !0xfff;
+
+    out.extend_from_slice(&(memory.len() as u64).to_le_bytes());
+    let mut ranges = Vec::new();
+    for &(start, contents) in memory {
+        let size = contents.len() as u64;
+        let end  = start.checked_add(size).and_then(|x| x.checked_sub(1));
+        let end  = match end {
+            Some(end) if size & 0xfff == 0 && start & 0xfff == 0 => end,
+            _ => return Err(Error::InvalidPhysRange {
+                start,
+                end: start.wrapping_add(size).wrapping_sub(1),
+            }),
+        };
+
+        out.extend_from_slice(&start.to_le_bytes());
+        out.extend_from_slice(&end.to_le_bytes());
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+        ranges.push((start, end));
+        offset += contents.len();
+    }
+
+    // Make sure the ranges do not overlap
+    ranges.sort();
+    for pair in ranges.windows(2) {
+        if pair[1].0 <= pair[0].1 {
+            return Err(Error::OverlappingPhysRanges {
+                start: pair[1].0,
+                end:   pair[1].1,
+            });
+        }
+    }
+
+    // Write the contents of the ranges
+    out.resize((table_end + 0xfff) & !0xfff, 0);
+    for &(_, contents) in memory {
+        out.extend_from_slice(contents);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    extern crate std;
+    use std::vec;
+
+    /// Create a register state with some distinct values
+    fn test_regs() -> Registers {
+        let mut regs = Registers {
+            qemu_version: 1,
+            qemu_size:    0x1d0,
+            rax:          0x1337,
+            r15:          0xf00d,
+            rip:          0xfffff80012345678,
+            efer:         0xd01,
+            cr3:          0x1ad000,
+            ..Default::default()
+        };
+        regs.dr[7]      = 0x400;
+        regs.fxsave[24] = 0x80;
+        regs.cs = Segment {
+            selector: 0x10,
+            limit:    0xffffffff,
+            flags:    0xa09b00,
+            base:     0,
+        };
+        regs.ldtr = Segment {
+            selector: 0,
+            limit:    0,
+            flags:    0,
+            base:     0,
+        };
+        regs.tr = Segment {
+            selector: 0x40,
+            limit:    0x67,
+            flags:    0x8b00,
+            base:     0xfffff80000001000,
+        };
+        regs
+    }
+
+    #[test]
+    fn round_trip() {
+        let regs = test_regs();
+        let low  = vec![0x41u8; 0x2000];
+        let high = vec![0x42u8; 0x1000];
+        let dump = write(&regs, &[(0x100000, &high), (0, &low)]).unwrap();
+
+        let parsed = FalkDump::parse(&dump).unwrap();
+        assert!(parsed.version == VERSION);
+        assert!(parsed.phys_ranges.len() == 2);
+        assert!(parsed.phys_ranges[0].start == 0);
+        assert!(parsed.phys_ranges[0].end == 0x1fff);
+        assert!(parsed.phys_ranges[1].start == 0x100000);
+        assert!(parsed.phys_ranges.iter().all(|x| x.offset & 0xfff == 0));
+
+        // The register state must survive unchanged
+        let mut a = Vec::new();
+        let mut b = Vec::new();
+        regs.write(&mut a);
+        parsed.regs.write(&mut b);
+        assert!(a == b && a.len() == REGISTERS_SIZE);
+
+        // Check physical memory lookups
+        assert!(parsed.phys_page(0x1800).unwrap() == &low[..0x800]);
+        assert!(parsed.phys_page(0x100000).unwrap() == &high[..]);
+        assert!(parsed.phys_page(0x2000).is_none());
+        assert!(parsed.phys_page(0x101000).is_none());
+    }
+
+    #[test]
+    fn version_1() {
+        // Version 1 has the register state size directly after the
+        // signature
+        let regs = test_regs();
+        let mut dump = Vec::new();
+        dump.extend_from_slice(SIGNATURE);
+        dump.extend_from_slice(&(REGISTERS_SIZE as u64).to_le_bytes());
+        regs.write(&mut dump);
+        dump.extend_from_slice(&1u64.to_le_bytes());
+        dump.extend_from_slice(&0u64.to_le_bytes());
+        dump.extend_from_slice(&0xfffu64.to_le_bytes());
+        dump.extend_from_slice(&(dump.len() as u64 + 8).to_le_bytes());
+        dump.extend_from_slice(&[0x55u8; 0x1000]);
+
+        let parsed = FalkDump::parse(&dump).unwrap();
+        assert!(parsed.version == 1);
+        assert!(parsed.regs.rip == regs.rip);
+        assert!(parsed.phys_page(0x10).unwrap() == &[0x55u8; 0xff0][..]);
+    }
+
+    #[test]
+    fn errors() {
+        let dump = write(&test_regs(), &[(0, &[0u8; 0x1000])]).unwrap();
+
+        // Every truncation of the falkdump must be an error, not a panic
+        for len in 0..dump.len() {
+            assert!(FalkDump::parse(&dump[..len]).is_err());
+        }
+
+        let mut bad = dump.clone();
+        bad[0] = b'X';
+        assert!(FalkDump::parse(&bad).err() == Some(Error::InvalidSignature));
+
+        let mut bad = dump.clone();
+        bad[8] = 3;
+        assert!(FalkDump::parse(&bad).err() ==
+                Some(Error::UnsupportedVersion(3)));
+
+        // Unaligned and overlapping ranges
+        assert!(write(&test_regs(), &[(0x800, &[0u8; 0x1000])]).is_err());
+        assert!(write(&test_regs(), &[(0, &[0u8; 0x800])]).is_err());
+        assert!(write(&test_regs(), &[(0, &[0u8; 0x2000]),
+                                      (0x1000, &[0u8; 0x1000])]).err() ==
+                Some(Error::OverlappingPhysRanges {
+                    start: 0x1000,
+                    end:   0x1fff,
+                }));
+    }
+
+    #[test]
+    fn vmx_segments() {
+        let segments = test_regs().vmx_segments();
+
+        // Long mode zeroes the limits, and G is cleared to match
+        assert!(segments.cs.limit == 0);
+        assert!(segments.cs.access_rights == 0x209b);
+
+        // Non-present segments are unusable
+        assert!(segments.ldtr.access_rights == 0x10000);
+
+        // TR keeps its limit
+        assert!(segments.tr.limit == 0x67);
+        assert!(segments.tr.access_rights == 0x8b);
+    }
+}
@@ -9,6 +9,29 @@ use alloc::sync::Arc;
 use alloc::borrow::Cow;
 use alloc::string::String;
 use noodle::*;
+use falkhash::FalkHasher;
+
+/// Version of the protocol. Bump this whenever a message is added or changed
+/// such that a kernel and server built from different commits refuse to talk
+/// to each other rather than misinterpreting each other's packets
+pub const PROTOCOL_VERSION: u32 = 15;
+
+/// Maximum number of pages which can be requested in a single `ReadPages`
+pub const MAX_READ_PAGES: usize = 256;
+
+/// Compute the content hash of a file from the hashes of each of its pages
+///
+/// Page hashes are the `FalkHasher` hash of each 4 KiB page of the file, with
+/// the final page zero padded. The content hash is the hash of all page
+/// hashes in little endian, such that a client can verify the page hashes it
+/// was given with just the content hash, and each page with its page hash.
+pub fn file_hash(hasher: &FalkHasher, page_hashes: &[u128]) -> u128 {
+    let mut raw = Vec::with_capacity(page_hashes.len() * 16);
+    for hash in page_hashes {
+        raw.extend_from_slice(&hash.to_le_bytes());
+    }
+    hasher.hash(&raw)
+}
 
 noodle!(serialize, deserialize,
     #[derive(Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
@@ -26,20 +49,233 @@ noodle!(serialize, deserialize,
     }
 );
 
+noodle!(serialize, deserialize,
+    /// Attribution of a coverage record to the input which first hit it
+    #[derive(Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+    pub struct AttributionRecord<'a> {
+        pub input:    u128,
+        pub coverage: CoverageRecord<'a>,
+    }
+);
+
+noodle!(serialize, deserialize,
+    /// A symbol of a module, such as an export
+    #[derive(Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+    pub struct SymbolRecord<'a> {
+        /// Offset of the symbol into the module
+        pub offset: u64,
+
+        /// Name of the symbol
+        pub name: Cow<'a, str>,
+    }
+);
+
+noodle!(serialize, deserialize,
+    /// The CodeView debug record of a module, identifying the PDB which
+    /// contains the symbols of the module
+    #[derive(Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+    pub struct CodeViewRecord<'a> {
+        /// Name of the PDB, as stored in the module. This may be a full path
+        /// on the machine the module was built on.
+        pub pdb: Cow<'a, str>,
+
+        /// GUID of the PDB, in the byte order it is stored in the module
+        pub guid: [u8; 16],
+
+        /// Age of the PDB
+        pub age: u32,
+    }
+);
+
+noodle!(serialize, deserialize,
+    /// A module observed in the guest, used to give coverage records (which
+    /// are module relative) an address and size for tooling like drcov, and
+    /// to symbolize module offsets
+    #[derive(Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+    pub struct ModuleRecord<'a> {
+        pub name: Cow<'a, Arc<String>>,
+        pub base: u64,
+        pub size: u64,
+
+        /// Exported symbols of the module, read from its PE headers in guest
+        /// memory
+        pub exports: Cow<'a, [SymbolRecord<'a>]>,
+
+        /// CodeView debug record of the module, if it has one
+        pub codeview: Option<CodeViewRecord<'a>>,
+    }
+);
+
+noodle!(serialize, deserialize,
+    /// A snapshot of the guest register state at the time of a crash
+    #[derive(Clone, PartialEq, Eq, Debug, Default)]
+    pub struct CrashRegisters {
+        pub rax:    u64,
+        pub rbx:    u64,
+        pub rcx:    u64,
+        pub rdx:    u64,
+        pub rsi:    u64,
+        pub rdi:    u64,
+        pub rsp:    u64,
+        pub rbp:    u64,
+        pub r8:     u64,
+        pub r9:     u64,
+        pub r10:    u64,
+        pub r11:    u64,
+        pub r12:    u64,
+        pub r13:    u64,
+        pub r14:    u64,
+        pub r15:    u64,
+        pub rip:    u64,
+        pub rflags: u64,
+        pub cr0:    u64,
+        pub cr2:    u64,
+        pub cr3:    u64,
+        pub cr4:    u64,
+        pub cs:     u64,
+        pub ss:     u64,
+    }
+);
+
+noodle!(serialize, deserialize,
+    /// An input which caused the target to crash
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct CrashRecord<'a> {
+        /// The input which caused the crash
+        pub input: InputRecord<'a>,
+
+        /// The kind of VM exit which caused the crash, without any
+        /// information specific to this instance of the crash (such as
+        /// faulting addresses). This is used for bucketing crashes.
+        pub kind: Cow<'a, str>,
+
+        /// Full description of the VM exit which caused the crash
+        pub vmexit: Cow<'a, str>,
+
+        /// Module which contained the faulting RIP
+        pub module: Option<Cow<'a, Arc<String>>>,
+
+        /// Offset of the faulting RIP into `module`, or the raw RIP if the
+        /// module could not be resolved
+        pub offset: u64,
+
+        /// Register state at the time of the crash
+        pub regs: CrashRegisters,
+    }
+);
+
+noodle!(serialize, deserialize,
+    /// A single instruction of an instruction trace
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct TraceStep<'a> {
+        /// Module which contained the instruction, if modules were resolved
+        /// for the trace
+        pub module: Option<Cow<'a, Arc<String>>>,
+
+        /// Offset of the instruction into `module`, or the raw RIP if the
+        /// module could not be resolved
+        pub offset: u64,
+
+        /// Values of the traced registers after the instruction executed, in
+        /// the order of `TraceRecord::registers`
+        pub regs: Cow<'a, [u64]>,
+
+        /// Guest physical addresses of the pages the instruction dirtied,
+        /// that is the pages it was the first to write to in the fuzz case.
+        /// Later writes to these pages are not recorded.
+        pub dirtied: Cow<'a, [u64]>,
+
+        /// Guest physical addresses of the pages the instruction faulted in
+        /// for a read. Reads of pages which are already mapped into the
+        /// worker are not recorded.
+        pub faulted: Cow<'a, [u64]>,
+    }
+);
+
+noodle!(serialize, deserialize,
+    /// An instruction trace of a single input, recorded by single stepping
+    /// the guest
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub struct TraceRecord<'a> {
+        /// The input which was traced
+        pub input: InputRecord<'a>,
+
+        /// Names of the registers recorded at every step
+        pub registers: Cow<'a, [Cow<'a, str>]>,
+
+        /// Total number of instructions executed. Only the most recent steps
+        /// are kept, so this may be larger than the number of `steps`.
+        pub total_steps: u64,
+
+        /// The most recent steps of the trace, oldest first
+        pub steps: Cow<'a, [TraceStep<'a>]>,
+
+        /// Description of the VM exit which ended the fuzz case
+        pub vmexit: Cow<'a, str>,
+    }
+);
+
 noodle!(serialize, deserialize,
 /// Messages sent to and from the server for network mapped files
+///
+/// Variants are serialized by their index, so `Login` and `LoginResponse`
+/// must stay the first two variants such that the version check works
+/// between any two builds. New variants must be appended at the end.
 pub enum ServerMessage<'a> {
+    /// Log in as a new fuzzer
+    Login {
+        /// `PROTOCOL_VERSION` the client was built with. This must remain the
+        /// first field of the first message sent such that it can always be
+        /// checked
+        version: u32,
+
+        /// Session ID of the `FuzzSession`
+        session_id: u64,
+
+        /// Core ID of the worker logging in
+        core_id: u32,
+
+        /// Name of the fuzz target. Coverage, inputs, and crashes are kept
+        /// separately for each target, such that one server can coordinate
+        /// campaigns against different snapshots at the same time
+        target: Cow<'a, str>,
+    },
+
+    /// Response to a `Login`, containing the `PROTOCOL_VERSION` of the
+    /// server. If it does not match the client's version, the server
+    /// disconnects the client after sending this. Otherwise it is followed by
+    /// the corpus in `Inputs` and a `SyncComplete`
+    LoginResponse {
+        version: u32,
+    },
+
     /// Request a file ID for a filename on the server. This will cause the
     /// file to get loaded into memory on the server and persisted with the
     /// same ID.
-    GetFileId(Cow<'a, str>),
+    GetFileId {
+        /// Name of the file, relative to the server's `files` directory
+        filename: Cow<'a, str>,
+
+        /// Set if the file is mapped writable. Only generations which were
+        /// requested writable may be written with `WritePage` and `Flush`.
+        writable: bool,
+    },
 
     /// Returns the file ID and length of the requested filename from a
-    /// `GetFileId()` if the file exists on the server
+    /// `GetFileId()` if the file exists on the server, otherwise the server
+    /// replies with `NoSuchFile`
     FileId {
         /// File ID
         id: u64,
 
+        /// Generation of the file, incremented every time the server reloads
+        /// the file due to it changing on disk. The server keeps serving this
+        /// generation for as long as this connection is open.
+        generation: u64,
+
+        /// Content hash of this generation of the file, see `file_hash()`
+        hash: u128,
+
         /// Size of the file (in bytes)
         size: usize,
     },
@@ -49,6 +285,9 @@ pub enum ServerMessage<'a> {
         /// File identifier from a successful `OpenRequest`
         id: u64,
 
+        /// Generation of the file from a successful `GetFileId`
+        generation: u64,
+
         /// Offset (in bytes) into the file to request to read
         offset: usize,
     },
@@ -57,12 +296,9 @@ pub enum ServerMessage<'a> {
     /// this packet containing the raw bytes for the `size` requested.
     ReadPageResponse([u8; 4096]),
 
-    /// Log in as a new fuzzer
-    Login(u64, u32),
-
     /// Report new coverage
     Coverage(Cow<'a, [CoverageRecord<'a>]>),
-    
+
     /// Report new inputs
     Inputs(Cow<'a, [InputRecord<'a>]>),
 
@@ -84,5 +320,98 @@ pub enum ServerMessage<'a> {
     /// The server has sent any messages related to syncing and the client
     /// should resume fuzzing.
     SyncComplete,
+
+    /// Report a crash which is unique (by kind, module, and offset) for this
+    /// session
+    Crash(CrashRecord<'a>),
+
+    /// Request a read of `count` consecutive pages of an opened file. The
+    /// server responds with exactly `count` `ReadPageResponse`s, in order.
+    /// Pages past the end of the file are zero filled.
+    ReadPages {
+        /// File identifier from a successful `OpenRequest`
+        id: u64,
+
+        /// Generation of the file from a successful `GetFileId`
+        generation: u64,
+
+        /// Offset (in bytes) into the file of the first page to read
+        offset: usize,
+
+        /// Number of pages to read, at most `MAX_READ_PAGES`
+        count: usize,
+    },
+
+    /// Request the hash of every page of an opened file
+    GetPageHashes {
+        /// File identifier from a successful `GetFileId`
+        id: u64,
+
+        /// Generation of the file from a successful `GetFileId`
+        generation: u64,
+    },
+
+    /// Hashes of each page in a file, in response to a `GetPageHashes`
+    PageHashes(Cow<'a, [u128]>),
+
+    /// Report newly observed modules
+    Modules(Cow<'a, [ModuleRecord<'a>]>),
+
+    /// Report which inputs caused new coverage
+    Attributions(Cow<'a, [AttributionRecord<'a>]>),
+
+    /// Server command to stop fuzzing until a `Resume`. The workers keep
+    /// syncing with the server while paused.
+    Pause,
+
+    /// Server command to resume fuzzing after a `Pause`
+    Resume,
+
+    /// Server command to soft reboot the node
+    SoftReboot,
+
+    /// Server command to change the timeout of fuzz cases (in microseconds),
+    /// `None` disables the timeout
+    SetTimeout(Option<u64>),
+
+    /// Server command to switch the fuzz session to a different snapshot file
+    SwitchSnapshot(Cow<'a, str>),
+
+    /// Write a page back to an opened file on the server. Only the bytes of
+    /// the page which are within the file are written, a file never grows.
+    /// There is no response, use `Flush` to wait for the writes to complete.
+    WritePage {
+        /// File identifier from a successful `GetFileId`
+        id: u64,
+
+        /// Generation of the file from a successful `GetFileId`
+        generation: u64,
+
+        /// Offset (in bytes) into the file of the page, must be page aligned
+        offset: usize,
+
+        /// New contents of the page
+        contents: [u8; 4096],
+    },
+
+    /// Request that all prior `WritePage`s to an opened file are committed
+    /// to disk. The server responds with a `FlushResponse` once they are.
+    Flush {
+        /// File identifier from a successful `GetFileId`
+        id: u64,
+
+        /// Generation of the file from a successful `GetFileId`
+        generation: u64,
+    },
+
+    /// Response to a `Flush`, all prior writes are on disk
+    FlushResponse,
+
+    /// Upload an instruction trace of an input
+    Trace(TraceRecord<'a>),
+
+    /// Response to a `GetFileId` for a file which does not exist on the
+    /// server, or which may not be accessed
+    NoSuchFile,
 });
 
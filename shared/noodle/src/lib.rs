@@ -58,6 +58,11 @@ impl<T: Writer + Reader> BufferedIo<T> {
             write: VecDeque::with_capacity(16 * 1024),
         }
     }
+    /// Get a reference to the underlying reader + writer
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
     /// Flush the internal TX buffer
     pub fn flush(&mut self) -> Option<()> {
         let (front, back) = self.write.as_slices();
@@ -123,10 +128,17 @@ impl<T: Writer + Reader> Reader for BufferedIo<T> {
 }
 
 /// `Reader` implementation for types that implement `Read`
+///
+/// Reaching EOF before `buf` could be filled with anything is treated as a
+/// failure, such that deserialization stops rather than retrying forever
 #[cfg(feature = "std")]
 impl<T: std::io::Read> Reader for T {
     fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
-        self.read(buf).ok()
+        match std::io::Read::read(self, buf) {
+            Ok(0) if buf.len() > 0 => None,
+            Ok(bread) => Some(bread),
+            Err(_) => None,
+        }
     }
 }
 
@@ -230,6 +242,24 @@ serialize_le!(i128);
 serialize_le!(usize, u64);
 serialize_le!(isize, i64);
 
+/// Implement `Serialize` for `bool`, as a single byte
+impl Serialize for bool {
+    fn serialize<W: Writer>(&self, writer: &mut W) -> Option<()> {
+        writer.write(&[*self as u8])
+    }
+}
+
+/// Implement `Deserialize` for `bool`, rejecting bytes other than 0 and 1
+impl Deserialize for bool {
+    fn deserialize<R: Reader>(reader: &mut R) -> Option<Self> {
+        match <u8 as Deserialize>::deserialize(reader)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
 /// Implement serialize for `&str`
 impl Serialize for str {
     fn serialize<W: Writer>(&self, writer: &mut W) -> Option<()> {
@@ -1136,6 +1166,16 @@ mod test {
         test_serdes!(TestE, TestE::Scoops);
     }
 
+    #[test]
+    fn test_bool() {
+        test_serdes!(bool, true);
+        test_serdes!(bool, false);
+
+        // Only 0 and 1 are valid
+        let mut ptr = &[2u8][..];
+        assert!(bool::deserialize(&mut ptr).is_none());
+    }
+
     #[test]
     fn test_struct() {
         // Empty struct
@@ -0,0 +1,378 @@
+//! A library for handling parsing of all x86 page table types, shared between
+//! the kernel and host tools which inspect guest memory
+
+#![no_std]
+
+use core::ops::Range;
+use core::mem::size_of;
+use core::convert::TryInto;
+use page_table::{VirtAddr, PhysAddr, PAGE_PRESENT, PAGE_SIZE};
+
+/// A page table entry describing the shapes, masks, and large paging support
+/// of a given level in a page table
+#[derive(PartialEq, Eq)]
+struct PageTableEntry {
+    /// Bits from the virtual address which are used as an index into this
+    /// level of the table
+    bits: Range<u8>,
+
+    /// Page table entry mask to get the physical address of the pages
+    page_mask: u64,
+
+    /// Size of a large page at this level and its physical address mask,
+    /// if a large page is supported at this level
+    large_page: Option<(u64, u64)>,
+}
+
+/// Get the physical address of a large page from its page table entry `ent`,
+/// where `lp_size` and `lp_mask` are the `large_page` of the level
+fn large_page_frame(ent: u64, lp_size: u64, lp_mask: u64) -> PhysAddr {
+    if lp_mask != 0 {
+        PhysAddr(ent & lp_mask)
+    } else {
+        // Ugh, special encoding for 4 MiB pages on non-PAE 32-bit modes
+        assert!(lp_size == 4 * 1024 * 1024);
+
+        // Figure out the physical address from the weird encoding
+        let frame31_22 = (ent & 0xffc00000) >> 22;
+        let frame40_32 = (ent & 0x003fe000) >> 13;
+        PhysAddr((frame40_32 << 32) | (frame31_22 << 22))
+    }
+}
+
+/// Creates a page table walker for a given page table shape
+macro_rules! define_walker {
+    ($fname:ident, $metadata:ident, $mappings:ident, $ety:ty, $cr3_mask:expr,
+     $tbl:expr) => {
+    pub fn $fname<'a, F>(cr3: u64, vaddr: VirtAddr, mut translate: F)
+        -> Option<(PhysAddr, u64, u64)>
+            where F: FnMut(PhysAddr) -> Option<$ety> {
+        // Mask off everything but the physical address from the CR3
+        let mut table = cr3 & $cr3_mask;
+
+        for (ii, level) in $tbl.iter().enumerate() {
+            // Compute the index of the page table entry at this level
+            let idx = (vaddr.0 >> level.bits.start) &
+                ((1 << (level.bits.end - level.bits.start)) - 1);
+
+            // Find the byte offset into the page table
+            let offset = idx * size_of::<$ety>() as u64;
+
+            // Read the entry
+            let ent = translate(PhysAddr(table + offset))? as u64;
+
+            // If the page is not present, return page not mapped
+            if (ent & PAGE_PRESENT) == 0 {
+                return None;
+            }
+
+            // Check if this level supports large pages
+            if let Some((lp_size, lp_mask)) = level.large_page {
+                // Check if this is a large page
+                if (ent & PAGE_SIZE) != 0 {
+                    // Woo, we have a large page, compute the offset into the
+                    // page the virtual address is referencing
+                    let offset = vaddr.0 & (lp_size - 1);
+                    return Some((large_page_frame(ent, lp_size, lp_mask),
+                        offset, lp_size));
+                }
+            }
+
+            // Check if this is the last level of the table
+            if ii == $tbl.len() - 1 {
+                // It's a page!
+
+                // Compute the offset into the page the virtual address is
+                // referencing
+                let offset = vaddr.0 & 0xfff;
+                return Some((PhysAddr(ent & level.page_mask), offset, 4096));
+            } else {
+                // It's a next level entry, update the table pointer
+                table = ent & level.page_mask;
+            }
+        }
+
+        // We cannot get here
+        unreachable!();
+    }
+
+    pub fn $metadata<'a, F, C>(table: u64, depth: u8, get_page: F,
+                               callback: &mut C)
+            where F: Fn(PhysAddr) -> Option<&'a [u8]> + Copy,
+                  C: FnMut(PhysAddr) {
+        // Stop traversal before the final PTE, we don't actually want to
+        // walk the pages in the system, only the page _tables_ so we stop
+        // before the final leaf
+        if depth.wrapping_add(2) as usize == $tbl.len() {
+            return;
+        }
+
+        // Mask the entry to get the page table
+        let table = if depth == !0 {
+            table & $cr3_mask
+        } else {
+            table & $tbl[depth as usize].page_mask
+        };
+
+        // Get the information about this page table level
+        let level = &$tbl[depth.wrapping_add(1) as usize];
+
+        // Determine the number of entries at this level
+        let entries = 1 << (level.bits.end - level.bits.start);
+
+        // Get access to the page table entries for this level
+        let page = get_page(PhysAddr(table));
+        if page.is_none() { return; }
+        let page = page.unwrap();
+
+        // Go through each entry at this level
+        for ent in 0..entries {
+            // Read the page table entry, depending on the page table type
+            let ent = <$ety>::from_le_bytes(
+                page[ent * size_of::<$ety>()..(ent + 1) * size_of::<$ety>()]
+                .try_into().unwrap()) as u64;
+
+            // Skip non-present pages
+            if ent & PAGE_PRESENT == 0 { continue; }
+
+            // Skip large pages as that's the end of the structure
+            if level.large_page.is_some() && (ent & PAGE_SIZE) != 0 {
+                // Entry was a large page, we don't have to recurse
+                continue;
+            }
+
+            // Invoke the callback with the metadata information
+            callback(PhysAddr(ent & level.page_mask));
+
+            // Recurse into this table
+            $metadata(ent & level.page_mask, depth.wrapping_add(1),
+                get_page, callback);
+        }
+    }
+
+    /// Invoke `callback` with the virtual address, physical address, size,
+    /// and raw page table entry of every present page mapped by the page
+    /// table in `cr3`, in order of virtual address
+    pub fn $mappings<'a, F, C>(cr3: u64, get_page: F, callback: &mut C)
+            where F: Fn(PhysAddr) -> Option<&'a [u8]> + Copy,
+                  C: FnMut(VirtAddr, PhysAddr, u64, u64) {
+        /// Walk the table at `depth`, which maps the virtual addresses
+        /// starting at `vaddr`
+        fn walk<'a, F, C>(table: u64, depth: usize, vaddr: u64, get_page: F,
+                          callback: &mut C)
+                where F: Fn(PhysAddr) -> Option<&'a [u8]> + Copy,
+                      C: FnMut(VirtAddr, PhysAddr, u64, u64) {
+            // Get the information about this page table level
+            let tbl: &[PageTableEntry] = $tbl;
+            let level = &tbl[depth];
+
+            // Determine the number of entries at this level
+            let entries = 1 << (level.bits.end - level.bits.start);
+
+            // Get access to the page table entries for this level
+            let page = match get_page(PhysAddr(table)) {
+                Some(page) => page,
+                None       => return,
+            };
+
+            for idx in 0..entries {
+                // Read the page table entry, depending on the page table type
+                let ent = <$ety>::from_le_bytes(
+                    page[idx * size_of::<$ety>()..
+                         (idx + 1) * size_of::<$ety>()]
+                    .try_into().unwrap()) as u64;
+
+                // Skip non-present pages
+                if ent & PAGE_PRESENT == 0 { continue; }
+
+                // Compute the virtual address mapped by this entry, sign
+                // extending 48-bit addresses to be canonical
+                let mut vaddr = vaddr | ((idx as u64) << level.bits.start);
+                if level.bits.end == 48 && vaddr & (1 << 47) != 0 {
+                    vaddr |= 0xffff_0000_0000_0000;
+                }
+
+                // Report large pages
+                if let Some((lp_size, lp_mask)) = level.large_page {
+                    if (ent & PAGE_SIZE) != 0 {
+                        callback(VirtAddr(vaddr),
+                                 large_page_frame(ent, lp_size, lp_mask),
+                                 lp_size, ent);
+                        continue;
+                    }
+                }
+
+                if depth == tbl.len() - 1 {
+                    // It's a page!
+                    callback(VirtAddr(vaddr),
+                             PhysAddr(ent & level.page_mask), 4096, ent);
+                } else {
+                    // It's a next level entry, recurse into it
+                    walk(ent & level.page_mask, depth + 1, vaddr, get_page,
+                         callback);
+                }
+            }
+        }
+
+        walk(cr3 & $cr3_mask, 0, 0, get_page, callback);
+    }
+    }
+}
+
+// 32-bit paging without PAE. A PDE with PS set maps a 4 MiB page, whose
+// physical address is split over the entry and decoded by
+// `large_page_frame()` as the mask is zero. The walker in the kernel had the
+// size and mask of this tuple swapped, so 4 MiB pages never translated.
+define_walker!(translate_32_no_pae, translate_32_no_pae_metadata,
+               translate_32_no_pae_mappings, u32, 0xfffff000, &[
+    PageTableEntry { bits: 22..32, page_mask: 0xfffff000,
+        large_page: Some((4 * 1024 * 1024, 0)) },
+
+    PageTableEntry { bits: 12..22, page_mask: 0xfffff000, large_page: None },
+]);
+
+// 32-bit paging with PAE. Entries at every level are 64 bits wide, and CR3
+// points at a 32-byte aligned table of 4 PDPTEs. The walker in the kernel
+// read the entries as 32 bits and page aligned CR3, thus it dropped the high
+// half of every entry and read the wrong PDPT when it was not page aligned.
+define_walker!(translate_32_pae, translate_32_pae_metadata,
+               translate_32_pae_mappings, u64, 0xffffffe0, &[
+    PageTableEntry { bits: 30..32, page_mask: 0xffffffffff000,
+        large_page: None },
+
+    PageTableEntry { bits: 21..30, page_mask: 0xffffffffff000,
+        large_page: Some((2 * 1024 * 1024, 0xfffffffe00000)) },
+
+    PageTableEntry { bits: 12..21, page_mask: 0xffffffffff000,
+        large_page: None },
+]);
+
+define_walker!(translate_64_4_level, translate_64_4_level_metadata,
+               translate_64_4_level_mappings, u64, 0xffffffffff000, &[
+    PageTableEntry { bits: 39..48, page_mask: 0xffffffffff000,
+        large_page: None },
+
+    PageTableEntry { bits: 30..39, page_mask: 0xffffffffff000,
+        large_page: Some((1024 * 1024 * 1024, 0xfffffc0000000)) },
+
+    PageTableEntry { bits: 21..30, page_mask: 0xffffffffff000,
+        large_page: Some((2 * 1024 * 1024, 0xfffffffe00000)) },
+
+    PageTableEntry { bits: 12..21, page_mask: 0xffffffffff000,
+        large_page: None },
+]);
+
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+    use page_table::PAGE_NX;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Write a `u64` to the physical memory `mem`
+    fn write64(mem: &mut [u8], paddr: u64, val: u64) {
+        mem[paddr as usize..][..8].copy_from_slice(&val.to_le_bytes());
+    }
+
+    /// Write a `u32` to the physical memory `mem`
+    fn write32(mem: &mut [u8], paddr: u64, val: u32) {
+        mem[paddr as usize..][..4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    #[test]
+    fn test_64_4_level() {
+        let mut mem = std::vec![0u8; 0x10000];
+
+        // 0x1000 -> 0x7000 as a 4 KiB page
+        write64(&mut mem, 0x1000, 0x2000 | PAGE_PRESENT);
+        write64(&mut mem, 0x2000, 0x3000 | PAGE_PRESENT);
+        write64(&mut mem, 0x3000, 0x4000 | PAGE_PRESENT);
+        write64(&mut mem, 0x4000 + 8, 0x7000 | PAGE_PRESENT);
+
+        // 0xffff800000200000 -> 0x40000000 as a 2 MiB page
+        write64(&mut mem, 0x1000 + 256 * 8, 0x5000 | PAGE_PRESENT);
+        write64(&mut mem, 0x5000, 0x6000 | PAGE_PRESENT);
+        write64(&mut mem, 0x6000 + 8,
+                0x40000000 | PAGE_SIZE | PAGE_PRESENT);
+
+        let mem = &mem;
+        let read = |paddr: PhysAddr| {
+            Some(u64::from_le_bytes(
+                mem[paddr.0 as usize..][..8].try_into().unwrap()))
+        };
+
+        assert!(translate_64_4_level(0x1000, VirtAddr(0x1234), read) ==
+                Some((PhysAddr(0x7000), 0x234, 4096)));
+        assert!(translate_64_4_level(0x1000,
+                                     VirtAddr(0xffff800000212345), read) ==
+                Some((PhysAddr(0x40000000), 0x12345, 2 * 1024 * 1024)));
+        assert!(translate_64_4_level(0x1000, VirtAddr(0x2000), read)
+                .is_none());
+
+        let mut mappings = Vec::new();
+        translate_64_4_level_mappings(0x1000,
+            |paddr| mem.get(paddr.0 as usize..),
+            &mut |vaddr, paddr, size, _| mappings.push((vaddr, paddr, size)));
+        assert!(mappings == [
+            (VirtAddr(0x1000), PhysAddr(0x7000), 4096),
+            (VirtAddr(0xffff800000200000), PhysAddr(0x40000000),
+             2 * 1024 * 1024),
+        ]);
+    }
+
+    #[test]
+    fn test_32_no_pae() {
+        let mut mem = std::vec![0u8; 0x10000];
+
+        // 0x00401000 -> 0x9000 as a 4 KiB page
+        write32(&mut mem, 0x1000 + 1 * 4, 0x2000 | PAGE_PRESENT as u32);
+        write32(&mut mem, 0x2000 + 1 * 4, 0x9000 | PAGE_PRESENT as u32);
+
+        // 0x80000000 -> 0x1_00c00000 as a 4 MiB page
+        write32(&mut mem, 0x1000 + 512 * 4,
+                0x00c00000 | (1 << 13) | (PAGE_SIZE | PAGE_PRESENT) as u32);
+
+        let mem = &mem;
+        let read = |paddr: PhysAddr| {
+            Some(u32::from_le_bytes(
+                mem[paddr.0 as usize..][..4].try_into().unwrap()))
+        };
+
+        assert!(translate_32_no_pae(0x1000, VirtAddr(0x00401010), read) ==
+                Some((PhysAddr(0x9000), 0x10, 4096)));
+        assert!(translate_32_no_pae(0x1000, VirtAddr(0x80123456), read) ==
+                Some((PhysAddr(0x1_00c00000), 0x123456, 4 * 1024 * 1024)));
+
+        let mut mappings = Vec::new();
+        translate_32_no_pae_mappings(0x1000,
+            |paddr| mem.get(paddr.0 as usize..),
+            &mut |vaddr, paddr, size, _| mappings.push((vaddr, paddr, size)));
+        assert!(mappings == [
+            (VirtAddr(0x00401000), PhysAddr(0x9000), 4096),
+            (VirtAddr(0x80000000), PhysAddr(0x1_00c00000), 4 * 1024 * 1024),
+        ]);
+    }
+
+    #[test]
+    fn test_32_pae() {
+        let mut mem = std::vec![0u8; 0x10000];
+
+        // 0xc0001000 -> 0x1_23456000 as a no-execute 4 KiB page, with the
+        // page directory pointer table at a 32-byte aligned address
+        write64(&mut mem, 0x1020 + 3 * 8, 0x2000 | PAGE_PRESENT);
+        write64(&mut mem, 0x2000, 0x3000 | PAGE_PRESENT);
+        write64(&mut mem, 0x3000 + 1 * 8,
+                0x1_23456000 | PAGE_NX | PAGE_PRESENT);
+
+        let mem = &mem;
+        let read = |paddr: PhysAddr| {
+            Some(u64::from_le_bytes(
+                mem[paddr.0 as usize..][..8].try_into().unwrap()))
+        };
+
+        assert!(translate_32_pae(0x1020, VirtAddr(0xc0001abc), read) ==
+                Some((PhysAddr(0x1_23456000), 0xabc, 4096)));
+    }
+}
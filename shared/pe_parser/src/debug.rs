@@ -0,0 +1,156 @@
+//! Debug directory parsing, including the CodeView record identifying the
+//! PDB of an image
+
+use core::convert::TryInto;
+use alloc::string::String;
+use crate::{Pe, Reader, Layout, DIRECTORY_DEBUG};
+
+/// Debug entry containing a CodeView record
+pub const IMAGE_DEBUG_TYPE_CODEVIEW: u32 = 2;
+
+/// Size of an `IMAGE_DEBUG_DIRECTORY`
+const DEBUG_ENTRY_SIZE: u32 = 0x1c;
+
+/// Maximum number of debug entries we iterate
+const MAX_DEBUG_ENTRIES: u32 = 64;
+
+/// Maximum size of a CodeView record we read, enough for the header and a
+/// long path to the PDB
+const MAX_CODEVIEW_SIZE: u32 = 0x18 + 1024;
+
+/// An entry of the debug directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DebugEntry {
+    /// Time stamp of the debug data
+    pub time_date_stamp: u32,
+
+    /// Major version of the debug data format
+    pub major_version: u16,
+
+    /// Minor version of the debug data format
+    pub minor_version: u16,
+
+    /// Type of the debug data (`IMAGE_DEBUG_TYPE_*`)
+    pub kind: u32,
+
+    /// Size of the debug data (in bytes)
+    pub size: u32,
+
+    /// RVA of the debug data, zero if it is not mapped into memory
+    pub rva: u32,
+
+    /// File offset of the debug data
+    pub file_offset: u32,
+}
+
+/// A CodeView (RSDS) record, identifying the PDB of the image
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CodeView {
+    /// GUID of the PDB, in the byte order it is stored in the image
+    pub guid: [u8; 16],
+
+    /// Age of the PDB
+    pub age: u32,
+
+    /// Path of the PDB, as recorded by the linker
+    pub pdb: String,
+}
+
+/// Iterator over the entries of the debug directory of a PE
+pub struct DebugEntries<'p, R: Reader> {
+    /// PE we are iterating the debug directory of
+    pe: &'p Pe<R>,
+
+    /// RVA of the next debug entry
+    rva: u32,
+
+    /// Number of entries we have yet to iterate
+    remaining: u32,
+}
+
+impl<R: Reader> Pe<R> {
+    /// Get an iterator over the entries of the debug directory of the image.
+    /// Returns `None` if there is no debug directory.
+    pub fn debug_entries(&self) -> Option<DebugEntries<'_, R>> {
+        let directory = self.directory(DIRECTORY_DEBUG)?;
+
+        Some(DebugEntries {
+            pe:        self,
+            rva:       directory.rva,
+            remaining: core::cmp::min(directory.size / DEBUG_ENTRY_SIZE,
+                                      MAX_DEBUG_ENTRIES),
+        })
+    }
+
+    /// Read the start of the data of the debug `entry` into `buf`
+    pub fn read_debug_data(&self, entry: &DebugEntry, buf: &mut [u8])
+            -> Option<()> {
+        if buf.len() > entry.size as usize { return None; }
+
+        match self.layout {
+            Layout::Mapped => {
+                if entry.rva == 0 { return None; }
+                self.read_rva(entry.rva, buf)
+            }
+            Layout::File => {
+                self.reader.borrow_mut().read(entry.file_offset as u64, buf)
+            }
+        }
+    }
+
+    /// Get the CodeView record of the image, if it has one
+    pub fn codeview(&self) -> Option<CodeView> {
+        self.debug_entries()?.find_map(|entry| {
+            if entry.kind != IMAGE_DEBUG_TYPE_CODEVIEW || entry.size < 0x18 {
+                return None;
+            }
+
+            // Read the record, we only support the RSDS format of PDB 7.0
+            let size = core::cmp::min(entry.size, MAX_CODEVIEW_SIZE);
+            let mut record = vec![0u8; size as usize];
+            self.read_debug_data(&entry, &mut record)?;
+            if &record[..4] != b"RSDS" { return None; }
+
+            // The path of the PDB is NUL terminated
+            let pdb = &record[0x18..];
+            let len = pdb.iter().position(|&x| x == 0)?;
+
+            let age = record[0x14..0x18].try_into().unwrap();
+            Some(CodeView {
+                guid: record[4..0x14].try_into().unwrap(),
+                age:  u32::from_le_bytes(age),
+                pdb:  String::from_utf8(pdb[..len].to_vec()).ok()?,
+            })
+        })
+    }
+}
+
+impl<'p, R: Reader> Iterator for DebugEntries<'p, R> {
+    type Item = DebugEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 { return None; }
+        self.remaining -= 1;
+
+        let mut raw = [0u8; DEBUG_ENTRY_SIZE as usize];
+        self.pe.read_rva(self.rva, &mut raw)?;
+        self.rva = self.rva.checked_add(DEBUG_ENTRY_SIZE)?;
+
+        let u16_field = |off: usize| {
+            u16::from_le_bytes(raw[off..off + 2].try_into().unwrap())
+        };
+        let u32_field = |off: usize| {
+            u32::from_le_bytes(raw[off..off + 4].try_into().unwrap())
+        };
+
+        Some(DebugEntry {
+            time_date_stamp: u32_field(0x4),
+            major_version:   u16_field(0x8),
+            minor_version:   u16_field(0xa),
+            kind:            u32_field(0xc),
+            size:            u32_field(0x10),
+            rva:             u32_field(0x14),
+            file_offset:     u32_field(0x18),
+        })
+    }
+}
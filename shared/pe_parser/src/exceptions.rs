@@ -0,0 +1,231 @@
+//! Exception directory (`.pdata`) and unwind information parsing, for x86_64
+//! images
+
+use alloc::vec::Vec;
+use crate::{Pe, Reader, DIRECTORY_EXCEPTION, IMAGE_FILE_MACHINE_X86_64};
+
+/// The function has an exception handler
+pub const UNW_FLAG_EHANDLER: u8 = 1;
+
+/// The function has a termination handler
+pub const UNW_FLAG_UHANDLER: u8 = 2;
+
+/// The unwind information is chained to that of another function
+pub const UNW_FLAG_CHAININFO: u8 = 4;
+
+/// Size of a `RUNTIME_FUNCTION`
+const RUNTIME_FUNCTION_SIZE: u32 = 12;
+
+/// A `RUNTIME_FUNCTION` entry of the exception directory
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RuntimeFunction {
+    /// RVA of the start of the function
+    pub begin: u32,
+
+    /// RVA of the end of the function (exclusive)
+    pub end: u32,
+
+    /// RVA of the unwind information of the function
+    pub unwind_info: u32,
+}
+
+/// An unwind operation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UnwindOp {
+    /// Push of a non-volatile register
+    PushNonVolatile { register: u8 },
+
+    /// Allocation of `size` bytes on the stack
+    Alloc { size: u32 },
+
+    /// Establishment of the frame pointer register
+    SetFramePointer,
+
+    /// Save of a non-volatile register at `offset` from the stack pointer
+    SaveNonVolatile { register: u8, offset: u32 },
+
+    /// Save of an XMM register at `offset` from the stack pointer
+    SaveXmm128 { register: u8, offset: u32 },
+
+    /// Push of a machine frame, with an error code if `error_code` is set
+    PushMachineFrame { error_code: bool },
+
+    /// An operation we do not decode, such as epilog descriptions
+    Other { op: u8, info: u8 },
+}
+
+/// An unwind code
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnwindCode {
+    /// Offset from the start of the prolog of the end of the instruction
+    /// performing the operation
+    pub offset: u8,
+
+    /// The operation
+    pub op: UnwindOp,
+}
+
+/// Unwind information of a function
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnwindInfo {
+    /// Version of the unwind information
+    pub version: u8,
+
+    /// Flags (`UNW_FLAG_*`)
+    pub flags: u8,
+
+    /// Size of the prolog (in bytes)
+    pub prolog_size: u8,
+
+    /// Frame pointer register, zero if there is none
+    pub frame_register: u8,
+
+    /// Offset of the frame pointer from the stack pointer (in bytes)
+    pub frame_offset: u8,
+
+    /// Unwind codes, in reverse order of the prolog
+    pub codes: Vec<UnwindCode>,
+
+    /// RVA of the exception or termination handler, if the function has one
+    pub handler: Option<u32>,
+
+    /// Function whose unwind information is chained to this one
+    pub chained: Option<RuntimeFunction>,
+}
+
+/// Iterator over the runtime functions of a PE
+pub struct RuntimeFunctions<'p, R: Reader> {
+    /// PE we are iterating the runtime functions of
+    pe: &'p Pe<R>,
+
+    /// RVA of the next runtime function
+    rva: u32,
+
+    /// Number of runtime functions we have yet to iterate
+    remaining: u32,
+}
+
+impl<R: Reader> Pe<R> {
+    /// Get an iterator over the runtime functions of the image. Returns
+    /// `None` if the image is not x86_64 or has no exception directory.
+    pub fn runtime_functions(&self) -> Option<RuntimeFunctions<'_, R>> {
+        if self.machine != IMAGE_FILE_MACHINE_X86_64 { return None; }
+        let directory = self.directory(DIRECTORY_EXCEPTION)?;
+
+        Some(RuntimeFunctions {
+            pe:        self,
+            rva:       directory.rva,
+            remaining: directory.size / RUNTIME_FUNCTION_SIZE,
+        })
+    }
+
+    /// Read a `RUNTIME_FUNCTION` at `rva`
+    fn read_runtime_function(&self, rva: u32) -> Option<RuntimeFunction> {
+        Some(RuntimeFunction {
+            begin:       self.read_u32(rva)?,
+            end:         self.read_u32(rva.checked_add(4)?)?,
+            unwind_info: self.read_u32(rva.checked_add(8)?)?,
+        })
+    }
+
+    /// Get the unwind information of `function`
+    pub fn unwind_info(&self, function: &RuntimeFunction)
+            -> Option<UnwindInfo> {
+        let rva = function.unwind_info;
+
+        let mut header = [0u8; 4];
+        self.read_rva(rva, &mut header)?;
+        let flags = header[0] >> 3;
+
+        // Read the unwind code slots, some codes take multiple slots
+        let mut slots = vec![0u8; header[2] as usize * 2];
+        self.read_rva(rva.checked_add(4)?, &mut slots)?;
+        let slots: Vec<u16> = slots.chunks(2)
+            .map(|x| u16::from_le_bytes([x[0], x[1]])).collect();
+
+        let mut codes = Vec::new();
+        let mut ii = 0;
+        while ii < slots.len() {
+            let offset = slots[ii] as u8;
+            let op     = (slots[ii] >> 8) as u8 & 0xf;
+            let info   = (slots[ii] >> 12) as u8;
+
+            // Get the operands which follow the code
+            let u16_operand = slots.get(ii + 1).map(|&x| x as u32);
+            let u32_operand = slots.get(ii + 1..ii + 3)
+                .map(|x| x[0] as u32 | (x[1] as u32) << 16);
+
+            let (op, used) = match op {
+                0 => (UnwindOp::PushNonVolatile { register: info }, 1),
+                1 if info == 0 => (UnwindOp::Alloc {
+                    size: u16_operand? * 8,
+                }, 2),
+                1 => (UnwindOp::Alloc { size: u32_operand? }, 3),
+                2 => (UnwindOp::Alloc { size: info as u32 * 8 + 8 }, 1),
+                3 => (UnwindOp::SetFramePointer, 1),
+                4 => (UnwindOp::SaveNonVolatile {
+                    register: info,
+                    offset:   u16_operand? * 8,
+                }, 2),
+                5 => (UnwindOp::SaveNonVolatile {
+                    register: info,
+                    offset:   u32_operand?,
+                }, 3),
+                6 => (UnwindOp::Other { op, info }, 2),
+                7 => (UnwindOp::Other { op, info }, 3),
+                8 => (UnwindOp::SaveXmm128 {
+                    register: info,
+                    offset:   u16_operand? * 16,
+                }, 2),
+                9 => (UnwindOp::SaveXmm128 {
+                    register: info,
+                    offset:   u32_operand?,
+                }, 3),
+                10 => (UnwindOp::PushMachineFrame { error_code: info == 1 },
+                       1),
+                _ => (UnwindOp::Other { op, info }, 1),
+            };
+
+            codes.push(UnwindCode {
+                offset,
+                op,
+            });
+            ii += used;
+        }
+
+        // The handler or chained function follows the codes, which are padded
+        // to an even number of slots
+        let trailer = rva.checked_add(4 + ((slots.len() as u32 + 1) & !1) * 2)?;
+        let (handler, chained) = if flags & UNW_FLAG_CHAININFO != 0 {
+            (None, Some(self.read_runtime_function(trailer)?))
+        } else if flags & (UNW_FLAG_EHANDLER | UNW_FLAG_UHANDLER) != 0 {
+            (Some(self.read_u32(trailer)?), None)
+        } else {
+            (None, None)
+        };
+
+        Some(UnwindInfo {
+            version:        header[0] & 7,
+            prolog_size:    header[1],
+            frame_register: header[3] & 0xf,
+            frame_offset:   (header[3] >> 4) * 16,
+            flags,
+            codes,
+            handler,
+            chained,
+        })
+    }
+}
+
+impl<'p, R: Reader> Iterator for RuntimeFunctions<'p, R> {
+    type Item = RuntimeFunction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 { return None; }
+        self.remaining -= 1;
+
+        let function = self.pe.read_runtime_function(self.rva)?;
+        self.rva = self.rva.checked_add(RUNTIME_FUNCTION_SIZE)?;
+        Some(function)
+    }
+}
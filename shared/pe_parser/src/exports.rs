@@ -0,0 +1,140 @@
+//! Export directory parsing
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use crate::{Pe, Reader, DataDirectory, DIRECTORY_EXPORT};
+
+/// Maximum number of entries in the export tables. Ordinals are 16-bit, so
+/// no valid export table is larger.
+const MAX_EXPORTS: u32 = 0x10000;
+
+/// What an export refers to
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ExportTarget {
+    /// RVA of the exported code or data in this image
+    Rva(u32),
+
+    /// The export is forwarded to an export of another DLL, in the form of
+    /// `dll.name` or `dll.#ordinal`
+    Forwarder(String),
+}
+
+/// An exported symbol
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Export {
+    /// Ordinal of the export, including the ordinal base
+    pub ordinal: u32,
+
+    /// Name of the export, `None` if it is only exported by ordinal or the
+    /// name could not be read
+    pub name: Option<String>,
+
+    /// What the export refers to
+    pub target: ExportTarget,
+}
+
+/// Iterator over the exports of a PE, in ordinal order
+pub struct Exports<'p, R: Reader> {
+    /// PE we are iterating the exports of
+    pe: &'p Pe<R>,
+
+    /// Location of the export directory, used to detect forwarders
+    directory: DataDirectory,
+
+    /// Ordinal of the first entry of the export address table
+    ordinal_base: u32,
+
+    /// RVA of the export address table
+    functions: u32,
+
+    /// Number of entries in the export address table
+    num_functions: u32,
+
+    /// Index into the export address table and RVA of the name of each named
+    /// export, sorted by index
+    names: Vec<(u32, u32)>,
+
+    /// Index of the next entry of the export address table
+    index: u32,
+}
+
+impl<R: Reader> Pe<R> {
+    /// Get an iterator over the exports of the image. Returns `None` if
+    /// there is no export directory or its name tables cannot be read.
+    pub fn exports(&self) -> Option<Exports<'_, R>> {
+        let directory = self.directory(DIRECTORY_EXPORT)?;
+        let ordinal_base  = self.read_u32(directory.rva.checked_add(0x10)?)?;
+        let num_functions = self.read_u32(directory.rva.checked_add(0x14)?)?;
+        let num_names     = self.read_u32(directory.rva.checked_add(0x18)?)?;
+        let functions     = self.read_u32(directory.rva.checked_add(0x1c)?)?;
+        let name_table    = self.read_u32(directory.rva.checked_add(0x20)?)?;
+        let ordinal_table = self.read_u32(directory.rva.checked_add(0x24)?)?;
+        if num_functions > MAX_EXPORTS || num_names > MAX_EXPORTS {
+            return None;
+        }
+
+        // Read the name and ordinal tables, which are parallel arrays
+        let mut raw_names = vec![0u8; num_names as usize * 4];
+        let mut raw_ordinals = vec![0u8; num_names as usize * 2];
+        if num_names > 0 {
+            self.read_rva(name_table, &mut raw_names)?;
+            self.read_rva(ordinal_table, &mut raw_ordinals)?;
+        }
+
+        let mut names: Vec<(u32, u32)> = raw_names.chunks(4)
+            .zip(raw_ordinals.chunks(2))
+            .map(|(name, index)| {
+                (u16::from_le_bytes([index[0], index[1]]) as u32,
+                 u32::from_le_bytes([name[0], name[1], name[2], name[3]]))
+            }).collect();
+
+        // Sort by index, keeping the first name for functions with aliases
+        names.sort_by_key(|x| x.0);
+        names.dedup_by_key(|x| x.0);
+
+        Some(Exports {
+            pe:    self,
+            index: 0,
+            directory,
+            ordinal_base,
+            functions,
+            num_functions,
+            names,
+        })
+    }
+}
+
+impl<'p, R: Reader> Iterator for Exports<'p, R> {
+    type Item = Export;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.num_functions {
+            let index = self.index;
+            self.index += 1;
+
+            // Get the RVA of the export, unused entries are zero
+            let rva = self.pe.read_u32(
+                self.functions.checked_add(index.checked_mul(4)?)?)?;
+            if rva == 0 { continue; }
+
+            // Get the name of the export, if it has one
+            let name = self.names.binary_search_by_key(&index, |x| x.0).ok()
+                .and_then(|x| self.pe.read_cstr(self.names[x].1));
+
+            // Forwarders point at a string in the export directory
+            let target = if self.directory.contains(rva) {
+                ExportTarget::Forwarder(self.pe.read_cstr(rva)?)
+            } else {
+                ExportTarget::Rva(rva)
+            };
+
+            return Some(Export {
+                ordinal: self.ordinal_base.wrapping_add(index),
+                name,
+                target,
+            });
+        }
+
+        None
+    }
+}
@@ -0,0 +1,172 @@
+//! Import directory parsing
+
+use alloc::string::String;
+use crate::{Pe, Reader, DIRECTORY_IMPORT};
+
+/// Maximum number of import descriptors we iterate
+const MAX_IMPORT_DESCRIPTORS: u32 = 0x1000;
+
+/// Maximum number of imported functions we iterate per descriptor
+const MAX_IMPORT_FUNCTIONS: u32 = 0x10000;
+
+/// A DLL imported by the image, from an import descriptor
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Import {
+    /// Name of the imported DLL
+    pub dll: String,
+
+    /// RVA of the import lookup table, this is the import address table if
+    /// the image has no separate lookup table
+    pub lookup_table: u32,
+
+    /// RVA of the import address table, the loader writes the address of
+    /// each imported function here
+    pub address_table: u32,
+
+    /// Time stamp of the DLL if the imports are bound
+    pub time_date_stamp: u32,
+}
+
+/// How an imported function is imported
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ImportName {
+    /// Imported by ordinal
+    Ordinal(u16),
+
+    /// Imported by name, with a hint of the index into the export name
+    /// table of the DLL
+    Name {
+        hint: u16,
+        name: String,
+    },
+}
+
+/// A function imported from a DLL
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ImportFunction {
+    /// RVA of the entry of the import address table for this function
+    pub iat: u32,
+
+    /// How the function is imported
+    pub name: ImportName,
+}
+
+/// Iterator over the DLLs imported by a PE
+pub struct Imports<'p, R: Reader> {
+    /// PE we are iterating the imports of
+    pe: &'p Pe<R>,
+
+    /// RVA of the next import descriptor
+    descriptor: u32,
+
+    /// Number of descriptors we have yet to iterate before giving up
+    remaining: u32,
+}
+
+/// Iterator over the functions imported from a DLL
+pub struct ImportFunctions<'p, R: Reader> {
+    /// PE we are iterating the imports of
+    pe: &'p Pe<R>,
+
+    /// RVA of the next entry of the import lookup table
+    lookup: u32,
+
+    /// RVA of the next entry of the import address table
+    iat: u32,
+
+    /// Number of functions we have yet to iterate before giving up
+    remaining: u32,
+}
+
+impl<R: Reader> Pe<R> {
+    /// Get an iterator over the DLLs imported by the image. Returns `None` if
+    /// there is no import directory.
+    pub fn imports(&self) -> Option<Imports<'_, R>> {
+        let directory = self.directory(DIRECTORY_IMPORT)?;
+
+        Some(Imports {
+            pe:         self,
+            descriptor: directory.rva,
+            remaining:  MAX_IMPORT_DESCRIPTORS,
+        })
+    }
+
+    /// Get an iterator over the functions imported from the DLL `import`
+    pub fn import_functions(&self, import: &Import) -> ImportFunctions<'_, R> {
+        ImportFunctions {
+            pe:        self,
+            lookup:    import.lookup_table,
+            iat:       import.address_table,
+            remaining: MAX_IMPORT_FUNCTIONS,
+        }
+    }
+}
+
+impl<'p, R: Reader> Iterator for Imports<'p, R> {
+    type Item = Import;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 { return None; }
+        self.remaining -= 1;
+
+        // Read the descriptor, the table is terminated by a zeroed one
+        let mut desc = [0u8; 0x14];
+        self.pe.read_rva(self.descriptor, &mut desc)?;
+        if desc.iter().all(|&x| x == 0) { return None; }
+        self.descriptor = self.descriptor.checked_add(0x14)?;
+
+        let field = |off: usize| {
+            u32::from_le_bytes([desc[off], desc[off + 1],
+                                desc[off + 2], desc[off + 3]])
+        };
+
+        // Use the address table as the lookup table if there is none
+        let address_table = field(0x10);
+        let lookup_table  = match field(0) {
+            0 => address_table,
+            x => x,
+        };
+
+        Some(Import {
+            dll:             self.pe.read_cstr(field(0xc))?,
+            time_date_stamp: field(4),
+            lookup_table,
+            address_table,
+        })
+    }
+}
+
+impl<'p, R: Reader> Iterator for ImportFunctions<'p, R> {
+    type Item = ImportFunction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 { return None; }
+        self.remaining -= 1;
+
+        // Read the lookup table entry, the table is zero terminated
+        let entry = self.pe.read_ptr(self.lookup)?;
+        if entry == 0 { return None; }
+
+        let iat = self.iat;
+        self.lookup = self.lookup.checked_add(self.pe.ptr_size())?;
+        self.iat    = self.iat.checked_add(self.pe.ptr_size())?;
+
+        // The top bit of the entry is set for imports by ordinal, otherwise
+        // the entry is the RVA of the hint and name
+        let ordinal_flag = 1 << (self.pe.ptr_size() * 8 - 1);
+        let name = if entry & ordinal_flag != 0 {
+            ImportName::Ordinal(entry as u16)
+        } else {
+            let rva = entry as u32 & 0x7fffffff;
+            ImportName::Name {
+                hint: self.pe.read_u16(rva)?,
+                name: self.pe.read_cstr(rva.checked_add(2)?)?,
+            }
+        };
+
+        Some(ImportFunction {
+            iat,
+            name,
+        })
+    }
+}
@@ -1,155 +1,873 @@
-//! PE parser for basic x86_64 and i386 support. Currently this does not
-//! support relocations.
+//! PE parser for x86_64 and i386 images
+//!
+//! A PE can be parsed from a byte slice or lazily through a read callback,
+//! and either as laid out in a file on disk or as mapped into memory by a
+//! loader. This allows parsing PEs straight out of guest memory. Besides the
+//! headers and sections, the export, import, base relocation, TLS, exception,
+//! and debug directories are exposed as iterators. Nothing in the PE is
+//! trusted, malformed PEs stop parsing or iteration rather than panic.
 
 #![no_std]
 
+#[macro_use] extern crate alloc;
+
+mod exports;
+mod imports;
+mod relocations;
+mod tls;
+mod exceptions;
+mod debug;
+
+pub use exports::*;
+pub use imports::*;
+pub use relocations::*;
+pub use tls::*;
+pub use exceptions::*;
+pub use debug::*;
+
+use core::cell::RefCell;
 use core::convert::TryInto;
+use alloc::vec::Vec;
+use alloc::string::String;
 
-const IMAGE_FILE_MACHINE_I386:   u16 = 0x014c;
-const IMAGE_FILE_MACHINE_X86_64: u16 = 0x8664;
+pub const IMAGE_FILE_MACHINE_I386:   u16 = 0x014c;
+pub const IMAGE_FILE_MACHINE_X86_64: u16 = 0x8664;
 
 const IMAGE_SCN_MEM_EXECUTE: u32 = 0x20000000;
 const IMAGE_SCN_MEM_READ:    u32 = 0x40000000;
 const IMAGE_SCN_MEM_WRITE:   u32 = 0x80000000;
 
-/// A validated PE file that has had some basic information parsed out of it.
-/// You can use functions on this structure to extract things like sections.
-pub struct PeParser<'a> {
-    /// Raw PE file
-    bytes: &'a [u8],
+/// Optional header magic of PE32 images
+const PE32_MAGIC: u16 = 0x10b;
 
-    /// Number of sections
-    num_sections: usize,
+/// Optional header magic of PE32+ images
+const PE32_PLUS_MAGIC: u16 = 0x20b;
 
-    /// Offset into the raw PE file where the section headers are
-    section_off: usize,
+/// Index of the export directory in the data directories
+pub const DIRECTORY_EXPORT: usize = 0;
 
-    /// Base of the image
-    image_base: u64,
+/// Index of the import directory in the data directories
+pub const DIRECTORY_IMPORT: usize = 1;
 
-    /// Virtual address of the entry point
-    pub entry_point: u64,
+/// Index of the exception directory in the data directories
+pub const DIRECTORY_EXCEPTION: usize = 3;
+
+/// Index of the base relocation directory in the data directories
+pub const DIRECTORY_BASERELOC: usize = 5;
+
+/// Index of the debug directory in the data directories
+pub const DIRECTORY_DEBUG: usize = 6;
+
+/// Index of the TLS directory in the data directories
+pub const DIRECTORY_TLS: usize = 9;
+
+/// Maximum number of data directories
+const MAX_DIRECTORIES: usize = 16;
+
+/// Maximum number of sections, this is the limit of the Windows loader
+const MAX_SECTIONS: usize = 96;
+
+/// Maximum length of strings we read, such as the names of exports
+const MAX_STRING_LEN: usize = 4096;
+
+/// A source of the bytes of a PE
+pub trait Reader {
+    /// Read `buf.len()` bytes at `offset` into `buf`. Returns `None` if any
+    /// of the bytes could not be read.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Option<()>;
 }
 
-impl<'a> PeParser<'a> {
-    /// Validate a PE file is sane, and return out a "parsed" version which
-    /// can be used to access different information from the PE
-    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
-        let bytes: &[u8] = bytes.as_ref();
+impl Reader for &[u8] {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Option<()> {
+        let offset: usize = offset.try_into().ok()?;
+        buf.copy_from_slice(self.get(offset..offset.checked_add(buf.len())?)?);
+        Some(())
+    }
+}
 
-        // Check for an MZ header
-        if bytes.get(0..2) != Some(b"MZ") { return None; }
+/// A `Reader` which reads through a callback, for example to read a PE out
+/// of guest memory as it is needed
+pub struct ReadFn<F>(pub F);
 
-        // Get the PE offset
-        let pe_offset: usize =
-            u32::from_le_bytes(bytes.get(0x3c..0x40)?.try_into().ok()?)
-            .try_into().ok()?;
+impl<F: FnMut(u64, &mut [u8]) -> Option<()>> Reader for ReadFn<F> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Option<()> {
+        (self.0)(offset, buf)
+    }
+}
 
-        // Check for the PE signature
-        if bytes.get(pe_offset..pe_offset.checked_add(4)?) != Some(b"PE\0\0") {
-            return None;
-        }
+/// How the PE is laid out in the `Reader`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layout {
+    /// As a file on disk, RVAs are translated to file offsets through the
+    /// raw data of the sections
+    File,
 
-        // Make sure the COFF header is within bounds of our input
-        if pe_offset.checked_add(0x18)? > bytes.len() {
-            return None;
+    /// As mapped into memory by a loader, offsets are RVAs
+    Mapped,
+}
+
+/// The location of a data directory
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct DataDirectory {
+    /// RVA of the directory
+    pub rva: u32,
+
+    /// Size of the directory (in bytes)
+    pub size: u32,
+}
+
+impl DataDirectory {
+    /// Check if `rva` is within the directory
+    pub fn contains(&self, rva: u32) -> bool {
+        rva >= self.rva && rva - self.rva < self.size
+    }
+}
+
+/// A section header
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Section {
+    /// Name of the section, padded with zeros
+    pub name: [u8; 8],
+
+    /// RVA of the section
+    pub virt_addr: u32,
+
+    /// Size of the section in memory
+    pub virt_size: u32,
+
+    /// File offset of the raw data of the section
+    pub raw_off: u32,
+
+    /// Size of the raw data of the section in the file
+    pub raw_size: u32,
+
+    /// Section characteristics (`IMAGE_SCN_*`)
+    pub characteristics: u32,
+}
+
+impl Section {
+    /// Check if the section is readable
+    pub fn read(&self) -> bool {
+        (self.characteristics & IMAGE_SCN_MEM_READ) != 0
+    }
+
+    /// Check if the section is writable
+    pub fn write(&self) -> bool {
+        (self.characteristics & IMAGE_SCN_MEM_WRITE) != 0
+    }
+
+    /// Check if the section is executable
+    pub fn execute(&self) -> bool {
+        (self.characteristics & IMAGE_SCN_MEM_EXECUTE) != 0
+    }
+
+    /// Number of bytes of the section which are backed by raw data in the
+    /// file
+    fn file_size(&self) -> u32 {
+        if self.virt_size == 0 {
+            self.raw_size
+        } else {
+            core::cmp::min(self.raw_size, self.virt_size)
         }
+    }
+}
+
+/// A validated PE with its headers parsed, read through a `Reader`
+pub struct Pe<R: Reader> {
+    /// Source of the bytes of the PE. This is in a `RefCell` such that
+    /// iterators can share the PE.
+    reader: RefCell<R>,
+
+    /// Layout of the PE in `reader`
+    layout: Layout,
+
+    /// Machine type (`IMAGE_FILE_MACHINE_*`)
+    pub machine: u16,
+
+    /// Set if this is a PE32+ image, which has 64-bit pointers
+    pub pe32_plus: bool,
+
+    /// Preferred base of the image
+    pub image_base: u64,
+
+    /// RVA of the entry point
+    pub entry_point: u32,
+
+    /// Size of the image when mapped into memory
+    pub size_of_image: u32,
+
+    /// Size of the headers, these are at the same offset in the file and in
+    /// memory
+    pub size_of_headers: u32,
+
+    /// Section headers
+    sections: Vec<Section>,
+
+    /// Data directories, zero for directories which are not present
+    directories: [DataDirectory; MAX_DIRECTORIES],
+}
+
+impl<R: Reader> Pe<R> {
+    /// Validate the headers of the PE in `reader` which is laid out as
+    /// `layout`, and parse them
+    pub fn parse(mut reader: R, layout: Layout) -> Option<Self> {
+        // Check for an MZ header
+        let mut mz = [0u8; 2];
+        reader.read(0, &mut mz)?;
+        if &mz != b"MZ" { return None; }
+
+        // Get the PE offset
+        let mut pe_offset = [0u8; 4];
+        reader.read(0x3c, &mut pe_offset)?;
+        let pe_offset = u32::from_le_bytes(pe_offset) as u64;
+
+        // Check for the PE signature and read the COFF header
+        let mut coff = [0u8; 0x18];
+        reader.read(pe_offset, &mut coff)?;
+        if &coff[..4] != b"PE\0\0" { return None; }
 
         // Determine the machine type and make sure it's for x86 or x86_64
-        let machine = u16::from_le_bytes(
-            bytes[pe_offset + 4..pe_offset + 6].try_into().ok()?);
+        let machine = u16::from_le_bytes(coff[4..6].try_into().unwrap());
         if machine != IMAGE_FILE_MACHINE_I386 &&
                 machine != IMAGE_FILE_MACHINE_X86_64 {
             return None;
         }
-        
-        // Get the number of sections
-        let num_sections: usize = u16::from_le_bytes(
-            bytes[pe_offset + 6..pe_offset + 8].try_into().ok()?)
-            .try_into().ok()?;
-
-        // Get the size of the optional header
-        let opt_header_size: usize = u16::from_le_bytes(
-            bytes[pe_offset + 0x14..pe_offset + 0x16].try_into().ok()?)
-            .try_into().ok()?;
-        
-        // Get the base for the program
-        let image_base = if machine == IMAGE_FILE_MACHINE_I386 {
-            u32::from_le_bytes(
-                bytes.get(pe_offset + 0x34..pe_offset + 0x38)?
-                .try_into().ok()?) as u64
-        } else if machine == IMAGE_FILE_MACHINE_X86_64 {
-            u64::from_le_bytes(
-                bytes.get(pe_offset + 0x30..pe_offset + 0x38)?
-                .try_into().ok()?)
-        } else {
-            unreachable!();
+
+        // Get the number of sections and the size of the optional header
+        let num_sections =
+            u16::from_le_bytes(coff[6..8].try_into().unwrap()) as usize;
+        let opt_header_size =
+            u16::from_le_bytes(coff[0x14..0x16].try_into().unwrap()) as usize;
+        if num_sections > MAX_SECTIONS { return None; }
+
+        // Read the optional header
+        let mut opt = vec![0u8; opt_header_size];
+        reader.read(pe_offset + 0x18, &mut opt)?;
+        let read_u32 = |off: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(opt.get(off..off + 4)?.try_into().ok()?))
         };
-        
-        // Get the entry point for the image
-        let entry_point: u64 = u32::from_le_bytes(
-            bytes.get(pe_offset + 0x28..pe_offset + 0x2c)?
-            .try_into().ok()?) as u64;
-        let entry_point = image_base.checked_add(entry_point)?;
-
-        // Compute the size of all headers, including sections and make sure
-        // everything is in bounds
-        let header_size = pe_offset.checked_add(0x18)?
-            .checked_add(opt_header_size)?
-            .checked_add(num_sections.checked_mul(0x28)?)?;
-        if header_size > bytes.len() {
-            return None;
+
+        // Get the fields which depend on the optional header format
+        let magic = u16::from_le_bytes(opt.get(0..2)?.try_into().ok()?);
+        let (pe32_plus, image_base, dirs) = match magic {
+            PE32_MAGIC => (false, read_u32(0x1c)? as u64, 0x5c),
+            PE32_PLUS_MAGIC => (true, u64::from_le_bytes(
+                opt.get(0x18..0x20)?.try_into().ok()?), 0x6c),
+            _ => return None,
+        };
+
+        let entry_point     = read_u32(0x10)?;
+        let size_of_image   = read_u32(0x38)?;
+        let size_of_headers = read_u32(0x3c)?;
+
+        // Get the data directories which are present, ignoring any which
+        // do not fit in the optional header
+        let num_dirs = read_u32(dirs)? as usize;
+        let mut directories = [DataDirectory::default(); MAX_DIRECTORIES];
+        for (ii, dir) in directories.iter_mut().enumerate().take(num_dirs) {
+            let off = dirs + 4 + ii * 8;
+            match (read_u32(off), read_u32(off + 4)) {
+                (Some(rva), Some(size)) => {
+                    *dir = DataDirectory { rva, size };
+                }
+                _ => break,
+            }
         }
 
+        // Read the section headers
+        let mut raw = vec![0u8; num_sections * 0x28];
+        reader.read(pe_offset + 0x18 + opt_header_size as u64, &mut raw)?;
+        let sections = raw.chunks(0x28).map(|x| {
+            let field = |off: usize| {
+                u32::from_le_bytes(x[off..off + 4].try_into().unwrap())
+            };
+
+            Section {
+                name:            x[..8].try_into().unwrap(),
+                virt_size:       field(0x8),
+                virt_addr:       field(0xc),
+                raw_size:        field(0x10),
+                raw_off:         field(0x14),
+                characteristics: field(0x24),
+            }
+        }).collect();
+
+        Some(Pe {
+            reader: RefCell::new(reader),
+            layout,
+            machine,
+            pe32_plus,
+            image_base,
+            entry_point,
+            size_of_image,
+            size_of_headers,
+            sections,
+            directories,
+        })
+    }
+
+    /// Get the section headers
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// Get data directory `index` (`DIRECTORY_*`), if the image has it
+    pub fn directory(&self, index: usize) -> Option<DataDirectory> {
+        let dir = *self.directories.get(index)?;
+        if dir.rva == 0 || dir.size == 0 { return None; }
+        Some(dir)
+    }
+
+    /// Convert a virtual address relative to the preferred image base into
+    /// an RVA
+    pub fn va_to_rva(&self, va: u64) -> Option<u32> {
+        va.checked_sub(self.image_base)?.try_into().ok()
+    }
+
+    /// Get the offset in the reader of `rva`, and the number of bytes which
+    /// can be read contiguously from there
+    fn rva_to_offset(&self, rva: u32) -> Option<(u64, u32)> {
+        match self.layout {
+            Layout::Mapped => {
+                if rva >= self.size_of_image { return None; }
+                Some((rva as u64, self.size_of_image - rva))
+            }
+            Layout::File => {
+                // The headers are at the same offset in the file
+                if rva < self.size_of_headers {
+                    return Some((rva as u64, self.size_of_headers - rva));
+                }
+
+                // Otherwise find the section with raw data for the RVA
+                self.sections.iter().find_map(|section| {
+                    let offset = rva.checked_sub(section.virt_addr)?;
+                    if offset >= section.file_size() { return None; }
+                    Some((section.raw_off as u64 + offset as u64,
+                          section.file_size() - offset))
+                })
+            }
+        }
+    }
+
+    /// Read the bytes at `rva` into `buf`. Returns `None` if any of the bytes
+    /// are not in the PE or could not be read.
+    pub fn read_rva(&self, mut rva: u32, mut buf: &mut [u8]) -> Option<()> {
+        while !buf.is_empty() {
+            let (offset, avail) = self.rva_to_offset(rva)?;
+            let len = core::cmp::min(avail as usize, buf.len());
+            self.reader.borrow_mut().read(offset, &mut buf[..len])?;
+
+            buf = &mut buf[len..];
+            rva = rva.checked_add(len as u32)?;
+        }
+
+        Some(())
+    }
+
+    /// Read a `u16` at `rva`
+    pub fn read_u16(&self, rva: u32) -> Option<u16> {
+        let mut buf = [0u8; 2];
+        self.read_rva(rva, &mut buf)?;
+        Some(u16::from_le_bytes(buf))
+    }
+
+    /// Read a `u32` at `rva`
+    pub fn read_u32(&self, rva: u32) -> Option<u32> {
+        let mut buf = [0u8; 4];
+        self.read_rva(rva, &mut buf)?;
+        Some(u32::from_le_bytes(buf))
+    }
+
+    /// Read a `u64` at `rva`
+    pub fn read_u64(&self, rva: u32) -> Option<u64> {
+        let mut buf = [0u8; 8];
+        self.read_rva(rva, &mut buf)?;
+        Some(u64::from_le_bytes(buf))
+    }
+
+    /// Size of a pointer in the image (in bytes)
+    pub fn ptr_size(&self) -> u32 {
+        if self.pe32_plus { 8 } else { 4 }
+    }
+
+    /// Read a pointer at `rva`, which is 64-bits for PE32+ images
+    pub fn read_ptr(&self, rva: u32) -> Option<u64> {
+        if self.pe32_plus {
+            self.read_u64(rva)
+        } else {
+            self.read_u32(rva).map(|x| x as u64)
+        }
+    }
+
+    /// Read a NUL terminated UTF-8 string at `rva`
+    pub fn read_cstr(&self, mut rva: u32) -> Option<String> {
+        let mut ret = Vec::new();
+        let mut buf = [0u8; 64];
+
+        while ret.len() < MAX_STRING_LEN {
+            // Read in chunks which do not cross a page boundary, such that a
+            // string which ends just before an unreadable page can be read
+            let chunk = 0x1000 - (rva as usize & 0xfff);
+            let chunk = core::cmp::min(chunk, buf.len());
+            self.read_rva(rva, &mut buf[..chunk])?;
+
+            if let Some(len) = buf[..chunk].iter().position(|&x| x == 0) {
+                ret.extend_from_slice(&buf[..len]);
+                return String::from_utf8(ret).ok();
+            }
+
+            ret.extend_from_slice(&buf[..chunk]);
+            rva = rva.checked_add(chunk as u32)?;
+        }
+
+        // The string was too long
+        None
+    }
+}
+
+/// A validated PE file that has had some basic information parsed out of it.
+/// You can use functions on this structure to extract things like sections.
+pub struct PeParser<'a> {
+    /// Raw PE file
+    bytes: &'a [u8],
+
+    /// Parsed PE
+    pe: Pe<&'a [u8]>,
+
+    /// Virtual address of the entry point
+    pub entry_point: u64,
+}
+
+impl<'a> PeParser<'a> {
+    /// Validate a PE file is sane, and return out a "parsed" version which
+    /// can be used to access different information from the PE
+    pub fn parse(bytes: &'a [u8]) -> Option<Self> {
+        let pe = Pe::parse(bytes, Layout::File)?;
+
+        // Get the entry point for the image
+        let entry_point = pe.image_base.checked_add(pe.entry_point as u64)?;
+
         Some(PeParser {
             bytes,
-            image_base,
-            num_sections,
+            pe,
             entry_point,
-            section_off: pe_offset + 0x18 + opt_header_size,
         })
     }
 
+    /// Get the parsed PE, to access its directories
+    pub fn pe(&self) -> &Pe<&'a [u8]> {
+        &self.pe
+    }
+
     /// Invoke a closure with the format
     /// (virtual addr, virtual size, raw initialize bytes,
     ///  read, write, execute) for each section in the PE file
     pub fn sections<F>(&self, mut func: F) -> Option<()>
             where F: FnMut(u64, u32, &[u8], bool, bool, bool) -> Option<()> {
-        let bytes = self.bytes;
-
-        for section in 0..self.num_sections {
-            let off = self.section_off + section * 0x28;
-
-            // Get the virtual and raw sizes and offsets
-            let virt_size = u32::from_le_bytes(
-                bytes[off + 0x8..off + 0xc].try_into().ok()?);
-            let virt_addr = u32::from_le_bytes(
-                bytes[off + 0xc..off + 0x10].try_into().ok()?);
-            let raw_size = u32::from_le_bytes(
-                bytes[off + 0x10..off + 0x14].try_into().ok()?);
-            let raw_off: usize = u32::from_le_bytes(
-                bytes[off + 0x14..off + 0x18].try_into().ok()?)
-                .try_into().ok()?;
-
-            // Get the section characteristics
-            let characteristics = u32::from_le_bytes(
-                bytes[off + 0x24..off + 0x28].try_into().ok()?);
-
+        for section in self.pe.sections() {
             // Truncate the raw size if it exceeds the section size
-            let raw_size: usize = core::cmp::min(raw_size, virt_size)
+            let raw_off = section.raw_off as usize;
+            let raw_size: usize =
+                core::cmp::min(section.raw_size, section.virt_size)
                 .try_into().ok()?;
 
             // Invoke the closure
             func(
-                self.image_base.checked_add(virt_addr as u64)?,
-                virt_size,
-                bytes.get(raw_off..raw_off.checked_add(raw_size)?)?,
-                (characteristics & IMAGE_SCN_MEM_READ)    != 0,
-                (characteristics & IMAGE_SCN_MEM_WRITE)   != 0,
-                (characteristics & IMAGE_SCN_MEM_EXECUTE) != 0)?;
+                self.pe.image_base.checked_add(section.virt_addr as u64)?,
+                section.virt_size,
+                self.bytes.get(raw_off..raw_off.checked_add(raw_size)?)?,
+                section.read(),
+                section.write(),
+                section.execute())?;
         }
 
         Some(())
     }
 }
 
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    extern crate std;
+    use std::vec::Vec;
+
+    /// Preferred base of the test images
+    fn image_base(pe32_plus: bool) -> u64 {
+        if pe32_plus { 0x140000000 } else { 0x400000 }
+    }
+
+    /// File offset of the raw data of the only section of the test images,
+    /// which is mapped at RVA 0x1000
+    const RAW_OFF: usize = 0x400;
+
+    /// Size of the test images when mapped
+    const IMAGE_SIZE: usize = 0x3000;
+
+    /// Write `val` at the RVA `rva` of the test image file `pe`
+    fn put(pe: &mut [u8], rva: usize, val: &[u8]) {
+        let off = if rva >= 0x1000 { rva - 0x1000 + RAW_OFF } else { rva };
+        pe[off..off + val.len()].copy_from_slice(val);
+    }
+
+    /// Write a `u16` at `rva`
+    fn put16(pe: &mut [u8], rva: usize, val: u16) {
+        put(pe, rva, &val.to_le_bytes());
+    }
+
+    /// Write a `u32` at `rva`
+    fn put32(pe: &mut [u8], rva: usize, val: u32) {
+        put(pe, rva, &val.to_le_bytes());
+    }
+
+    /// Write a pointer at `rva`
+    fn put_ptr(pe: &mut [u8], rva: usize, val: u64, pe32_plus: bool) {
+        if pe32_plus {
+            put(pe, rva, &val.to_le_bytes());
+        } else {
+            put32(pe, rva, val as u32);
+        }
+    }
+
+    /// Build a small PE file with one section containing an export, import,
+    /// base relocation, TLS, exception, and debug directory
+    fn build(pe32_plus: bool) -> Vec<u8> {
+        let mut pe = vec![0u8; RAW_OFF + 0x1000];
+        let ptr  = if pe32_plus { 8 } else { 4 };
+        let base = image_base(pe32_plus);
+
+        // Headers
+        let opt = 0x58;
+        let opt_size = if pe32_plus { 0xf0 } else { 0xe0 };
+        put(&mut pe, 0, b"MZ");
+        put32(&mut pe, 0x3c, 0x40);
+        put(&mut pe, 0x40, b"PE\0\0");
+        put16(&mut pe, 0x44, if pe32_plus {
+            IMAGE_FILE_MACHINE_X86_64
+        } else {
+            IMAGE_FILE_MACHINE_I386
+        });
+        put16(&mut pe, 0x46, 1);
+        put16(&mut pe, 0x54, opt_size as u16);
+        put16(&mut pe, opt, if pe32_plus { 0x20b } else { 0x10b });
+        put32(&mut pe, opt + 0x10, 0x1000);
+        if pe32_plus {
+            put(&mut pe, opt + 0x18, &base.to_le_bytes());
+        } else {
+            put32(&mut pe, opt + 0x1c, base as u32);
+        }
+        put32(&mut pe, opt + 0x38, IMAGE_SIZE as u32);
+        put32(&mut pe, opt + 0x3c, 0x200);
+
+        // Data directories
+        let dirs = opt + if pe32_plus { 0x6c } else { 0x5c };
+        put32(&mut pe, dirs, 16);
+        for &(index, rva, size) in &[
+                (DIRECTORY_EXPORT,    0x1100, 0x100),
+                (DIRECTORY_IMPORT,    0x1200, 0x28),
+                (DIRECTORY_EXCEPTION, 0x1600, 0x18),
+                (DIRECTORY_BASERELOC, 0x1300, 0x1c),
+                (DIRECTORY_DEBUG,     0x1800, 0x1c),
+                (DIRECTORY_TLS,       0x1400, 0x28)] {
+            put32(&mut pe, dirs + 4 + index * 8, rva);
+            put32(&mut pe, dirs + 8 + index * 8, size);
+        }
+
+        // Section header
+        let section = opt + opt_size;
+        put(&mut pe, section, b".text\0\0\0");
+        put32(&mut pe, section + 0x8, 0x2000);
+        put32(&mut pe, section + 0xc, 0x1000);
+        put32(&mut pe, section + 0x10, 0x1000);
+        put32(&mut pe, section + 0x14, RAW_OFF as u32);
+        put32(&mut pe, section + 0x24,
+              IMAGE_SCN_MEM_READ | IMAGE_SCN_MEM_EXECUTE);
+
+        // Exports, `Alpha` at ordinal 5, an unnamed export at ordinal 6, and
+        // `Fwd` at ordinal 7 which is forwarded to `other.Beta`
+        put32(&mut pe, 0x1110, 5);
+        put32(&mut pe, 0x1114, 4);
+        put32(&mut pe, 0x1118, 2);
+        put32(&mut pe, 0x111c, 0x1140);
+        put32(&mut pe, 0x1120, 0x1150);
+        put32(&mut pe, 0x1124, 0x1158);
+        put32(&mut pe, 0x1140, 0x1000);
+        put32(&mut pe, 0x1144, 0x1010);
+        put32(&mut pe, 0x1148, 0x1180);
+        put32(&mut pe, 0x1150, 0x1160);
+        put32(&mut pe, 0x1154, 0x1168);
+        put16(&mut pe, 0x1158, 0);
+        put16(&mut pe, 0x115a, 2);
+        put(&mut pe, 0x1160, b"Alpha\0");
+        put(&mut pe, 0x1168, b"Fwd\0");
+        put(&mut pe, 0x1180, b"other.Beta\0");
+
+        // Imports of `Sleep` and ordinal 7 from kernel32.dll
+        put32(&mut pe, 0x1200, 0x1240);
+        put32(&mut pe, 0x120c, 0x1280);
+        put32(&mut pe, 0x1210, 0x1260);
+        for &table in &[0x1240, 0x1260] {
+            put_ptr(&mut pe, table, 0x1290, pe32_plus);
+            put_ptr(&mut pe, table + ptr, 7 | 1 << (ptr * 8 - 1),
+                    pe32_plus);
+        }
+        put(&mut pe, 0x1280, b"kernel32.dll\0");
+        put16(&mut pe, 0x1290, 0x12);
+        put(&mut pe, 0x1292, b"Sleep\0");
+
+        // Base relocations, in two blocks with padding
+        put32(&mut pe, 0x1300, 0x1000);
+        put32(&mut pe, 0x1304, 0x10);
+        put16(&mut pe, 0x1308, 0xa008);
+        put16(&mut pe, 0x130a, 0xa010);
+        put16(&mut pe, 0x130c, 0x3020);
+        put32(&mut pe, 0x1310, 0x2000);
+        put32(&mut pe, 0x1314, 0xc);
+        put16(&mut pe, 0x1318, 0xa004);
+
+        // TLS with two callbacks
+        for (ii, &rva) in [0x1500, 0x1510, 0x1520, 0x1530].iter().enumerate()
+        {
+            put_ptr(&mut pe, 0x1400 + ii * ptr, base + rva,
+                    pe32_plus);
+        }
+        put32(&mut pe, 0x1400 + ptr * 4, 0x10);
+        put_ptr(&mut pe, 0x1530, base + 0x1000, pe32_plus);
+        put_ptr(&mut pe, 0x1530 + ptr, base + 0x1010, pe32_plus);
+
+        // Two runtime functions, one with a handler and one chained to it
+        for (ii, &(begin, end, unwind)) in
+                [(0x1000, 0x1010, 0x1700), (0x1010, 0x1020, 0x1720)]
+                .iter().enumerate() {
+            put32(&mut pe, 0x1600 + ii * 12, begin);
+            put32(&mut pe, 0x1604 + ii * 12, end);
+            put32(&mut pe, 0x1608 + ii * 12, unwind);
+        }
+
+        // `sub rsp, 0x100` and `push rbx` with an exception handler
+        put(&mut pe, 0x1700, &[1 | (UNW_FLAG_EHANDLER << 3), 8, 3, 0]);
+        put(&mut pe, 0x1704, &[8, 0x01, 0x20, 0x00, 4, 0x30]);
+        put32(&mut pe, 0x170c, 0x1050);
+
+        // Chained unwind information
+        put(&mut pe, 0x1720, &[1 | (UNW_FLAG_CHAININFO << 3), 0, 0, 0]);
+        put32(&mut pe, 0x1724, 0x1000);
+        put32(&mut pe, 0x1728, 0x1010);
+        put32(&mut pe, 0x172c, 0x1700);
+
+        // CodeView record
+        let pdb = b"C:\\x\\test.pdb\0";
+        put32(&mut pe, 0x180c, IMAGE_DEBUG_TYPE_CODEVIEW);
+        put32(&mut pe, 0x1810, 0x18 + pdb.len() as u32);
+        put32(&mut pe, 0x1814, 0x1820);
+        put32(&mut pe, 0x1818, (RAW_OFF + 0x820) as u32);
+        put(&mut pe, 0x1820, b"RSDS");
+        let guid: Vec<u8> = (0..16).collect();
+        put(&mut pe, 0x1824, &guid);
+        put32(&mut pe, 0x1834, 3);
+        put(&mut pe, 0x1838, pdb);
+
+        pe
+    }
+
+    /// Map the test image file `pe` into memory
+    fn map(pe: &[u8]) -> Vec<u8> {
+        let mut mapped = vec![0u8; IMAGE_SIZE];
+        mapped[..0x200].copy_from_slice(&pe[..0x200]);
+        mapped[0x1000..0x2000].copy_from_slice(&pe[RAW_OFF..RAW_OFF + 0x1000]);
+        mapped
+    }
+
+    /// Check all directories of a test image
+    fn check<R: Reader>(pe: &Pe<R>, pe32_plus: bool) {
+        let base = image_base(pe32_plus);
+        assert_eq!(pe.pe32_plus, pe32_plus);
+        assert_eq!(pe.image_base, base);
+        assert_eq!(pe.entry_point, 0x1000);
+        assert_eq!(pe.sections().len(), 1);
+        assert!(pe.sections()[0].execute() && !pe.sections()[0].write());
+
+        let exports: Vec<Export> = pe.exports().unwrap().collect();
+        assert_eq!(exports, vec![
+            Export {
+                ordinal: 5,
+                name:    Some("Alpha".into()),
+                target:  ExportTarget::Rva(0x1000),
+            },
+            Export {
+                ordinal: 6,
+                name:    None,
+                target:  ExportTarget::Rva(0x1010),
+            },
+            Export {
+                ordinal: 7,
+                name:    Some("Fwd".into()),
+                target:  ExportTarget::Forwarder("other.Beta".into()),
+            },
+        ]);
+
+        let imports: Vec<Import> = pe.imports().unwrap().collect();
+        assert_eq!(imports.len(), 1);
+        assert_eq!(imports[0].dll, "kernel32.dll");
+        let functions: Vec<ImportFunction> =
+            pe.import_functions(&imports[0]).collect();
+        let ptr = pe.ptr_size();
+        assert_eq!(functions, vec![
+            ImportFunction {
+                iat:  0x1260,
+                name: ImportName::Name { hint: 0x12, name: "Sleep".into() },
+            },
+            ImportFunction {
+                iat:  0x1260 + ptr,
+                name: ImportName::Ordinal(7),
+            },
+        ]);
+
+        let relocations: Vec<Relocation> =
+            pe.relocations().unwrap().collect();
+        assert_eq!(relocations, vec![
+            Relocation { rva: 0x1008, kind: IMAGE_REL_BASED_DIR64 },
+            Relocation { rva: 0x1010, kind: IMAGE_REL_BASED_DIR64 },
+            Relocation { rva: 0x1020, kind: IMAGE_REL_BASED_HIGHLOW },
+            Relocation { rva: 0x2004, kind: IMAGE_REL_BASED_DIR64 },
+        ]);
+
+        let tls = pe.tls().unwrap();
+        assert_eq!(tls.start_raw_data, base + 0x1500);
+        assert_eq!(tls.address_of_callbacks, base + 0x1530);
+        assert_eq!(tls.size_of_zero_fill, 0x10);
+        let callbacks: Vec<u64> = pe.tls_callbacks().unwrap().collect();
+        assert_eq!(callbacks, vec![base + 0x1000, base + 0x1010]);
+
+        let codeview = pe.codeview().unwrap();
+        assert_eq!(codeview.pdb, "C:\\x\\test.pdb");
+        assert_eq!(codeview.age, 3);
+        assert_eq!(&codeview.guid[..], &(0..16).collect::<Vec<u8>>()[..]);
+
+        // Runtime functions only exist for x86_64
+        if !pe32_plus {
+            assert!(pe.runtime_functions().is_none());
+            return;
+        }
+
+        let functions: Vec<RuntimeFunction> =
+            pe.runtime_functions().unwrap().collect();
+        assert_eq!(functions.len(), 2);
+
+        let unwind = pe.unwind_info(&functions[0]).unwrap();
+        assert_eq!(unwind.prolog_size, 8);
+        assert_eq!(unwind.handler, Some(0x1050));
+        assert_eq!(unwind.codes, vec![
+            UnwindCode { offset: 8, op: UnwindOp::Alloc { size: 0x100 } },
+            UnwindCode {
+                offset: 4,
+                op:     UnwindOp::PushNonVolatile { register: 3 },
+            },
+        ]);
+
+        let unwind = pe.unwind_info(&functions[1]).unwrap();
+        assert_eq!(unwind.chained, Some(functions[0]));
+        assert!(unwind.codes.is_empty());
+    }
+
+    /// Run every parser on `pe`, making sure nothing panics
+    fn parse_all<R: Reader>(pe: &Pe<R>) {
+        if let Some(x) = pe.exports() { x.for_each(drop); }
+        if let Some(imports) = pe.imports() {
+            for import in imports {
+                pe.import_functions(&import).for_each(drop);
+            }
+        }
+        if let Some(x) = pe.relocations() { x.for_each(drop); }
+        if let Some(x) = pe.tls_callbacks() { x.for_each(drop); }
+        if let Some(functions) = pe.runtime_functions() {
+            for function in functions {
+                pe.unwind_info(&function);
+            }
+        }
+        pe.codeview();
+    }
+
+    #[test]
+    fn file_layout() {
+        for &pe32_plus in &[true, false] {
+            let file = build(pe32_plus);
+            let pe = Pe::parse(&file[..], Layout::File).unwrap();
+            check(&pe, pe32_plus);
+        }
+    }
+
+    #[test]
+    fn mapped_layout() {
+        for &pe32_plus in &[true, false] {
+            let mapped = map(&build(pe32_plus));
+
+            // Read through a callback, as we would from guest memory
+            let pe = Pe::parse(ReadFn(|offset: u64, buf: &mut [u8]| {
+                let offset = offset as usize;
+                buf.copy_from_slice(mapped.get(offset..offset + buf.len())?);
+                Some(())
+            }), Layout::Mapped).unwrap();
+            check(&pe, pe32_plus);
+        }
+    }
+
+    #[test]
+    fn unreadable_pages() {
+        // Make the page with the export name tables unreadable, the other
+        // directories must still be readable
+        let mapped = map(&build(true));
+        let pe = Pe::parse(ReadFn(|offset: u64, buf: &mut [u8]| {
+            let offset = offset as usize;
+            if offset < 0x1200 && offset + buf.len() > 0x1100 { return None; }
+            buf.copy_from_slice(mapped.get(offset..offset + buf.len())?);
+            Some(())
+        }), Layout::Mapped).unwrap();
+
+        assert!(pe.exports().is_none());
+        assert_eq!(pe.imports().unwrap().count(), 1);
+        assert_eq!(pe.codeview().unwrap().age, 3);
+    }
+
+    #[test]
+    fn malformed() {
+        let file = build(true);
+
+        // Truncated files
+        for len in 0..file.len() {
+            if let Some(pe) = Pe::parse(&file[..len], Layout::File) {
+                parse_all(&pe);
+            }
+        }
+
+        // Corrupted headers and directories
+        for off in (0..0x200).chain(RAW_OFF + 0x100..RAW_OFF + 0x900) {
+            for &val in &[0x00, 0x7f, 0xff] {
+                let mut file = file.clone();
+                file[off] = val;
+                if let Some(pe) = Pe::parse(&file[..], Layout::File) {
+                    parse_all(&pe);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pe_parser_sections() {
+        let file = build(true);
+        let pe = PeParser::parse(&file).unwrap();
+        let base = image_base(true);
+        assert_eq!(pe.entry_point, base + 0x1000);
+
+        let mut sections = 0;
+        pe.sections(|vaddr, vsize, raw, read, write, execute| {
+            assert_eq!(vaddr, base + 0x1000);
+            assert_eq!(vsize, 0x2000);
+            assert_eq!(raw, &file[RAW_OFF..RAW_OFF + 0x1000]);
+            assert!(read && !write && execute);
+            sections += 1;
+            Some(())
+        }).unwrap();
+        assert_eq!(sections, 1);
+    }
+}
@@ -0,0 +1,94 @@
+//! Base relocation directory parsing
+
+use crate::{Pe, Reader, DIRECTORY_BASERELOC};
+
+/// Padding entry, which is skipped while iterating
+pub const IMAGE_REL_BASED_ABSOLUTE: u8 = 0;
+
+/// Relocation of a 32-bit pointer
+pub const IMAGE_REL_BASED_HIGHLOW: u8 = 3;
+
+/// Relocation of a 64-bit pointer
+pub const IMAGE_REL_BASED_DIR64: u8 = 10;
+
+/// A base relocation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Relocation {
+    /// RVA of the value to relocate
+    pub rva: u32,
+
+    /// Type of the relocation (`IMAGE_REL_BASED_*`)
+    pub kind: u8,
+}
+
+/// Iterator over the base relocations of a PE
+pub struct Relocations<'p, R: Reader> {
+    /// PE we are iterating the relocations of
+    pe: &'p Pe<R>,
+
+    /// RVA of the next relocation block
+    block: u32,
+
+    /// RVA of the end of the relocation directory
+    end: u32,
+
+    /// RVA of the page the entries of the current block relocate
+    page: u32,
+
+    /// RVA of the next entry of the current block
+    entry: u32,
+
+    /// RVA of the end of the current block
+    block_end: u32,
+}
+
+impl<R: Reader> Pe<R> {
+    /// Get an iterator over the base relocations of the image. Returns
+    /// `None` if there is no base relocation directory.
+    pub fn relocations(&self) -> Option<Relocations<'_, R>> {
+        let directory = self.directory(DIRECTORY_BASERELOC)?;
+
+        Some(Relocations {
+            pe:        self,
+            block:     directory.rva,
+            end:       directory.rva.checked_add(directory.size)?,
+            page:      0,
+            entry:     0,
+            block_end: 0,
+        })
+    }
+}
+
+impl<'p, R: Reader> Iterator for Relocations<'p, R> {
+    type Item = Relocation;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Get the next entry of the current block
+            if self.block_end.saturating_sub(self.entry) >= 2 {
+                let entry = self.pe.read_u16(self.entry)?;
+                self.entry += 2;
+
+                let kind = (entry >> 12) as u8;
+                if kind == IMAGE_REL_BASED_ABSOLUTE { continue; }
+
+                return Some(Relocation {
+                    rva: self.page.checked_add(entry as u32 & 0xfff)?,
+                    kind,
+                });
+            }
+
+            // Go to the next block, each block starts with the page RVA and
+            // the size of the block, including this header
+            if self.end.saturating_sub(self.block) < 8 { return None; }
+            let size = self.pe.read_u32(self.block + 4)?;
+            if size < 8 { return None; }
+
+            self.page      = self.pe.read_u32(self.block)?;
+            self.entry     = self.block + 8;
+            self.block_end = core::cmp::min(
+                self.block.checked_add(size)?, self.end);
+            self.block     = self.block_end;
+        }
+    }
+}
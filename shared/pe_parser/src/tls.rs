@@ -0,0 +1,89 @@
+//! TLS directory parsing
+
+use crate::{Pe, Reader, DIRECTORY_TLS};
+
+/// Maximum number of TLS callbacks we iterate
+const MAX_TLS_CALLBACKS: u32 = 0x400;
+
+/// The TLS directory. Addresses are virtual addresses relative to the
+/// preferred image base, see `Pe::va_to_rva`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Tls {
+    /// Address of the start of the TLS template
+    pub start_raw_data: u64,
+
+    /// Address of the end of the TLS template
+    pub end_raw_data: u64,
+
+    /// Address of the TLS index, written by the loader
+    pub address_of_index: u64,
+
+    /// Address of the zero terminated array of TLS callbacks
+    pub address_of_callbacks: u64,
+
+    /// Size of the zero filled area following the TLS template
+    pub size_of_zero_fill: u32,
+
+    /// TLS characteristics
+    pub characteristics: u32,
+}
+
+/// Iterator over the addresses of the TLS callbacks of a PE
+pub struct TlsCallbacks<'p, R: Reader> {
+    /// PE we are iterating the TLS callbacks of
+    pe: &'p Pe<R>,
+
+    /// RVA of the next entry of the callback array
+    rva: u32,
+
+    /// Number of callbacks we have yet to iterate before giving up
+    remaining: u32,
+}
+
+impl<R: Reader> Pe<R> {
+    /// Get the TLS directory of the image, if it has one
+    pub fn tls(&self) -> Option<Tls> {
+        let directory = self.directory(DIRECTORY_TLS)?;
+        let rva  = directory.rva;
+        let size = self.ptr_size();
+
+        Some(Tls {
+            start_raw_data:       self.read_ptr(rva)?,
+            end_raw_data:         self.read_ptr(rva.checked_add(size)?)?,
+            address_of_index:     self.read_ptr(rva.checked_add(size * 2)?)?,
+            address_of_callbacks: self.read_ptr(rva.checked_add(size * 3)?)?,
+            size_of_zero_fill:    self.read_u32(rva.checked_add(size * 4)?)?,
+            characteristics:
+                self.read_u32(rva.checked_add(size * 4 + 4)?)?,
+        })
+    }
+
+    /// Get an iterator over the addresses of the TLS callbacks of the image.
+    /// Returns `None` if the image has no TLS callbacks.
+    pub fn tls_callbacks(&self) -> Option<TlsCallbacks<'_, R>> {
+        let tls = self.tls()?;
+        if tls.address_of_callbacks == 0 { return None; }
+
+        Some(TlsCallbacks {
+            pe:        self,
+            rva:       self.va_to_rva(tls.address_of_callbacks)?,
+            remaining: MAX_TLS_CALLBACKS,
+        })
+    }
+}
+
+impl<'p, R: Reader> Iterator for TlsCallbacks<'p, R> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 { return None; }
+        self.remaining -= 1;
+
+        // The array is zero terminated
+        let callback = self.pe.read_ptr(self.rva)?;
+        if callback == 0 { return None; }
+
+        self.rva = self.rva.checked_add(self.pe.ptr_size())?;
+        Some(callback)
+    }
+}
@@ -0,0 +1,64 @@
+//! Parsing of the line based text files shipped next to snapshots on the
+//! server, such as register overrides, Linux structure offsets, and basic
+//! block lists
+//!
+//! Every line holds whitespace separated fields. A `#` starts a comment
+//! which runs to the end of the line, and lines without fields are ignored.
+//! Numbers are decimal, or hex with a `0x` prefix.
+
+#![no_std]
+
+use core::str::SplitWhitespace;
+
+/// Parse a number, in hex if prefixed with `0x`
+pub fn parse_num(val: &str) -> Option<u64> {
+    match val.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None      => val.parse().ok(),
+    }
+}
+
+/// Get an iterator over the lines of `text` which have fields, as the line
+/// number (starting at 1) and the fields of the line with comments removed
+pub fn lines(text: &str)
+        -> impl Iterator<Item = (usize, SplitWhitespace<'_>)> {
+    text.lines().enumerate().filter_map(|(ii, line)| {
+        let fields = line.split('#').next().unwrap().split_whitespace();
+        fields.clone().next().map(|_| (ii + 1, fields))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use std::vec::Vec;
+    use crate::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_num("1234"), Some(1234));
+        assert_eq!(parse_num("0x1234"), Some(0x1234));
+        assert_eq!(parse_num("0xffffffffffffffff"), Some(!0));
+        assert_eq!(parse_num("0x"), None);
+        assert_eq!(parse_num("1234h"), None);
+        assert_eq!(parse_num("-1"), None);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let text = "# Header\n\
+                    \n\
+                    efer 0xd01 # trailing comment\n\
+                    \t  \n\
+                    module ntdll.dll\n";
+
+        let lines: Vec<(usize, Vec<&str>)> = lines(text)
+            .map(|(line, fields)| (line, fields.collect()))
+            .collect();
+        assert_eq!(lines, [
+            (3, ["efer", "0xd01"].to_vec()),
+            (5, ["module", "ntdll.dll"].to_vec()),
+        ]);
+    }
+}